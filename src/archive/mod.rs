@@ -0,0 +1,114 @@
+//! Database kept in a tar archive
+//!
+//! The whole archive is read into memory when opened. `commit` writes a new archive next to the old
+//! one and renames it into place, so an interrupted commit leaves the previous archive intact.
+use super::binary_io;
+use super::error::MsdbError;
+use super::storage::{MemoryStorage, Storage};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path;
+/// Databases created with a name ending in this extension are kept in a tar archive
+pub const EXTENSION: &str = ".tar";
+pub struct TarStorage {
+    path: String,
+    content: MemoryStorage,
+}
+impl TarStorage {
+    /// Create an empty archive. Existing files are not overwritten.
+    pub fn create(path: &str) -> Result<Self, MsdbError> {
+        if path::Path::new(path).exists() {
+            return Err(MsdbError::AlreadyExists(path.to_string()));
+        }
+        let storage = Self {
+            path: path.to_string(),
+            content: MemoryStorage::new(path),
+        };
+        storage.write_archive(&storage.content)?;
+        Ok(storage)
+    }
+    pub fn open(path: &str) -> Result<Self, MsdbError> {
+        let file = File::open(path).map_err(|e| MsdbError::from_io(path, e))?;
+        let mut archive = tar::Archive::new(file);
+        let mut blobs = BTreeMap::new();
+        for entry in archive.entries().map_err(|e| MsdbError::from_io(path, e))? {
+            let mut entry = entry.map_err(|e| MsdbError::from_io(path, e))?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = entry
+                .path()
+                .map_err(|e| MsdbError::from_io(path, e))?
+                .to_string_lossy()
+                .to_string();
+            let mut data = Vec::new();
+            entry
+                .read_to_end(&mut data)
+                .map_err(|e| MsdbError::from_io(path, e))?;
+            blobs.insert(name, data);
+        }
+        let mut content = MemoryStorage::new(path);
+        content.load(blobs);
+        Ok(Self {
+            path: path.to_string(),
+            content,
+        })
+    }
+    fn write_archive(&self, content: &MemoryStorage) -> Result<(), MsdbError> {
+        let temp_path = format!("{}.tmp", self.path);
+        let file = File::create(&temp_path).map_err(|e| MsdbError::from_io(&temp_path, e))?;
+        let mut builder = tar::Builder::new(file);
+        for (name, data) in content.blobs() {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o600);
+            builder
+                .append_data(&mut header, name, &data[..])
+                .map_err(|e| MsdbError::from_io(&temp_path, e))?;
+        }
+        builder
+            .into_inner()
+            .and_then(|mut i| i.flush().and_then(|_| i.sync_all()))
+            .map_err(|e| MsdbError::from_io(&temp_path, e))?;
+        fs::rename(&temp_path, &self.path).map_err(|e| MsdbError::from_io(&temp_path, e))?;
+        let directory = path::Path::new(&self.path)
+            .parent()
+            .map(|i| i.to_string_lossy().to_string())
+            .filter(|i| !i.is_empty())
+            .unwrap_or_else(|| String::from("."));
+        binary_io::sync_directory(&directory)
+    }
+}
+impl Storage for TarStorage {
+    fn get(&mut self, name: &str) -> Result<Vec<u8>, MsdbError> {
+        self.content.get(name)
+    }
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<(), MsdbError> {
+        self.content.put(name, data)
+    }
+    fn delete(&mut self, name: &str) -> Result<(), MsdbError> {
+        self.content.delete(name)
+    }
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, MsdbError> {
+        self.content.list(prefix)
+    }
+    /// Rewrite the whole archive
+    fn commit(&mut self) -> Result<(), MsdbError> {
+        //Staged changes are kept if the archive cannot be written
+        let mut content = self.content.clone();
+        content.commit()?;
+        self.write_archive(&content)?;
+        self.content = content;
+        Ok(())
+    }
+    fn has_staged_changes(&self) -> bool {
+        self.content.has_staged_changes()
+    }
+    fn name(&self) -> &str {
+        &self.path
+    }
+    fn contains(&mut self, name: &str) -> bool {
+        self.content.contains(name)
+    }
+}
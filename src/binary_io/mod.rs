@@ -1,40 +1,118 @@
-use sodiumoxide::crypto::secretbox;
+use super::blockencrypt::{self, DatabaseKey};
+use super::compression::Compression;
+use super::error::MsdbError;
+use super::padding::Padding;
+use super::storage::Storage;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
-pub fn read_with_nonce(filename: &str) -> (Vec<u8>, secretbox::Nonce) {
-    (read_all(filename), read_nonce(filename))
+/// Read a blob together with the header in its `.nonce` blob
+pub fn read_with_nonce(
+    storage: &mut dyn Storage,
+    name: &str,
+) -> Result<(Vec<u8>, Vec<u8>), MsdbError> {
+    Ok((storage.get(name)?, storage.get(&format!("{}.nonce", name))?))
 }
-pub fn write_with_nonce(filename: &str, data: &[u8], nonce: secretbox::Nonce) {
-    let mut nonce_filename = filename.to_string();
-    nonce_filename.push_str(".nonce");
-    write_all(filename, data);
-    write_all(&nonce_filename[..], &nonce[..]);
+/// Stage a blob together with its `.nonce` blob
+pub fn write_with_nonce(
+    storage: &mut dyn Storage,
+    name: &str,
+    data: &[u8],
+    nonce: &[u8],
+) -> Result<(), MsdbError> {
+    storage.put(name, data)?;
+    storage.put(&format!("{}.nonce", name), nonce)
 }
-/// Write data to a file.
+/// Stage removal of a blob together with its `.nonce` blob
+pub fn remove_with_nonce(storage: &mut dyn Storage, name: &str) -> Result<(), MsdbError> {
+    storage.delete(name)?;
+    storage.delete(&format!("{}.nonce", name))
+}
+/// Read and decrypt the block at `path`, written at `epoch`
+pub fn read_block(
+    storage: &mut dyn Storage,
+    path: &str,
+    index: u64,
+    epoch: u64,
+    key: &DatabaseKey,
+) -> Result<Vec<u8>, MsdbError> {
+    let (block, header) = read_with_nonce(storage, path)?;
+    blockencrypt::decrypt_block(&block, key, path, index, epoch, &header)
+}
+/// Encrypt and stage the block at `path`
+pub fn write_block(
+    storage: &mut dyn Storage,
+    path: &str,
+    index: u64,
+    epoch: u64,
+    data: &[u8],
+    key: &DatabaseKey,
+    padding: &Padding,
+) -> Result<(), MsdbError> {
+    write_compressed_block(
+        storage,
+        path,
+        index,
+        epoch,
+        data,
+        key,
+        Compression::None,
+        padding,
+    )
+}
+/// Compress, pad, encrypt and stage the block at `path`
+#[allow(clippy::too_many_arguments)]
+pub fn write_compressed_block(
+    storage: &mut dyn Storage,
+    path: &str,
+    index: u64,
+    epoch: u64,
+    data: &[u8],
+    key: &DatabaseKey,
+    compression: Compression,
+    padding: &Padding,
+) -> Result<(), MsdbError> {
+    let (block, header) =
+        blockencrypt::encrypt_block(data, key, path, index, epoch, compression, padding);
+    write_with_nonce(storage, path, &block, &header)
+}
+/// Write data to a file outside of storage, such as the destination of `read > FILE`.
 ///
-/// Only use this function for writing salt, since there's no point for using it elsewhere.
-pub fn write_all(filename: &str, data: &[u8]) {
-    let mut file = File::create(filename).unwrap();
-    file.write_all(data).expect("Unable to write to file");
+/// Nothing is flushed; use [`write_all_synced`] where the file must survive a crash.
+pub fn write_all(filename: &str, data: &[u8]) -> Result<(), MsdbError> {
+    let mut file = File::create(filename).map_err(|e| MsdbError::from_io(filename, e))?;
+    file.write_all(data)
+        .map_err(|e| MsdbError::from_io(filename, e))
 }
-/// Read a file to a Vec<u8>.
+/// Write data to a file and flush it onto disk before returning
+pub fn write_all_synced(filename: &str, data: &[u8]) -> Result<(), MsdbError> {
+    let mut file = File::create(filename).map_err(|e| MsdbError::from_io(filename, e))?;
+    file.write_all(data)
+        .and_then(|_| file.sync_all())
+        .map_err(|e| MsdbError::from_io(filename, e))
+}
+/// Flush directory entries (created, renamed or removed files) onto disk
+#[cfg(unix)]
+pub fn sync_directory(directory: &str) -> Result<(), MsdbError> {
+    File::open(directory)
+        .and_then(|i| i.sync_all())
+        .map_err(|e| MsdbError::from_io(directory, e))
+}
+/// Flush directory entries (created, renamed or removed files) onto disk
 ///
-/// Only use this function for reading salt, since there's no point for using it elsewhere.
-pub fn read_all(filename: &str) -> Vec<u8> {
-    let file = File::open(filename).unwrap();
+/// Directories cannot be opened as files on this platform; renames are flushed by the filesystem.
+#[cfg(not(unix))]
+pub fn sync_directory(_directory: &str) -> Result<(), MsdbError> {
+    Ok(())
+}
+/// Read a whole file to a Vec<u8>, such as a blob being imported, a journal or a blob of a
+/// database directory.
+pub fn read_all(filename: &str) -> Result<Vec<u8>, MsdbError> {
+    let file = File::open(filename).map_err(|e| MsdbError::from_io(filename, e))?;
     let mut buf_reader = BufReader::new(file);
     let mut contents: Vec<u8> = Vec::new();
-    buf_reader.read_to_end(&mut contents).unwrap();
-    contents
-}
-fn read_nonce(filename: &str) -> secretbox::Nonce {
-    let mut filename = filename.to_string();
-    filename.push_str(".nonce");
-    let mut nonce_file = File::open(filename).expect("Error when opening nonce file");
-    let mut nonce_vec = Vec::new();
-    nonce_file
-        .read_to_end(&mut nonce_vec)
-        .expect("Error when reading nonce file");
-    secretbox::Nonce::from_slice(&nonce_vec[..]).unwrap()
+    buf_reader
+        .read_to_end(&mut contents)
+        .map_err(|e| MsdbError::from_io(filename, e))?;
+    Ok(contents)
 }
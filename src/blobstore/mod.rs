@@ -0,0 +1,265 @@
+//! Content-addressed blob store of a database
+//!
+//! Once enabled (`dedup on`), blob cells hold a reference into the store instead of their content.
+//! Content is addressed by a keyed hash (BLAKE2b, keyed from the data key) of its plaintext, so
+//! equal content is stored only once, and outsiders cannot tell what is stored. Each blob is kept
+//! as a [stream](crate::stream) in `blobs/streams/<id>/`.
+//!
+//! The index lives in main metadata attributes `blob.<hash>` (stream identifier, length and number
+//! of references), so hashes are only ever written encrypted, and the index is committed together
+//! with the cells referring to it.
+use super::blockencrypt::DatabaseKey;
+use super::error::MsdbError;
+use super::metadata::Metadata;
+use super::padding::Padding;
+use super::storage::Storage;
+use super::stream::{self, STREAM_ID_LEN};
+use sodiumoxide::crypto::generichash;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+pub const HASH_LEN: usize = 32;
+/// Owner of the streams of the store
+pub const DIRECTORY: &str = "blobs";
+pub const ENABLED_ATTRIBUTE: &str = "dedup";
+pub const ENTRY_PREFIX: &str = "blob.";
+struct Entry {
+    stream: [u8; STREAM_ID_LEN],
+    len: u64,
+    references: u64,
+}
+impl Entry {
+    /// `stream,len,references`
+    fn parse(value: &str) -> Option<Self> {
+        let mut value = value.split(',');
+        let stream = hex::decode(value.next()?).ok()?.try_into().ok()?;
+        let len = value.next()?.parse().ok()?;
+        let references = value.next()?.parse().ok()?;
+        Some(Self {
+            stream,
+            len,
+            references,
+        })
+    }
+    fn to_value(&self) -> String {
+        format!(
+            "{},{},{}",
+            hex::encode(self.stream),
+            self.len,
+            self.references
+        )
+    }
+}
+fn entry_key(hash: &[u8; HASH_LEN]) -> String {
+    format!("{}{}", ENTRY_PREFIX, hex::encode(hash))
+}
+fn entry(metadata: &Metadata, hash: &[u8; HASH_LEN]) -> Result<Option<Entry>, MsdbError> {
+    metadata
+        .attribute()
+        .get(&entry_key(hash))
+        .map(|i| {
+            Entry::parse(i).ok_or_else(|| {
+                MsdbError::CorruptBlock(format!("Invalid blob store entry {}", entry_key(hash)))
+            })
+        })
+        .transpose()
+}
+/// Whether new blob cells go into the store
+pub fn is_enabled(metadata: &Metadata) -> bool {
+    metadata.attribute().contains_key(ENABLED_ATTRIBUTE)
+}
+/// Whether nothing is stored, so no cell refers to the store
+pub fn is_empty(metadata: &Metadata) -> bool {
+    !metadata
+        .attribute()
+        .keys()
+        .any(|i| i.starts_with(ENTRY_PREFIX))
+}
+/// Blobs already in the store stay there when it is disabled
+pub fn set_enabled(metadata: &mut Metadata, enabled: bool) {
+    if enabled {
+        metadata.set_attribute(ENABLED_ATTRIBUTE, "on");
+    } else {
+        metadata.delete_attribute(ENABLED_ATTRIBUTE);
+    }
+}
+/// Feeds everything read through it into a hash
+struct HashingReader<'a, R: Read> {
+    inner: &'a mut R,
+    state: generichash::State,
+}
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.state.update(&buf[..len]).unwrap();
+        Ok(len)
+    }
+}
+/// Put everything from reader into the store, adding a reference. Returns hash and length.
+///
+/// Content is always streamed first, since its hash is only known at the end; the new stream is
+/// dropped again if the content is already stored.
+pub fn put(
+    metadata: &mut Metadata,
+    storage: &mut dyn Storage,
+    key: &DatabaseKey,
+    reader: &mut impl Read,
+) -> Result<([u8; HASH_LEN], u64), MsdbError> {
+    let mut reader = HashingReader {
+        inner: reader,
+        state: generichash::State::new(HASH_LEN, Some(key.blob_key())).unwrap(),
+    };
+    let padding = Padding::from_metadata(metadata)?;
+    let (id, len) = stream::write(storage, key, DIRECTORY, &mut reader, &padding)?;
+    let hash: [u8; HASH_LEN] = reader
+        .state
+        .finalize()
+        .unwrap()
+        .as_ref()
+        .try_into()
+        .unwrap();
+    let entry = match entry(metadata, &hash)? {
+        Some(mut i) => {
+            stream::remove(storage, DIRECTORY, &id)?;
+            i.references += 1;
+            i
+        }
+        None => Entry {
+            stream: id,
+            len,
+            references: 1,
+        },
+    };
+    metadata.set_attribute(&entry_key(&hash), &entry.to_value());
+    Ok((hash, len))
+}
+/// Drop a reference, removing the content with the last one
+pub fn release(
+    metadata: &mut Metadata,
+    storage: &mut dyn Storage,
+    hash: &[u8; HASH_LEN],
+) -> Result<(), MsdbError> {
+    let mut entry = entry(metadata, hash)?
+        .ok_or_else(|| MsdbError::NotFound(format!("Blob {}", hex::encode(hash))))?;
+    entry.references = entry.references.saturating_sub(1);
+    if entry.references == 0 {
+        stream::remove(storage, DIRECTORY, &entry.stream)?;
+        metadata.delete_attribute(&entry_key(hash));
+    } else {
+        metadata.set_attribute(&entry_key(hash), &entry.to_value());
+    }
+    Ok(())
+}
+/// Decrypt content into writer chunk by chunk, returning its length
+pub fn read(
+    metadata: &Metadata,
+    storage: &mut dyn Storage,
+    key: &DatabaseKey,
+    hash: &[u8; HASH_LEN],
+    writer: &mut (impl Write + ?Sized),
+) -> Result<u64, MsdbError> {
+    let entry = entry(metadata, hash)?
+        .ok_or_else(|| MsdbError::NotFound(format!("Blob {}", hex::encode(hash))))?;
+    stream::read(storage, key, DIRECTORY, &entry.stream, writer)
+}
+/// Set reference counts to `references`, found by walking every cell. Content no longer referred
+/// to, and streams left over by interrupted writes, are removed. Returns the number of blobs
+/// removed.
+pub fn recount(
+    metadata: &mut Metadata,
+    storage: &mut dyn Storage,
+    references: &HashMap<[u8; HASH_LEN], u64>,
+) -> Result<usize, MsdbError> {
+    let entries: Vec<(String, String)> = metadata
+        .attribute()
+        .iter()
+        .filter(|i| i.0.starts_with(ENTRY_PREFIX))
+        .map(|(i, j)| (i.clone(), j.clone()))
+        .collect();
+    let mut removed = 0;
+    let mut live_streams = Vec::new();
+    for (name, value) in entries {
+        let invalid = || MsdbError::CorruptBlock(format!("Invalid blob store entry {}", name));
+        let hash: [u8; HASH_LEN] = hex::decode(&name[ENTRY_PREFIX.len()..])
+            .ok()
+            .and_then(|i| i.try_into().ok())
+            .ok_or_else(invalid)?;
+        let mut entry = Entry::parse(&value).ok_or_else(invalid)?;
+        match references.get(&hash) {
+            Some(&i) => {
+                entry.references = i;
+                live_streams.push(stream::directory(DIRECTORY, &entry.stream));
+                metadata.set_attribute(&name, &entry.to_value());
+            }
+            None => {
+                metadata.delete_attribute(&name);
+                removed += 1;
+            }
+        }
+    }
+    for i in storage.list(&format!("{}/", DIRECTORY))? {
+        if !live_streams
+            .iter()
+            .any(|j| i.starts_with(&format!("{}/", j)))
+        {
+            storage.delete(&i)?;
+        }
+    }
+    Ok(removed)
+}
+#[cfg(test)]
+mod tests {
+    use super::{put, read, recount, release};
+    use crate::blockencrypt::DatabaseKey;
+    use crate::metadata::Metadata;
+    use crate::storage::{MemoryStorage, Storage};
+    use sodiumoxide::crypto::secretbox;
+    use std::collections::HashMap;
+    #[test]
+    fn deduplication() {
+        let key = DatabaseKey::new(secretbox::gen_key());
+        let mut storage = MemoryStorage::new("memory");
+        let mut metadata = Metadata::create();
+        let (logo, len) = put(&mut metadata, &mut storage, &key, &mut &b"logo"[..]).unwrap();
+        assert_eq!(len, 4);
+        let files = storage.list("blobs/").unwrap();
+        assert_eq!(
+            put(&mut metadata, &mut storage, &key, &mut &b"logo"[..]).unwrap(),
+            (logo, 4)
+        );
+        assert_eq!(storage.list("blobs/").unwrap(), files);
+        let (other, _) = put(&mut metadata, &mut storage, &key, &mut &b"other"[..]).unwrap();
+        assert_ne!(logo, other);
+        //Hash depends on the key
+        let mut another_database = MemoryStorage::new("memory");
+        let another_key = DatabaseKey::new(secretbox::gen_key());
+        let (hash, _) = put(
+            &mut Metadata::create(),
+            &mut another_database,
+            &another_key,
+            &mut &b"logo"[..],
+        )
+        .unwrap();
+        assert_ne!(hash, logo);
+        release(&mut metadata, &mut storage, &logo).unwrap();
+        let mut content = Vec::new();
+        read(&metadata, &mut storage, &key, &logo, &mut content).unwrap();
+        assert_eq!(content, b"logo");
+        release(&mut metadata, &mut storage, &logo).unwrap();
+        assert!(read(&metadata, &mut storage, &key, &logo, &mut Vec::new()).is_err());
+        assert!(storage
+            .list("blobs/")
+            .unwrap()
+            .iter()
+            .all(|i| !files.contains(i)));
+        //Only what is still referred to survives a recount
+        put(&mut metadata, &mut storage, &key, &mut &b"logo"[..]).unwrap();
+        let references: HashMap<_, _> = vec![(other, 2)].into_iter().collect();
+        assert_eq!(
+            recount(&mut metadata, &mut storage, &references).unwrap(),
+            1
+        );
+        assert!(read(&metadata, &mut storage, &key, &logo, &mut Vec::new()).is_err());
+        assert_eq!(storage.list("blobs/").unwrap().len(), 2);
+    }
+}
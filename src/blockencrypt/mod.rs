@@ -1,6 +1,134 @@
+use super::compression::Compression;
+use super::error::MsdbError;
+use super::padding::{self, Padding};
+use sodiumoxide::crypto::aead::xchacha20poly1305_ietf;
+use sodiumoxide::crypto::generichash;
 use sodiumoxide::crypto::pwhash;
+use sodiumoxide::crypto::pwhash::argon2id13;
 use sodiumoxide::crypto::secretbox;
-pub fn password_deriv(password: &str, salt: pwhash::Salt) -> secretbox::Key {
+use sodiumoxide::crypto::secretstream::xchacha20poly1305 as secretstream;
+use std::cell::Cell;
+use std::convert::TryInto;
+/// Format byte of block headers sealed with XChaCha20-Poly1305
+const AEAD_HEADER_VERSION: u8 = 1;
+/// Format byte of block headers which also carry the epoch
+const EPOCH_HEADER_VERSION: u8 = 2;
+/// Format byte of block headers which carry the epoch and the compression algorithm
+const COMPRESSED_HEADER_VERSION: u8 = 3;
+/// Format byte of block headers like [`COMPRESSED_HEADER_VERSION`], whose block is also padded
+const PADDED_HEADER_VERSION: u8 = 4;
+const BLOCK_AD_PREFIX: &[u8] = b"msdb-block";
+pub const DATABASE_ID_LEN: usize = 16;
+/// Password hashing algorithm and cost of a key slot
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kdf {
+    /// scrypt with interactive limits, used by databases created before Argon2 support
+    Scrypt,
+    Argon2id {
+        opslimit: usize,
+        memlimit: usize,
+    },
+}
+impl Default for Kdf {
+    fn default() -> Self {
+        Self::Argon2id {
+            opslimit: argon2id13::OPSLIMIT_INTERACTIVE.0,
+            memlimit: argon2id13::MEMLIMIT_INTERACTIVE.0,
+        }
+    }
+}
+impl Kdf {
+    /// Parse `interactive`, `moderate`, `sensitive` or explicit `OPS,MEM` (memory in bytes, `K`/`M`/`G` suffix allowed)
+    pub fn from_profile(profile: &str) -> Result<Self, MsdbError> {
+        let (opslimit, memlimit) = match profile {
+            "interactive" => (
+                argon2id13::OPSLIMIT_INTERACTIVE,
+                argon2id13::MEMLIMIT_INTERACTIVE,
+            ),
+            "moderate" => (argon2id13::OPSLIMIT_MODERATE, argon2id13::MEMLIMIT_MODERATE),
+            "sensitive" => (
+                argon2id13::OPSLIMIT_SENSITIVE,
+                argon2id13::MEMLIMIT_SENSITIVE,
+            ),
+            i => {
+                let invalid = || {
+                    MsdbError::InvalidArgument(format!(
+                        "Invalid KDF profile {}: use interactive, moderate, sensitive or OPS,MEM",
+                        i
+                    ))
+                };
+                let mut explicit = i.split(',');
+                let opslimit = explicit
+                    .next()
+                    .and_then(|j| j.parse().ok())
+                    .ok_or_else(invalid)?;
+                let memlimit = explicit.next().and_then(parse_size).ok_or_else(invalid)?;
+                if explicit.next().is_some() || opslimit == 0 {
+                    return Err(invalid());
+                }
+                (
+                    argon2id13::OpsLimit(opslimit),
+                    argon2id13::MemLimit(memlimit),
+                )
+            }
+        };
+        Ok(Self::Argon2id {
+            opslimit: opslimit.0,
+            memlimit: memlimit.0,
+        })
+    }
+    pub fn gen_salt(&self) -> Vec<u8> {
+        match self {
+            Self::Scrypt => pwhash::gen_salt()[..].to_vec(),
+            Self::Argon2id { .. } => argon2id13::gen_salt()[..].to_vec(),
+        }
+    }
+    pub fn derive(&self, password: &str, salt: &[u8]) -> Result<secretbox::Key, MsdbError> {
+        let invalid_salt = || MsdbError::CorruptBlock(String::from("Invalid salt"));
+        match self {
+            Self::Scrypt => password_deriv(
+                password,
+                pwhash::Salt::from_slice(salt).ok_or_else(invalid_salt)?,
+            ),
+            Self::Argon2id { opslimit, memlimit } => {
+                let salt = argon2id13::Salt::from_slice(salt).ok_or_else(invalid_salt)?;
+                let mut k = secretbox::Key([0; secretbox::KEYBYTES]);
+                {
+                    let secretbox::Key(ref mut kb) = k;
+                    argon2id13::derive_key(
+                        kb,
+                        password.as_bytes(),
+                        &salt,
+                        argon2id13::OpsLimit(*opslimit),
+                        argon2id13::MemLimit(*memlimit),
+                    )
+                    .map_err(|_| MsdbError::KeyDerivation)?;
+                }
+                Ok(k)
+            }
+        }
+    }
+}
+impl std::fmt::Display for Kdf {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Scrypt => write!(f, "scrypt"),
+            Self::Argon2id { opslimit, memlimit } => {
+                write!(f, "argon2id ops={} mem={}K", opslimit, memlimit / 1024)
+            }
+        }
+    }
+}
+fn parse_size(size: &str) -> Option<usize> {
+    let (number, unit) = match size.chars().last()? {
+        'K' | 'k' => (&size[..size.len() - 1], 1 << 10),
+        'M' | 'm' => (&size[..size.len() - 1], 1 << 20),
+        'G' | 'g' => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    number.parse::<usize>().ok()?.checked_mul(unit)
+}
+pub fn password_deriv(password: &str, salt: pwhash::Salt) -> Result<secretbox::Key, MsdbError> {
     let mut k = secretbox::Key([0; secretbox::KEYBYTES]);
     {
         let secretbox::Key(ref mut kb) = k;
@@ -11,26 +139,376 @@ pub fn password_deriv(password: &str, salt: pwhash::Salt) -> secretbox::Key {
             pwhash::OPSLIMIT_INTERACTIVE,
             pwhash::MEMLIMIT_INTERACTIVE,
         )
+        .map_err(|_| MsdbError::KeyDerivation)?;
+    }
+    Ok(k)
+}
+/// Data key of an unlocked database, together with the keys derived from it
+pub struct DatabaseKey {
+    data_key: secretbox::Key,
+    block_key: xchacha20poly1305_ietf::Key,
+    stream_key: secretstream::Key,
+    /// Key of the hash which addresses blobs in the blob store
+    blob_key: Vec<u8>,
+    id: [u8; DATABASE_ID_LEN],
+    /// Reject blocks sealed before associated data was introduced
+    aead_required: Cell<bool>,
+}
+impl DatabaseKey {
+    pub fn new(data_key: secretbox::Key) -> Self {
+        let block_key = xchacha20poly1305_ietf::Key::from_slice(&derive_subkey(
+            &data_key,
+            b"msdb-block-key",
+            xchacha20poly1305_ietf::KEYBYTES,
+        ))
+        .unwrap();
+        let stream_key = secretstream::Key::from_slice(&derive_subkey(
+            &data_key,
+            b"msdb-stream-key",
+            secretstream::KEYBYTES,
+        ))
         .unwrap();
+        let blob_key = derive_subkey(&data_key, b"msdb-blob-hash-key", generichash::KEY_MAX);
+        let mut id = [0; DATABASE_ID_LEN];
+        id.copy_from_slice(&derive_subkey(
+            &data_key,
+            b"msdb-database-id",
+            DATABASE_ID_LEN,
+        ));
+        Self {
+            data_key,
+            block_key,
+            stream_key,
+            blob_key,
+            id,
+            aead_required: Cell::new(false),
+        }
+    }
+    /// Key wrapped by key slots
+    pub const fn data_key(&self) -> &secretbox::Key {
+        &self.data_key
+    }
+    /// Key of blobs encrypted with `secretstream`, see [`crate::stream`]
+    pub const fn stream_key(&self) -> &secretstream::Key {
+        &self.stream_key
+    }
+    /// Key of the hash of blob content, see [`crate::blobstore`]
+    pub fn blob_key(&self) -> &[u8] {
+        &self.blob_key
+    }
+    /// Key of the file table of a single-file container, see [`crate::container`]
+    pub fn container_key(&self) -> secretbox::Key {
+        secretbox::Key::from_slice(&derive_subkey(
+            &self.data_key,
+            b"msdb-container-key",
+            secretbox::KEYBYTES,
+        ))
+        .unwrap()
+    }
+    /// Refuse legacy `secretbox` blocks from now on
+    pub fn require_aead(&self) {
+        self.aead_required.set(true);
+    }
+    /// Identifier of the database, shared by every copy of it
+    pub const fn id(&self) -> &[u8; DATABASE_ID_LEN] {
+        &self.id
+    }
+    /// `ad` of a block: database ID, block index, epoch (if any), encoding of the plaintext
+    /// (compression and padding, see [`encoding`]) and path relative to database root
+    fn associated_data(
+        &self,
+        path: &str,
+        index: u64,
+        epoch: Option<u64>,
+        encoding: &[u8],
+    ) -> Vec<u8> {
+        let mut ad = Vec::with_capacity(
+            BLOCK_AD_PREFIX.len() + DATABASE_ID_LEN + 16 + encoding.len() + path.len(),
+        );
+        ad.extend_from_slice(BLOCK_AD_PREFIX);
+        ad.extend_from_slice(&self.id);
+        ad.extend_from_slice(&index.to_le_bytes());
+        if let Some(epoch) = epoch {
+            ad.extend_from_slice(&epoch.to_le_bytes());
+        }
+        ad.extend_from_slice(encoding);
+        ad.extend_from_slice(path.as_bytes());
+        ad
     }
-    k
 }
-pub fn encrypt_block(input_block: &[u8], password: &secretbox::Key) -> (Vec<u8>, secretbox::Nonce) {
-    let nonce = secretbox::gen_nonce();
-    let plaintext = &input_block[..];
-    (secretbox::seal(plaintext, &nonce, password), nonce)
-    //let their_plaintext = secretbox::open(&ciphertext, &nonce, &key).unwrap();
-    //assert!(plaintext == &their_plaintext[..]);
+fn derive_subkey(key: &secretbox::Key, label: &[u8], len: usize) -> Vec<u8> {
+    let mut state = generichash::State::new(len, Some(&key[..])).unwrap();
+    state.update(label).unwrap();
+    state.finalize().unwrap().as_ref().to_vec()
+}
+/// Bytes of `ad` telling how the plaintext of a block with header `version` and compression
+/// algorithm byte `compression` is encoded, so neither can be rewritten in the header
+fn encoding(version: u8, compression: u8) -> Vec<u8> {
+    match version {
+        COMPRESSED_HEADER_VERSION => vec![compression],
+        PADDED_HEADER_VERSION => vec![PADDED_HEADER_VERSION, compression],
+        _ => Vec::new(),
+    }
+}
+/// Encrypt a block stored at `path` (relative to database root), returning ciphertext and header
+///
+/// The header is the content of `.nonce` file: a format byte, the epoch of the block, the
+/// compression algorithm if the block is compressed or padded, and the nonce. Blocks which do not
+/// get smaller are stored uncompressed. Blocks are unpadded on decryption whatever `padding` was.
+pub fn encrypt_block(
+    input_block: &[u8],
+    key: &DatabaseKey,
+    path: &str,
+    index: u64,
+    epoch: u64,
+    compression: Compression,
+    padding: &Padding,
+) -> (Vec<u8>, Vec<u8>) {
+    let nonce = xchacha20poly1305_ietf::gen_nonce();
+    let compressed = match compression {
+        Compression::None => None,
+        i => Some(i.compress(input_block)).filter(|j| j.len() < input_block.len()),
+    };
+    let (plaintext, compression) = match compressed {
+        Some(i) => (i, compression),
+        None => (input_block.to_vec(), Compression::None),
+    };
+    let (plaintext, version) = match (padding, compression) {
+        (Padding::None, Compression::None) => (plaintext, EPOCH_HEADER_VERSION),
+        (Padding::None, _) => (plaintext, COMPRESSED_HEADER_VERSION),
+        (i, _) => (i.pad(&plaintext), PADDED_HEADER_VERSION),
+    };
+    let ad = key.associated_data(
+        path,
+        index,
+        Some(epoch),
+        &encoding(version, compression.id()),
+    );
+    let ciphertext = xchacha20poly1305_ietf::seal(&plaintext, Some(&ad), &nonce, &key.block_key);
+    let mut header = vec![version];
+    header.extend_from_slice(&epoch.to_le_bytes());
+    if version != EPOCH_HEADER_VERSION {
+        header.push(compression.id());
+    }
+    header.extend_from_slice(&nonce[..]);
+    (ciphertext, header)
 }
+/// Decrypt a block read from `path`, which is expected to be written at `epoch`
+///
+/// Blocks moved to another path or index, or restored from another epoch, fail authentication.
+/// Legacy headers (a bare `secretbox` nonce) are accepted until the database is upgraded.
 pub fn decrypt_block(
     input_block: &[u8],
-    password: &secretbox::Key,
+    key: &DatabaseKey,
+    path: &str,
+    index: u64,
+    epoch: u64,
+    header: &[u8],
+) -> Result<Vec<u8>, MsdbError> {
+    if is_legacy_header(header) {
+        if key.aead_required.get() {
+            return Err(MsdbError::CorruptBlock(format!(
+                "{} is not authenticated with its location",
+                path
+            )));
+        }
+        let nonce = secretbox::Nonce::from_slice(header).unwrap();
+        return secretbox::open(input_block, &nonce, &key.data_key)
+            .map_err(|_| MsdbError::BadPassword);
+    }
+    let block_epoch = header_epoch(header);
+    if block_epoch != epoch {
+        return Err(MsdbError::CorruptBlock(format!(
+            "{} is from epoch {}, expected {}",
+            path, block_epoch, epoch
+        )));
+    }
+    let (version, nonce, compression) = match header.split_first() {
+        //Written before epochs were introduced
+        Some((&AEAD_HEADER_VERSION, nonce)) => (AEAD_HEADER_VERSION, nonce, 0),
+        Some((&EPOCH_HEADER_VERSION, nonce)) if nonce.len() > 8 => {
+            (EPOCH_HEADER_VERSION, &nonce[8..], 0)
+        }
+        Some((&i @ (COMPRESSED_HEADER_VERSION | PADDED_HEADER_VERSION), nonce))
+            if nonce.len() > 9 =>
+        {
+            (i, &nonce[9..], nonce[8])
+        }
+        _ => (0, &[][..], 0),
+    };
+    let ad = match version {
+        AEAD_HEADER_VERSION => key.associated_data(path, index, None, &[]),
+        i => key.associated_data(path, index, Some(epoch), &encoding(i, compression)),
+    };
+    let nonce = xchacha20poly1305_ietf::Nonce::from_slice(nonce)
+        .ok_or_else(|| MsdbError::CorruptBlock(format!("Invalid nonce file of {}", path)))?;
+    let mut plaintext =
+        xchacha20poly1305_ietf::open(input_block, Some(&ad), &nonce, &key.block_key)
+            .map_err(|_| MsdbError::CorruptBlock(format!("Authentication failed for {}", path)))?;
+    if version == PADDED_HEADER_VERSION {
+        plaintext = padding::unpad(plaintext)?;
+    }
+    match Compression::from_id(compression)? {
+        Compression::None => Ok(plaintext),
+        i => i.decompress(&plaintext),
+    }
+}
+/// Whether a block header is in the legacy `secretbox` format
+pub fn is_legacy_header(header: &[u8]) -> bool {
+    header.len() == secretbox::NONCEBYTES
+}
+/// Epoch recorded in a block header. Blocks written before epochs were introduced are at epoch 0.
+pub fn header_epoch(header: &[u8]) -> u64 {
+    match header.split_first() {
+        Some((&EPOCH_HEADER_VERSION | &COMPRESSED_HEADER_VERSION | &PADDED_HEADER_VERSION, i))
+            if i.len() >= 8 =>
+        {
+            u64::from_le_bytes(i[..8].try_into().unwrap())
+        }
+        _ => 0,
+    }
+}
+/// Encrypt a key with another key (usually derived from password)
+pub fn wrap_key(
+    key: &secretbox::Key,
+    wrapping_key: &secretbox::Key,
+) -> (Vec<u8>, secretbox::Nonce) {
+    let nonce = secretbox::gen_nonce();
+    (secretbox::seal(&key[..], &nonce, wrapping_key), nonce)
+}
+pub fn unwrap_key(
+    wrapped_key: &[u8],
+    wrapping_key: &secretbox::Key,
     nonce: secretbox::Nonce,
-) -> Vec<u8> {
-    if let Ok(m) = secretbox::open(input_block, &nonce, password) {
-        m
-    } else {
-        println!("Invalid password");
-        std::process::exit(0);
+) -> Result<secretbox::Key, MsdbError> {
+    let key =
+        secretbox::open(wrapped_key, &nonce, wrapping_key).map_err(|_| MsdbError::BadPassword)?;
+    secretbox::Key::from_slice(&key)
+        .ok_or_else(|| MsdbError::CorruptBlock(String::from("Invalid key header")))
+}
+#[cfg(test)]
+mod tests {
+    use super::{decrypt_block, encrypt_block, DatabaseKey, Kdf};
+    use crate::compression::Compression;
+    use crate::padding::Padding;
+    use sodiumoxide::crypto::secretbox;
+    #[test]
+    fn kdf_profile() {
+        assert_eq!(Kdf::from_profile("interactive").unwrap(), Kdf::default());
+        assert_eq!(
+            Kdf::from_profile("3,64M").unwrap(),
+            Kdf::Argon2id {
+                opslimit: 3,
+                memlimit: 64 << 20
+            }
+        );
+        assert!(Kdf::from_profile("fast").is_err());
+        assert!(Kdf::from_profile("0,64M").is_err());
+        assert!(Kdf::from_profile("3,64X").is_err());
+    }
+    #[test]
+    fn block_bound_to_location() {
+        let key = DatabaseKey::new(secretbox::gen_key());
+        let (block, header) = encrypt_block(
+            b"data",
+            &key,
+            "0011/2233/1.blk",
+            1,
+            7,
+            Compression::None,
+            &Padding::None,
+        );
+        assert_eq!(
+            decrypt_block(&block, &key, "0011/2233/1.blk", 1, 7, &header).unwrap(),
+            b"data"
+        );
+        assert!(decrypt_block(&block, &key, "0011/2233/0.blk", 0, 7, &header).is_err());
+        assert!(decrypt_block(&block, &key, "0011/4455/1.blk", 1, 7, &header).is_err());
+        assert!(decrypt_block(&block, &key, "0011/2233/1.blk", 1, 8, &header).is_err());
+        //Epoch in header cannot be rewritten
+        let mut forged_header = header.clone();
+        forged_header[1] = 8;
+        assert!(decrypt_block(&block, &key, "0011/2233/1.blk", 1, 8, &forged_header).is_err());
+        let other_database = DatabaseKey::new(secretbox::gen_key());
+        assert!(decrypt_block(&block, &other_database, "0011/2233/1.blk", 1, 7, &header).is_err());
+    }
+    #[test]
+    fn compressed_block() {
+        let key = DatabaseKey::new(secretbox::gen_key());
+        let data = b"note ".repeat(1000);
+        let (block, header) = encrypt_block(
+            &data,
+            &key,
+            "0011/2233/0.blk",
+            0,
+            7,
+            Compression::Lz4,
+            &Padding::None,
+        );
+        assert!(block.len() < data.len() / 10);
+        assert_eq!(
+            decrypt_block(&block, &key, "0011/2233/0.blk", 0, 7, &header).unwrap(),
+            data
+        );
+        //Algorithm in header cannot be rewritten
+        let mut forged_header = header.clone();
+        forged_header[9] = Compression::None.id();
+        assert!(decrypt_block(&block, &key, "0011/2233/0.blk", 0, 7, &forged_header).is_err());
+        //Incompressible blocks keep the uncompressed format
+        let (block, header) = encrypt_block(
+            b"data",
+            &key,
+            "0011/2233/0.blk",
+            0,
+            7,
+            Compression::Lz4,
+            &Padding::None,
+        );
+        assert_eq!(header.len(), 1 + 8 + 24);
+        assert_eq!(
+            decrypt_block(&block, &key, "0011/2233/0.blk", 0, 7, &header).unwrap(),
+            b"data"
+        );
+    }
+    #[test]
+    fn padded_block() {
+        let key = DatabaseKey::new(secretbox::gen_key());
+        let buckets = Padding::from_name("buckets:256,4096").unwrap();
+        let (short, header) =
+            encrypt_block(b"a", &key, "metadata", 0, 7, Compression::None, &buckets);
+        let (long, _) = encrypt_block(
+            &[b'a'; 200],
+            &key,
+            "metadata",
+            0,
+            7,
+            Compression::None,
+            &buckets,
+        );
+        assert_eq!(short.len(), long.len());
+        assert_eq!(
+            decrypt_block(&short, &key, "metadata", 0, 7, &header).unwrap(),
+            b"a"
+        );
+        //Padding cannot be passed off as content
+        let mut forged_header = header.clone();
+        forged_header[0] = 3;
+        assert!(decrypt_block(&short, &key, "metadata", 0, 7, &forged_header).is_err());
+        //Compressed and padded
+        let data = b"note ".repeat(1000);
+        let (block, header) = encrypt_block(
+            &data,
+            &key,
+            "0011/2233/0.blk",
+            0,
+            7,
+            Compression::Lz4,
+            &buckets,
+        );
+        assert_eq!(block.len(), 256 + 16);
+        assert_eq!(
+            decrypt_block(&block, &key, "0011/2233/0.blk", 0, 7, &header).unwrap(),
+            data
+        );
     }
 }
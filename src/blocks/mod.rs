@@ -1,5 +1,9 @@
 pub const CELL_IDENTIFIER_LENGTH: u32 = 8;
+use super::blobstore::HASH_LEN;
 use super::db_commands::METADATA_INDEX_LEN;
+use super::error::MsdbError;
+use super::stream::STREAM_ID_LEN;
+use super::utils;
 use std::collections::HashMap;
 use std::convert::TryInto;
 pub enum Cell {
@@ -8,41 +12,108 @@ pub enum Cell {
     Link(LinkType, LinkTarget, [u8; CELL_IDENTIFIER_LENGTH as usize]),
     LiteralIncomplete(Vec<u8>, IncompleteIdentifier),
     BlobIncomplete(Vec<u8>, IncompleteIdentifier),
+    Integer(i64, [u8; CELL_IDENTIFIER_LENGTH as usize]),
+    Float(f64, [u8; CELL_IDENTIFIER_LENGTH as usize]),
+    Boolean(bool, [u8; CELL_IDENTIFIER_LENGTH as usize]),
+    /// Seconds since Unix epoch
+    Timestamp(i64, [u8; CELL_IDENTIFIER_LENGTH as usize]),
+    Uuid([u8; 16], [u8; CELL_IDENTIFIER_LENGTH as usize]),
+    /// Blob kept in a stream outside of the field: stream identifier and length
+    Stream(
+        [u8; STREAM_ID_LEN],
+        u64,
+        [u8; CELL_IDENTIFIER_LENGTH as usize],
+    ),
+    /// Blob in the blob store of the database: hash of content and length
+    Shared([u8; HASH_LEN], u64, [u8; CELL_IDENTIFIER_LENGTH as usize]),
+}
+impl Cell {
+    /// Identifier of the object which the cell belongs to
+    pub const fn identifier(&self) -> [u8; CELL_IDENTIFIER_LENGTH as usize] {
+        match self {
+            Self::Literal(_, i) | Self::Blob(_, i) | Self::Link(_, _, i) => *i,
+            Self::LiteralIncomplete(_, i) | Self::BlobIncomplete(_, i) => i.identifier,
+            Self::Integer(_, i)
+            | Self::Float(_, i)
+            | Self::Boolean(_, i)
+            | Self::Timestamp(_, i)
+            | Self::Uuid(_, i) => *i,
+            Self::Stream(_, _, i) | Self::Shared(_, _, i) => *i,
+        }
+    }
+    /// Type name, as given to `new` and `alter`
+    pub const fn type_name(&self) -> &'static str {
+        match self {
+            Self::Literal(..) | Self::LiteralIncomplete(..) => "literal",
+            Self::Blob(..) | Self::BlobIncomplete(..) | Self::Stream(..) | Self::Shared(..) => {
+                "blob"
+            }
+            Self::Link(LinkType::Forward, ..) => "link",
+            Self::Link(LinkType::Reverse, ..) => "revlink",
+            Self::Integer(..) => "int",
+            Self::Float(..) => "float",
+            Self::Boolean(..) => "bool",
+            Self::Timestamp(..) => "timestamp",
+            Self::Uuid(..) => "uuid",
+        }
+    }
+    /// Value of an integer, float, boolean, timestamp or UUID cell, as shown to the user
+    pub fn typed_value(&self) -> Option<String> {
+        match self {
+            Self::Integer(i, _) => Some(i.to_string()),
+            Self::Float(i, _) => Some(i.to_string()),
+            Self::Boolean(i, _) => Some(i.to_string()),
+            Self::Timestamp(i, _) => Some(utils::format_timestamp(*i)),
+            Self::Uuid(i, _) => Some(utils::format_uuid(i)),
+            _ => None,
+        }
+    }
 }
 impl std::fmt::Display for Cell {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Self::Literal(i, j) => write!(f, "[Literal]({}) {}", hex::encode(j), i),
             Self::Blob(i, j) => write!(f, "[Blob]({}) {:?}", hex::encode(j), i),
+            Self::Stream(i, j, k) => write!(
+                f,
+                "[Blob]({}) stream {}, {} bytes",
+                hex::encode(k),
+                hex::encode(i),
+                j
+            ),
+            Self::Shared(i, j, k) => write!(
+                f,
+                "[Blob]({}) shared {}, {} bytes",
+                hex::encode(k),
+                hex::encode(i),
+                j
+            ),
             Self::Link(i, j, k) => write!(
                 f,
-                "{}",
-                format!(
-                    "[{}]({}) {}",
-                    match i {
-                        LinkType::Forward => "ForwardLink",
-                        LinkType::Reverse => "ReverseLink",
-                    },
-                    hex::encode(k),
-                    match j {
-                        LinkTarget::SameBlock(l) => hex::encode(l),
-                        LinkTarget::AnotherField(l, m) =>
-                            format!("{} -> {}", hex::encode(l), hex::encode(m)),
-                        LinkTarget::AnotherStruct(l, m, n) => format!(
-                            "{} -> {} -> {}",
-                            hex::encode(l),
-                            hex::encode(m),
-                            hex::encode(n)
-                        ),
-                    }
-                )
+                "[{}]({}) {}",
+                match i {
+                    LinkType::Forward => "ForwardLink",
+                    LinkType::Reverse => "ReverseLink",
+                },
+                hex::encode(k),
+                match j {
+                    LinkTarget::SameBlock(l) => hex::encode(l),
+                    LinkTarget::AnotherField(l, m) =>
+                        format!("{} -> {}", hex::encode(l), hex::encode(m)),
+                    LinkTarget::AnotherStruct(l, m, n) => format!(
+                        "{} -> {} -> {}",
+                        hex::encode(l),
+                        hex::encode(m),
+                        hex::encode(n)
+                    ),
+                }
             ),
             Self::LiteralIncomplete(i, j) => write!(
                 f,
                 "[LiteralIncomplete]({} - {}{}) {}",
                 hex::encode(j.identifier),
                 if j.is_final { "F" } else { "" },
-                format!("{}", j.num),
+                j.num,
                 hex::encode(i)
             ),
             Self::BlobIncomplete(i, j) => write!(
@@ -50,9 +121,20 @@ impl std::fmt::Display for Cell {
                 "[BlobIncomplete]({} - {}{}) {}",
                 hex::encode(j.identifier),
                 if j.is_final { "F" } else { "" },
-                format!("{}", j.num),
+                j.num,
                 hex::encode(i)
             ),
+            Self::Integer(..)
+            | Self::Float(..)
+            | Self::Boolean(..)
+            | Self::Timestamp(..)
+            | Self::Uuid(..) => write!(
+                f,
+                "[{}]({}) {}",
+                self.type_name(),
+                hex::encode(self.identifier()),
+                self.typed_value().unwrap()
+            ),
         }
     }
 }
@@ -72,9 +154,10 @@ pub enum LinkType {
     Forward,
     Reverse,
 }
+/// Position of a part in a literal or blob cell split across blocks
 pub struct IncompleteIdentifier {
     pub identifier: [u8; 8],
-    num: u8,
+    num: u32,
     is_final: bool,
 }
 pub struct BlockQueue {
@@ -98,10 +181,42 @@ impl std::fmt::Display for BlockQueue {
         write!(f, "{}", cached_block_string)
     }
 }
+fn metadata_identifier_at(
+    content: &[u8],
+    offset: usize,
+) -> Result<[u8; METADATA_INDEX_LEN as usize], MsdbError> {
+    content
+        .get(offset..offset + METADATA_INDEX_LEN as usize)
+        .and_then(|i| i.try_into().ok())
+        .ok_or_else(|| MsdbError::CorruptBlock(String::from("Link cell is too short")))
+}
+fn cell_identifier_at(
+    content: &[u8],
+    offset: usize,
+) -> Result<[u8; CELL_IDENTIFIER_LENGTH as usize], MsdbError> {
+    content
+        .get(offset..offset + CELL_IDENTIFIER_LENGTH as usize)
+        .and_then(|i| i.try_into().ok())
+        .ok_or_else(|| MsdbError::CorruptBlock(String::from("Link cell is too short")))
+}
+/// Content of a fixed-size cell
+fn fixed_content<const N: usize>(content: &[u8], cell_type: &str) -> Result<[u8; N], MsdbError> {
+    content
+        .try_into()
+        .map_err(|_| MsdbError::CorruptBlock(format!("{} cell should be {} bytes", cell_type, N)))
+}
+/// Opcode, size and identifier
+const CELL_HEADER_LENGTH: usize = 1 + 4 + CELL_IDENTIFIER_LENGTH as usize;
+/// Part number and whether it is the last part, before the content of a continuation
+const PART_HEADER_LENGTH: usize = 4 + 1;
+fn part_header(num: u32, is_final: bool) -> Vec<u8> {
+    let mut result = num.to_be_bytes().to_vec();
+    result.push(u8::from(is_final));
+    result
+}
 struct CellReadingBuffer {
     cell_size: u32,
     cell_opcode: u8,
-    current_byte_offset: u32,
     identifier: [u8; 8],
     content: Vec<u8>,
 }
@@ -110,12 +225,16 @@ impl CellReadingBuffer {
         Self {
             cell_size: 0,
             cell_opcode: 0,
-            current_byte_offset: 0,
             identifier: [0, 0, 0, 0, 0, 0, 0, 0],
             content: Vec::new(),
         }
     }
 }
+impl Default for BlockQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl BlockQueue {
     pub const fn new() -> Self {
         Self {
@@ -126,107 +245,41 @@ impl BlockQueue {
     pub fn import_from_vec(&mut self, raw_content: Vec<u8>) {
         self.queue.push(raw_content);
     }
-    pub fn from_vec(raw_content: Vec<u8>, default_cell_size: u32) -> Self {
+    pub fn from_vec(raw_content: Vec<u8>, default_cell_size: u32) -> Result<Self, MsdbError> {
         let mut result = Self::new();
         result.import_from_vec(raw_content);
-        result.raw_to_cell(default_cell_size);
-        result
+        result.raw_to_cell(default_cell_size)?;
+        Ok(result)
     }
-    pub fn raw_to_cell(&mut self, default_cell_size: u32) {
+    pub fn raw_to_cell(&mut self, default_cell_size: u32) -> Result<(), MsdbError> {
         let mut pre_translate_result: Vec<CellReadingBuffer> = Vec::new();
-        for i in &mut self.queue {
-            let mut current_cell = CellReadingBuffer::new();
-            let mut block_offset: u32 = 0;
-            let mut is_ended: bool = false;
-            let mut same_size_as_default: bool = true;
-            //在offset = len之前不停读取
-            while (block_offset as usize) < i.len() {
-                match current_cell.current_byte_offset {
-                    0 => {
-                        //第一Byte
-                        //1. 判断是否需要对cell_size进行对齐修改
-                        //2. 识别出OPCODE
-                        current_cell.cell_opcode =
-                            i[(current_cell.current_byte_offset + block_offset) as usize];
-                        match i[(current_cell.current_byte_offset + block_offset) as usize] % 2 {
-                            0 => {
-                                //size和default一样
-                                current_cell.cell_opcode -= 1;
-                            }
-                            1 => {
-                                //size和default不一样
-                                same_size_as_default = false;
-                            }
-                            _ => panic!("Error when trying to find opcode and size for a cell."),
-                        }
-                    }
-                    1..=12 => {
-                        if !same_size_as_default {
-                            //i[1]~i[4] => 大小
-                            //i[5]~i[12] => Identifier
-                            match current_cell.current_byte_offset {
-                                1..=4 => {
-                                    current_cell.cell_size += u32::from(
-                                        i[(current_cell.current_byte_offset + block_offset)
-                                            as usize],
-                                    ) * 256_u32
-                                        .pow(4 - current_cell.current_byte_offset)
-                                        as u32;
-                                }
-                                5..=12 => {
-                                    current_cell.identifier
-                                        [current_cell.current_byte_offset as usize - 5] = i
-                                        [(current_cell.current_byte_offset + block_offset)
-                                            as usize];
-                                }
-                                _ => panic!(
-                                    "Error when loading byte offset for a different size cell"
-                                ),
-                            }
-                        } else {
-                            //i[1]~i[8]  => identifier
-                            if let 1..=8 = current_cell.current_byte_offset {
-                                current_cell.cell_size = default_cell_size;
-                                current_cell.identifier
-                                    [current_cell.current_byte_offset as usize - 1] =
-                                    i[(current_cell.current_byte_offset + block_offset) as usize];
-                            } else {
-                                if current_cell.current_byte_offset == current_cell.cell_size + 8 {
-                                    is_ended = true;
-                                }
-                                current_cell.content.push(
-                                    i[(current_cell.current_byte_offset + block_offset) as usize],
-                                );
-                            }
-                        }
-                    }
-                    _ => {
-                        //读取内容，直到最后一Byte
-                        //首先检查剩余Byte数
-                        if current_cell.current_byte_offset
-                            == current_cell.cell_size + if same_size_as_default { 8 } else { 12 }
-                        {
-                            //目前的东西是最后一byte
-                            is_ended = true;
-                        }
-                        //读取内容
-                        current_cell
-                            .content
-                            .push(i[(current_cell.current_byte_offset + block_offset) as usize])
-                    }
-                }
-                if is_ended {
-                    //将byte_offset叠加
-                    block_offset +=
-                        current_cell.cell_size + if same_size_as_default { 9 } else { 13 };
-                    //将current_cell push到result中
-                    pre_translate_result.push(current_cell);
-                    current_cell = CellReadingBuffer::new();
-                    is_ended = false;
-                    same_size_as_default = true;
+        let exceeds = || MsdbError::CorruptBlock(String::from("cell exceeds the end of block"));
+        for i in &self.queue {
+            let mut rest = &i[..];
+            while let Some((&first_byte, remaining)) = rest.split_first() {
+                //第一Byte: OPCODE, 偶数表示size和default一样, 不写入size
+                let mut current_cell = CellReadingBuffer::new();
+                let remaining = if first_byte % 2 == 0 {
+                    current_cell.cell_opcode =
+                        first_byte.checked_sub(1).ok_or(MsdbError::BadOpcode(0))?;
+                    current_cell.cell_size = default_cell_size;
+                    remaining
                 } else {
-                    current_cell.current_byte_offset += 1;
-                }
+                    current_cell.cell_opcode = first_byte;
+                    let size = remaining.get(..4).ok_or_else(exceeds)?;
+                    current_cell.cell_size = u32::from_be_bytes(size.try_into().unwrap());
+                    &remaining[4..]
+                };
+                let identifier_length = CELL_IDENTIFIER_LENGTH as usize;
+                let end = identifier_length + current_cell.cell_size as usize;
+                current_cell.identifier =
+                    cell_identifier_at(remaining, 0).map_err(|_| exceeds())?;
+                current_cell.content = remaining
+                    .get(identifier_length..end)
+                    .ok_or_else(exceeds)?
+                    .to_vec();
+                rest = &remaining[end..];
+                pre_translate_result.push(current_cell);
             }
         }
         for i in pre_translate_result {
@@ -240,318 +293,163 @@ impl BlockQueue {
                 //11 - Link(Same Block + Reverse)
                 //13 - Link(Different Block + Reverse)
                 //15 - Link(Different Struct + Reverse)
-                //17 - Continue(Init - Text)
-                //19 - Continue(Init - Blob)
-                //21 - Continue
-                0 => panic!(
-                    "Found OPCODE - 0 in {} raw block",
-                    String::from_utf8(i.identifier.to_vec()).expect(
-                        "Unable to decode identifier to string when warning OPCODE failure"
-                    )
-                ),
+                //17 - Continue(Init - Text), part 0
+                //19 - Continue(Init - Blob), part 0
+                //21 - Continue (u32 part number, big endian, and is_final)
+                //23 - Integer (i64, big endian)
+                //25 - Float (f64, big endian)
+                //27 - Boolean (0 or 1)
+                //29 - Timestamp (i64 seconds since Unix epoch, big endian)
+                //31 - UUID
+                //33 - Stream (identifier, then u64 length, big endian)
+                //35 - Shared (hash, then u64 length, big endian)
                 1 => self.cells.push(Cell::Literal(
-                    String::from_utf8(i.content)
-                        .expect("Unable to read from literal cell to utf8 string"),
+                    String::from_utf8(i.content).map_err(|_| {
+                        MsdbError::CorruptBlock(String::from(
+                            "Unable to read from literal cell to utf8 string",
+                        ))
+                    })?,
                     i.identifier,
                 )),
                 3 => self.cells.push(Cell::Blob(i.content, i.identifier)),
-                5 => {
-                    //一共8 Byte
-                    let result_identifier: [u8; CELL_IDENTIFIER_LENGTH as usize] = i.content
-                        [0..CELL_IDENTIFIER_LENGTH as usize]
-                        .try_into()
-                        .unwrap();
-                    self.cells.push(Cell::Link(
-                        LinkType::Forward,
-                        LinkTarget::SameBlock(result_identifier),
-                        i.identifier,
-                    ));
-                }
-                7 => {
-                    //一共16 Byte
-                    let result_field_identifier: [u8; METADATA_INDEX_LEN as usize] = i.content
-                        [0..METADATA_INDEX_LEN as usize]
-                        .try_into()
-                        .unwrap();
-                    let result_cell_identifier: [u8; CELL_IDENTIFIER_LENGTH as usize] = i.content
-                        [METADATA_INDEX_LEN as usize
-                            ..(METADATA_INDEX_LEN + CELL_IDENTIFIER_LENGTH) as usize]
-                        .try_into()
-                        .unwrap();
-                    self.cells.push(Cell::Link(
-                        LinkType::Forward,
-                        LinkTarget::AnotherField(result_field_identifier, result_cell_identifier),
-                        i.identifier,
-                    ));
-                }
-                9 => {
-                    //一共24 Byte
-                    let result_struct_identifier: [u8; METADATA_INDEX_LEN as usize] = i.content
-                        [0..METADATA_INDEX_LEN as usize]
-                        .try_into()
-                        .unwrap();
-                    let result_field_identifier: [u8; METADATA_INDEX_LEN as usize] = i.content
-                        [METADATA_INDEX_LEN as usize
-                            ..(METADATA_INDEX_LEN + METADATA_INDEX_LEN) as usize]
-                        .try_into()
-                        .unwrap();
-                    let result_cell_identifier: [u8; CELL_IDENTIFIER_LENGTH as usize] = i.content
-                        [METADATA_INDEX_LEN as usize
-                            ..(METADATA_INDEX_LEN + CELL_IDENTIFIER_LENGTH) as usize]
-                        .try_into()
-                        .unwrap();
-                    self.cells.push(Cell::Link(
-                        LinkType::Forward,
-                        LinkTarget::AnotherStruct(
-                            result_struct_identifier,
-                            result_field_identifier,
-                            result_cell_identifier,
-                        ),
-                        i.identifier,
-                    ));
-                }
-                11 => {
+                5 | 11 => self.cells.push(Cell::Link(
+                    if i.cell_opcode == 5 {
+                        LinkType::Forward
+                    } else {
+                        LinkType::Reverse
+                    },
                     //一共8 Byte
-                    let result_identifier: [u8; CELL_IDENTIFIER_LENGTH as usize] = i.content
-                        [0..CELL_IDENTIFIER_LENGTH as usize]
-                        .try_into()
-                        .unwrap();
-                    self.cells.push(Cell::Link(
-                        LinkType::Reverse,
-                        LinkTarget::SameBlock(result_identifier),
-                        i.identifier,
-                    ));
-                }
-                13 => {
+                    LinkTarget::SameBlock(cell_identifier_at(&i.content, 0)?),
+                    i.identifier,
+                )),
+                7 | 13 => self.cells.push(Cell::Link(
+                    if i.cell_opcode == 7 {
+                        LinkType::Forward
+                    } else {
+                        LinkType::Reverse
+                    },
                     //一共16 Byte
-                    let result_field_identifier: [u8; METADATA_INDEX_LEN as usize] = i.content
-                        [0..METADATA_INDEX_LEN as usize]
-                        .try_into()
-                        .unwrap();
-                    let result_cell_identifier: [u8; CELL_IDENTIFIER_LENGTH as usize] = i.content
-                        [METADATA_INDEX_LEN as usize
-                            ..(METADATA_INDEX_LEN + CELL_IDENTIFIER_LENGTH) as usize]
-                        .try_into()
-                        .unwrap();
-                    self.cells.push(Cell::Link(
-                        LinkType::Reverse,
-                        LinkTarget::AnotherField(result_field_identifier, result_cell_identifier),
-                        i.identifier,
-                    ));
-                }
-                15 => {
-                    let result_struct_identifier: [u8; METADATA_INDEX_LEN as usize] = i.content
-                        [0..METADATA_INDEX_LEN as usize]
-                        .try_into()
-                        .unwrap();
-                    let result_field_identifier: [u8; METADATA_INDEX_LEN as usize] = i.content
-                        [METADATA_INDEX_LEN as usize
-                            ..(METADATA_INDEX_LEN + METADATA_INDEX_LEN) as usize]
-                        .try_into()
-                        .unwrap();
-                    let result_cell_identifier: [u8; CELL_IDENTIFIER_LENGTH as usize] = i.content
-                        [METADATA_INDEX_LEN as usize
-                            ..(METADATA_INDEX_LEN + CELL_IDENTIFIER_LENGTH) as usize]
-                        .try_into()
-                        .unwrap();
-                    self.cells.push(Cell::Link(
-                        LinkType::Reverse,
-                        LinkTarget::AnotherStruct(
-                            result_struct_identifier,
-                            result_field_identifier,
-                            result_cell_identifier,
-                        ),
-                        i.identifier,
-                    ));
-                }
-                17 => self.cells.push(Cell::LiteralIncomplete(
-                    i.content,
-                    IncompleteIdentifier {
-                        identifier: i.identifier,
-                        num: 0,
-                        is_final: false,
+                    LinkTarget::AnotherField(
+                        metadata_identifier_at(&i.content, 0)?,
+                        cell_identifier_at(&i.content, METADATA_INDEX_LEN as usize)?,
+                    ),
+                    i.identifier,
+                )),
+                9 | 15 => self.cells.push(Cell::Link(
+                    if i.cell_opcode == 9 {
+                        LinkType::Forward
+                    } else {
+                        LinkType::Reverse
                     },
+                    //一共24 Byte
+                    LinkTarget::AnotherStruct(
+                        metadata_identifier_at(&i.content, 0)?,
+                        metadata_identifier_at(&i.content, METADATA_INDEX_LEN as usize)?,
+                        cell_identifier_at(&i.content, 2 * METADATA_INDEX_LEN as usize)?,
+                    ),
+                    i.identifier,
                 )),
-                19 => self.cells.push(Cell::BlobIncomplete(
-                    i.content,
-                    IncompleteIdentifier {
+                17 | 19 => {
+                    let part = IncompleteIdentifier {
                         identifier: i.identifier,
                         num: 0,
                         is_final: false,
-                    },
-                )),
-                21 => {
-                    //第一Byte是序列号, 第二字节是is_final(1为真，0为否)
-                    let num: u8 = i.content[0];
-                    let is_final: bool = {
-                        match i.content[1] {
-                            0 => false,
-                            1 => true,
-                            _ => panic!("Error when parsing extended data"),
-                        }
                     };
-                    let mut cell_content = i.content;
-                    cell_content.remove(0);
-                    cell_content.remove(0);
-                    let mut identifier_list: (Vec<u8>, bool, bool) = (vec![num], false, false);
-                    if is_final {
-                        identifier_list.1 = true;
+                    self.cells.push(if i.cell_opcode == 17 {
+                        Cell::LiteralIncomplete(i.content, part)
                     } else {
-                        for j in &self.cells {
-                            if match j {
-                                Cell::BlobIncomplete(_, k) | Cell::LiteralIncomplete(_, k) => {
-                                    k.identifier
-                                }
-                                _ => [0; CELL_IDENTIFIER_LENGTH as usize],
-                            } == i.identifier
-                            {
-                                identifier_list.0.push(match j {
-                                    Cell::BlobIncomplete(_, k) | Cell::LiteralIncomplete(_, k) => {
-                                        k.num
-                                    }
-                                    _ => 0,
-                                });
-                                if match j {
-                                    Cell::BlobIncomplete(_, k) | Cell::LiteralIncomplete(_, k) => {
-                                        k.is_final
-                                    }
-                                    _ => false,
-                                } {
-                                    identifier_list.1 = true;
-                                }
-                            }
-                        }
-                    };
-                    if identifier_list.1 {
-                        identifier_list.2 = true;
-                        identifier_list.0.sort();
-                        for j in 0..identifier_list.0.len() - 1 {
-                            if identifier_list.0[j] + 1 != identifier_list.0[j + 1] {
-                                identifier_list.2 = false;
-                            }
-                        }
+                        Cell::BlobIncomplete(i.content, part)
+                    })
+                }
+                21 => {
+                    //前4字节是序列号(big endian), 第5字节是is_final(1为真，0为否)
+                    if i.content.len() < PART_HEADER_LENGTH {
+                        return Err(MsdbError::CorruptBlock(String::from(
+                            "Error when parsing extended data",
+                        )));
                     }
-                    if identifier_list.2 {
-                        let mut collapse_incomplete: HashMap<u8, &Cell> = HashMap::new();
-                        let mut content_result: Vec<u8> = Vec::new();
-                        let mut maxium_num: u8 = 0;
-                        let mut is_literal_cell = true;
-                        for j in &self.cells {
-                            if match j {
-                                Cell::BlobIncomplete(_, k) | Cell::LiteralIncomplete(_, k) => {
-                                    k.identifier
-                                }
-                                _ => [0; CELL_IDENTIFIER_LENGTH as usize],
-                            } == i.identifier
-                            {
-                                if let Cell::BlobIncomplete(_, _) = j {
-                                    is_literal_cell = false;
-                                }
-                                collapse_incomplete.insert(
-                                    match j {
-                                        Cell::BlobIncomplete(_, k)
-                                        | Cell::LiteralIncomplete(_, k) => k.num,
-                                        _ => 0,
-                                    },
-                                    j,
-                                );
-                                if match j {
-                                    Cell::BlobIncomplete(_, k) | Cell::LiteralIncomplete(_, k) => {
-                                        k.num
-                                    }
-                                    _ => 0,
-                                } > maxium_num
-                                {
-                                    maxium_num = match j {
-                                        Cell::BlobIncomplete(_, k)
-                                        | Cell::LiteralIncomplete(_, k) => k.num,
-                                        _ => 0,
-                                    };
-                                }
-                            }
+                    let num = u32::from_be_bytes(i.content[..4].try_into().unwrap());
+                    let is_final = match i.content[4] {
+                        0 => false,
+                        1 => true,
+                        _ => {
+                            return Err(MsdbError::CorruptBlock(String::from(
+                                "Error when parsing extended data",
+                            )))
                         }
-                        for j in 0..=maxium_num {
-                            if j == num {
-                                for l in &cell_content {
-                                    content_result.push(*l);
-                                }
-                            } else {
-                                let k = match collapse_incomplete.get(&j).unwrap() {
-                                    Cell::BlobIncomplete(l, _) | Cell::LiteralIncomplete(l, _) => l,
-                                    _ => panic!("Unexpected incomplete type"),
-                                };
-                                for l in k {
-                                    content_result.push(*l);
-                                }
-                            }
-                        }
-                        self.cells.push(if is_literal_cell {
-                            Cell::Literal(
-                                String::from_utf8(content_result).expect(
-                                    "Unable to read from multiple literal cell to utf8 string",
-                                ),
-                                i.identifier,
-                            )
-                        } else {
-                            Cell::Blob(content_result, i.identifier)
-                        });
-                    } else {
-                        let mut is_literal = true;
-                        for j in &self.cells {
-                            if match j {
-                                Cell::BlobIncomplete(_, k) => k.identifier,
-                                _ => [0; CELL_IDENTIFIER_LENGTH as usize],
-                            } == i.identifier
-                            {
-                                is_literal = false;
-                            }
+                    };
+                    //Whether it is a literal is only known from part 0, see collapse_incomplete
+                    self.cells.push(Cell::BlobIncomplete(
+                        i.content[PART_HEADER_LENGTH..].to_vec(),
+                        IncompleteIdentifier {
+                            identifier: i.identifier,
+                            num,
+                            is_final,
+                        },
+                    ))
+                }
+                23 => self.cells.push(Cell::Integer(
+                    i64::from_be_bytes(fixed_content(&i.content, "Integer")?),
+                    i.identifier,
+                )),
+                25 => self.cells.push(Cell::Float(
+                    f64::from_be_bytes(fixed_content(&i.content, "Float")?),
+                    i.identifier,
+                )),
+                27 => self.cells.push(Cell::Boolean(
+                    match fixed_content(&i.content, "Boolean")? {
+                        [0] => false,
+                        [1] => true,
+                        _ => {
+                            return Err(MsdbError::CorruptBlock(String::from(
+                                "Boolean cell should be 0 or 1",
+                            )))
                         }
-                        self.cells.push(if is_literal {
-                            Cell::LiteralIncomplete(
-                                cell_content,
-                                IncompleteIdentifier {
-                                    identifier: i.identifier,
-                                    num,
-                                    is_final,
-                                },
-                            )
-                        } else {
-                            Cell::BlobIncomplete(
-                                cell_content,
-                                IncompleteIdentifier {
-                                    identifier: i.identifier,
-                                    num,
-                                    is_final,
-                                },
-                            )
-                        })
-                    }
+                    },
+                    i.identifier,
+                )),
+                29 => self.cells.push(Cell::Timestamp(
+                    i64::from_be_bytes(fixed_content(&i.content, "Timestamp")?),
+                    i.identifier,
+                )),
+                31 => self
+                    .cells
+                    .push(Cell::Uuid(fixed_content(&i.content, "UUID")?, i.identifier)),
+                33 => {
+                    let content: [u8; STREAM_ID_LEN + 8] = fixed_content(&i.content, "Stream")?;
+                    self.cells.push(Cell::Stream(
+                        content[..STREAM_ID_LEN].try_into().unwrap(),
+                        u64::from_be_bytes(content[STREAM_ID_LEN..].try_into().unwrap()),
+                        i.identifier,
+                    ))
                 }
-                _ => {}
+                35 => {
+                    let content: [u8; HASH_LEN + 8] = fixed_content(&i.content, "Shared")?;
+                    self.cells.push(Cell::Shared(
+                        content[..HASH_LEN].try_into().unwrap(),
+                        u64::from_be_bytes(content[HASH_LEN..].try_into().unwrap()),
+                        i.identifier,
+                    ))
+                }
+                j => return Err(MsdbError::BadOpcode(j)),
             }
         }
+        self.collapse_incomplete()
     }
+    /// Encode cells into blocks of at most `vector_length` bytes. Literal and blob cells which do
+    /// not fit in a block are split into numbered parts.
     pub fn cell_to_raw(&mut self, vector_length: Option<u32>, default_cell_size: u32) {
         let mut pre_translate_result: Vec<CellReadingBuffer> = Vec::new();
-        let vector_length = if let Some(i) = vector_length {
-            i
-        } else {
-            u32::max_value()
-        };
+        let vector_length = vector_length.unwrap_or(u32::MAX);
         for i in &self.cells {
             let mut current_buffer = CellReadingBuffer::new();
-            current_buffer.identifier = match i {
-                Cell::Blob(_, j) | Cell::Link(_, _, j) | Cell::Literal(_, j) => *j,
-                Cell::BlobIncomplete(_, j) | Cell::LiteralIncomplete(_, j) => j.identifier,
-            };
+            current_buffer.identifier = i.identifier();
             match &i {
                 Cell::Literal(j, _) => {
-                    current_buffer.cell_size = j.len().try_into().unwrap();
                     current_buffer.cell_opcode = 1;
                     current_buffer.content = j.as_bytes().to_vec();
                 }
                 Cell::Blob(j, _) => {
-                    current_buffer.cell_size = j.len().try_into().unwrap();
                     current_buffer.cell_opcode = 3;
                     current_buffer.content = j.to_vec();
                 }
@@ -559,27 +457,15 @@ impl BlockQueue {
                     match k {
                         LinkTarget::SameBlock(k) => {
                             current_buffer.cell_opcode = 5;
-                            current_buffer.cell_size = 8;
                             current_buffer.content = k.to_vec();
                         }
                         LinkTarget::AnotherField(k, l) => {
                             current_buffer.cell_opcode = 7;
-                            current_buffer.cell_size = 16;
-                            current_buffer.content = k.to_vec();
-                            for m in l {
-                                current_buffer.content.push(*m);
-                            }
+                            current_buffer.content = [&k[..], &l[..]].concat();
                         }
                         LinkTarget::AnotherStruct(k, l, m) => {
                             current_buffer.cell_opcode = 9;
-                            current_buffer.cell_size = 24;
-                            current_buffer.content = k.to_vec();
-                            for n in l {
-                                current_buffer.content.push(*n);
-                            }
-                            for n in m {
-                                current_buffer.content.push(*n);
-                            }
+                            current_buffer.content = [&k[..], &l[..], &m[..]].concat();
                         }
                     }
                     if let LinkType::Reverse = j {
@@ -589,57 +475,156 @@ impl BlockQueue {
                 Cell::LiteralIncomplete(j, k) | Cell::BlobIncomplete(j, k) => {
                     if k.num == 0 {
                         current_buffer.cell_opcode = if let Cell::LiteralIncomplete(_, _) = &i {
-                            13
+                            17
                         } else {
-                            15
+                            19
                         };
-                        current_buffer.cell_size = j.len().try_into().unwrap();
                         current_buffer.content = j.to_vec();
                     } else {
-                        current_buffer.cell_opcode = 17;
-                        current_buffer.cell_size = (j.len() + 2).try_into().unwrap();
-                        current_buffer.content = vec![k.num, if k.is_final { 1 } else { 0 }];
-                        for l in j {
-                            current_buffer.content.push(*l);
-                        }
+                        current_buffer.cell_opcode = 21;
+                        current_buffer.content = part_header(k.num, k.is_final);
+                        current_buffer.content.extend_from_slice(j);
+                    }
+                }
+                Cell::Integer(j, _) | Cell::Timestamp(j, _) => {
+                    current_buffer.cell_opcode = if let Cell::Integer(_, _) = &i { 23 } else { 29 };
+                    current_buffer.content = j.to_be_bytes().to_vec();
+                }
+                Cell::Float(j, _) => {
+                    current_buffer.cell_opcode = 25;
+                    current_buffer.content = j.to_be_bytes().to_vec();
+                }
+                Cell::Boolean(j, _) => {
+                    current_buffer.cell_opcode = 27;
+                    current_buffer.content = vec![u8::from(*j)];
+                }
+                Cell::Uuid(j, _) => {
+                    current_buffer.cell_opcode = 31;
+                    current_buffer.content = j.to_vec();
+                }
+                Cell::Stream(j, k, _) => {
+                    current_buffer.cell_opcode = 33;
+                    current_buffer.content = [&j[..], &k.to_be_bytes()[..]].concat();
+                }
+                Cell::Shared(j, k, _) => {
+                    current_buffer.cell_opcode = 35;
+                    current_buffer.content = [&j[..], &k.to_be_bytes()[..]].concat();
+                }
+            }
+            let is_split = matches!(current_buffer.cell_opcode, 1 | 3)
+                && CELL_HEADER_LENGTH + current_buffer.content.len() > vector_length as usize;
+            if is_split {
+                //Every part, including the header of continuations, fits in a block
+                let part_length = (vector_length as usize)
+                    .saturating_sub(CELL_HEADER_LENGTH + PART_HEADER_LENGTH)
+                    .max(1);
+                let parts: Vec<&[u8]> = current_buffer.content.chunks(part_length).collect();
+                for (num, part) in parts.iter().enumerate() {
+                    let mut part_buffer = CellReadingBuffer::new();
+                    part_buffer.identifier = current_buffer.identifier;
+                    if num == 0 {
+                        part_buffer.cell_opcode = current_buffer.cell_opcode + 16;
+                        part_buffer.content = part.to_vec();
+                    } else {
+                        part_buffer.cell_opcode = 21;
+                        part_buffer.content = part_header(
+                            num.try_into().expect("Too many parts in a cell"),
+                            num + 1 == parts.len(),
+                        );
+                        part_buffer.content.extend_from_slice(part);
                     }
+                    part_buffer.cell_size = part_buffer.content.len().try_into().unwrap();
+                    pre_translate_result.push(part_buffer);
                 }
+            } else {
+                current_buffer.cell_size = current_buffer.content.len().try_into().unwrap();
+                pre_translate_result.push(current_buffer);
             }
-            pre_translate_result.push(current_buffer);
         }
         let mut result_vec: Vec<u8> = Vec::new();
-        for i in &mut pre_translate_result {
-            if result_vec.len() + 5 + i.identifier.len() + i.content.len()
-                > (vector_length as usize)
+        for i in &pre_translate_result {
+            if !result_vec.is_empty()
+                && result_vec.len() + CELL_HEADER_LENGTH + i.content.len()
+                    > (vector_length as usize)
             {
                 self.queue.push(result_vec);
                 result_vec = Vec::new();
             }
             //判断大小是否是默认大小
-            let mut first_byte: u8 = i.cell_opcode;
             if i.cell_size == default_cell_size {
-                first_byte += 1;
-                result_vec.push(first_byte);
+                result_vec.push(i.cell_opcode + 1);
             } else {
-                //计算大小
-                result_vec.push(first_byte);
-                for j in 0..4 {
-                    result_vec.push((i.cell_size / 256_u32.pow(3 - j)).try_into().unwrap());
-                    i.cell_size -= (i.cell_size / 256_u32.pow(3 - j)) * (256_u32.pow(3 - j));
-                }
-            }
-            for j in &i.identifier {
-                result_vec.push(*j);
+                result_vec.push(i.cell_opcode);
+                result_vec.extend_from_slice(&i.cell_size.to_be_bytes());
             }
+            result_vec.extend_from_slice(&i.identifier);
             //附上内容
-            for j in &i.content {
-                result_vec.push(*j);
-            }
+            result_vec.extend_from_slice(&i.content);
         }
         if !result_vec.is_empty() {
             self.queue.push(result_vec);
         }
     }
+    /// Rebuild literal and blob cells whose parts are all loaded. Cells missing a part are left
+    /// incomplete.
+    fn collapse_incomplete(&mut self) -> Result<(), MsdbError> {
+        let mut parts: HashMap<[u8; CELL_IDENTIFIER_LENGTH as usize], Vec<(u32, bool)>> =
+            HashMap::new();
+        for i in &self.cells {
+            if let Cell::LiteralIncomplete(_, j) | Cell::BlobIncomplete(_, j) = i {
+                parts
+                    .entry(j.identifier)
+                    .or_default()
+                    .push((j.num, j.is_final));
+            }
+        }
+        for (identifier, mut nums) in parts {
+            nums.sort_unstable();
+            //Parts 0 to n, of which only the last one is final
+            let is_complete = nums
+                .iter()
+                .enumerate()
+                .all(|(i, j)| j.0 as usize == i && j.1 == (i + 1 == nums.len()));
+            if !is_complete {
+                continue;
+            }
+            let mut position = None;
+            let mut pieces = Vec::new();
+            let mut remaining = Vec::new();
+            for i in std::mem::take(&mut self.cells) {
+                match i {
+                    Cell::LiteralIncomplete(j, k) if k.identifier == identifier => {
+                        position.get_or_insert(remaining.len());
+                        pieces.push((k.num, true, j));
+                    }
+                    Cell::BlobIncomplete(j, k) if k.identifier == identifier => {
+                        position.get_or_insert(remaining.len());
+                        pieces.push((k.num, false, j));
+                    }
+                    i => remaining.push(i),
+                }
+            }
+            pieces.sort_unstable_by_key(|i| i.0);
+            //Only the first part tells whether it is a literal
+            let is_literal = pieces[0].1;
+            let content: Vec<u8> = pieces.into_iter().flat_map(|i| i.2).collect();
+            let cell = if is_literal {
+                Cell::Literal(
+                    String::from_utf8(content).map_err(|_| {
+                        MsdbError::CorruptBlock(String::from(
+                            "Unable to read from multiple literal cell to utf8 string",
+                        ))
+                    })?,
+                    identifier,
+                )
+            } else {
+                Cell::Blob(content, identifier)
+            };
+            remaining.insert(position.unwrap(), cell);
+            self.cells = remaining;
+        }
+        Ok(())
+    }
     pub fn clean_cells(&mut self) {
         self.cells = Vec::new();
     }
@@ -650,11 +635,7 @@ impl BlockQueue {
         let mut position_deletd = Vec::new();
         let mut position = 0;
         for i in &self.cells {
-            if match i {
-                Cell::Blob(_, j) | Cell::Literal(_, j) | Cell::Link(_, _, j) => *j,
-                Cell::BlobIncomplete(_, j) | Cell::LiteralIncomplete(_, j) => j.identifier,
-            } == identifier
-            {
+            if i.identifier() == identifier {
                 position_deletd.push(position);
             } else {
                 position += 1;
@@ -664,6 +645,17 @@ impl BlockQueue {
             self.cells.remove(i);
         }
     }
+    /// Put cell in place of the cells of the same object, or append it if there are none
+    pub fn replace_cell(&mut self, cell: Cell) {
+        let identifier = cell.identifier();
+        match self.cells.iter().position(|i| i.identifier() == identifier) {
+            Some(position) => {
+                self.cells.retain(|i| i.identifier() != identifier);
+                self.cells.insert(position, cell);
+            }
+            None => self.cells.push(cell),
+        }
+    }
     pub fn delete_literal_cell_based_on_content(&mut self, content: &str) {
         let mut position_deletd = Vec::new();
         let mut position = 0;
@@ -683,3 +675,30 @@ impl BlockQueue {
         }
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::{BlockQueue, Cell};
+    #[test]
+    fn split_large_cells() {
+        let blob: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let literal = "多".repeat(30_000);
+        let mut queue = BlockQueue::new();
+        queue.import_cell(Cell::Blob(blob.clone(), [1; 8]));
+        queue.import_cell(Cell::Literal(String::new(), [2; 8]));
+        queue.import_cell(Cell::Literal(literal.clone(), [3; 8]));
+        queue.import_cell(Cell::Integer(-5, [4; 8]));
+        queue.cell_to_raw(Some(65536), 512);
+        assert!(queue.queue.len() > 4);
+        assert!(queue.queue.iter().all(|i| i.len() <= 65536));
+        let mut loaded = BlockQueue::new();
+        for i in queue.queue {
+            loaded.import_from_vec(i);
+        }
+        loaded.raw_to_cell(512).unwrap();
+        assert_eq!(loaded.cells.len(), 4);
+        assert!(matches!(&loaded.cells[0], Cell::Blob(i, j) if *i == blob && *j == [1; 8]));
+        assert!(matches!(&loaded.cells[1], Cell::Literal(i, j) if i.is_empty() && *j == [2; 8]));
+        assert!(matches!(&loaded.cells[2], Cell::Literal(i, j) if *i == literal && *j == [3; 8]));
+        assert!(matches!(loaded.cells[3], Cell::Integer(-5, j) if j == [4; 8]));
+    }
+}
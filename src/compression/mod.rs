@@ -0,0 +1,132 @@
+//! Optional compression of blocks before encryption
+//!
+//! The algorithm is kept in structure metadata attributes: `compression` for every field of the
+//! structure (and its cell list), and `compression.<field>` for a single field. It applies to
+//! blocks written from then on; the algorithm of each block is recorded in its header, so blocks
+//! written with another setting (or before compression existed) are read as they are.
+use super::error::MsdbError;
+use super::metadata::Metadata;
+pub const STRUCTURE_ATTRIBUTE: &str = "compression";
+pub const FIELD_PREFIX: &str = "compression.";
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// LZ4 block format, prefixed with the uncompressed length
+    Lz4,
+}
+impl Compression {
+    pub fn from_name(name: &str) -> Result<Self, MsdbError> {
+        match name.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "lz4" => Ok(Self::Lz4),
+            _ => Err(MsdbError::InvalidArgument(format!(
+                "Compression {} not reconized. Available: none, lz4",
+                name
+            ))),
+        }
+    }
+    /// Algorithm byte of block headers
+    pub const fn id(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Lz4 => 1,
+        }
+    }
+    pub fn from_id(id: u8) -> Result<Self, MsdbError> {
+        match id {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Lz4),
+            i => Err(MsdbError::CorruptBlock(format!(
+                "Unknown compression algorithm {}",
+                i
+            ))),
+        }
+    }
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::None => data.to_vec(),
+            Self::Lz4 => lz4_flex::compress_prepend_size(data),
+        }
+    }
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>, MsdbError> {
+        match self {
+            Self::None => Ok(data.to_vec()),
+            Self::Lz4 => lz4_flex::decompress_size_prepended(data)
+                .map_err(|e| MsdbError::CorruptBlock(format!("Unable to decompress block: {}", e))),
+        }
+    }
+    /// Algorithm for blocks of field in a structure: the one set for the field, or else the one of
+    /// the structure. The cell list (`field` is `None`) follows the structure.
+    pub fn of_field(metadata: &Metadata, field: Option<&str>) -> Result<Self, MsdbError> {
+        let attribute = metadata.attribute();
+        field
+            .and_then(|i| attribute.get(&format!("{}{}", FIELD_PREFIX, i)))
+            .or_else(|| attribute.get(STRUCTURE_ATTRIBUTE))
+            .map_or(Ok(Self::None), |i| Self::from_name(i))
+    }
+    /// Set the algorithm of a field, or of the structure if `field` is `None`
+    pub fn set(metadata: &mut Metadata, field: Option<&str>, compression: Self) {
+        match field {
+            Some(i) => {
+                metadata.set_attribute(&format!("{}{}", FIELD_PREFIX, i), &compression.to_string())
+            }
+            None => metadata.set_attribute(STRUCTURE_ATTRIBUTE, &compression.to_string()),
+        }
+    }
+    /// Make a field follow the structure again
+    pub fn unset(metadata: &mut Metadata, field: &str) {
+        metadata.delete_attribute(&format!("{}{}", FIELD_PREFIX, field));
+    }
+    /// Algorithms set for single fields
+    pub fn fields(metadata: &Metadata) -> Vec<(&str, &str)> {
+        metadata
+            .attribute()
+            .iter()
+            .filter_map(|(i, j)| Some((i.strip_prefix(FIELD_PREFIX)?, j.as_str())))
+            .collect()
+    }
+}
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::Compression;
+    use crate::metadata::Metadata;
+    #[test]
+    fn field_overrides_structure() {
+        let mut metadata = Metadata::create();
+        assert_eq!(
+            Compression::of_field(&metadata, Some("note")).unwrap(),
+            Compression::None
+        );
+        Compression::set(&mut metadata, None, Compression::Lz4);
+        Compression::set(&mut metadata, Some("photo"), Compression::None);
+        assert_eq!(
+            Compression::of_field(&metadata, Some("note")).unwrap(),
+            Compression::Lz4
+        );
+        assert_eq!(
+            Compression::of_field(&metadata, Some("photo")).unwrap(),
+            Compression::None
+        );
+        assert_eq!(Compression::fields(&metadata), vec![("photo", "none")]);
+        Compression::unset(&mut metadata, "photo");
+        assert_eq!(
+            Compression::of_field(&metadata, Some("photo")).unwrap(),
+            Compression::Lz4
+        );
+        let text = "note ".repeat(1000);
+        let compressed = Compression::Lz4.compress(text.as_bytes());
+        assert!(compressed.len() < text.len() / 10);
+        assert_eq!(
+            Compression::Lz4.decompress(&compressed).unwrap(),
+            text.as_bytes()
+        );
+    }
+}
@@ -0,0 +1,475 @@
+//! Single-file database layout
+//!
+//! A container starts with two superblock slots, followed by extents holding the content of every
+//! file. The extent table maps each file name (its path in the directory layout) to an extent, and
+//! is stored in an extent itself.
+//!
+//! Only the names needed to unlock the database (key slots and legacy `salt` and `key`) are kept in
+//! plaintext. The rest of the table is sealed with a key derived from the data key, so names and
+//! sizes of blocks are only known after [`Storage::unlock`]. Until then, nothing else can be
+//! written, and new extents go after the end of file.
+//!
+//! Changes are written into free space first. `commit` then writes a new table and finally the
+//! superblock slot not in use, so an interrupted commit leaves the previous state intact.
+use super::blockencrypt::DatabaseKey;
+use super::error::MsdbError;
+use super::storage::Storage;
+use sodiumoxide::crypto::generichash;
+use sodiumoxide::crypto::secretbox;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+/// Databases created with a name ending in this extension use the single-file layout
+pub const EXTENSION: &str = ".msdbc";
+const MAGIC: &[u8; 8] = b"MSDBCTNR";
+const FORMAT_VERSION: u8 = 2;
+/// Table entirely in plaintext
+const PLAINTEXT_TABLE_VERSION: u8 = 1;
+/// Names kept in the plaintext part of the table
+const PUBLIC_NAMES: [&str; 3] = ["salt", "key", "key.nonce"];
+const PUBLIC_PREFIX: &str = "keyslots/";
+/// Each superblock slot takes a sector of its own
+const SLOT_SIZE: u64 = 512;
+const SUPERBLOCK_LEN: usize = 72;
+const CHECKSUM_LEN: usize = 16;
+/// Extents start after both superblock slots
+const DATA_START: u64 = 4096;
+/// File name to extent
+type Table = BTreeMap<String, Extent>;
+/// Generation, table extent, plaintext part and sealed part of the table in a superblock slot
+type Slot = (u64, Extent, Table, Vec<u8>);
+#[derive(Clone, Copy, PartialEq)]
+struct Extent {
+    offset: u64,
+    len: u64,
+}
+impl Extent {
+    const fn end(self) -> u64 {
+        self.offset + self.len
+    }
+}
+pub struct Container {
+    path: String,
+    file: File,
+    generation: u64,
+    table_extent: Extent,
+    committed: Table,
+    /// Table including staged changes
+    table: Table,
+    /// Key of the sealed part of the table
+    key: Option<secretbox::Key>,
+    /// Sealed part of the committed table, until it is unlocked
+    sealed: Option<Vec<u8>>,
+}
+impl Container {
+    /// Create an empty container. Existing files are not overwritten.
+    pub fn create(path: &str) -> Result<Self, MsdbError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| MsdbError::from_io(path, e))?;
+        let mut container = Self {
+            path: path.to_string(),
+            file,
+            generation: 0,
+            table_extent: Extent { offset: 0, len: 0 },
+            committed: BTreeMap::new(),
+            table: BTreeMap::new(),
+            key: None,
+            sealed: None,
+        };
+        container.commit()?;
+        Ok(container)
+    }
+    pub fn open(path: &str) -> Result<Self, MsdbError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| MsdbError::from_io(path, e))?;
+        let mut container = Self {
+            path: path.to_string(),
+            file,
+            generation: 0,
+            table_extent: Extent { offset: 0, len: 0 },
+            committed: BTreeMap::new(),
+            table: BTreeMap::new(),
+            key: None,
+            sealed: None,
+        };
+        let mut newest = None;
+        for slot in 0..2 {
+            if let Some((generation, table_extent, table, sealed)) = container.read_slot(slot)? {
+                if newest.as_ref().is_none_or(|(i, _, _, _)| generation > *i) {
+                    newest = Some((generation, table_extent, table, sealed));
+                }
+            }
+        }
+        let (generation, table_extent, table, sealed) = newest
+            .ok_or_else(|| MsdbError::CorruptBlock(format!("{} is not a valid container", path)))?;
+        container.generation = generation;
+        container.table_extent = table_extent;
+        container.committed = table.clone();
+        container.table = table;
+        container.sealed = Some(sealed).filter(|i| !i.is_empty());
+        Ok(container)
+    }
+    /// Find free space which is used neither by the committed state nor by staged changes
+    fn allocate(&self, len: u64) -> Result<Extent, MsdbError> {
+        if len == 0 {
+            return Ok(Extent { offset: 0, len: 0 });
+        }
+        if self.sealed.is_some() {
+            //Extents in the sealed part of the table are unknown
+            let end = self.file_len()?;
+            return Ok(Extent {
+                offset: end.max(DATA_START),
+                len,
+            });
+        }
+        let mut used: Vec<Extent> = self
+            .committed
+            .values()
+            .chain(self.table.values())
+            .copied()
+            .chain(std::iter::once(self.table_extent))
+            .filter(|i| i.len > 0)
+            .collect();
+        used.sort_by_key(|i| i.offset);
+        let mut offset = DATA_START;
+        for i in used {
+            if i.offset >= offset + len {
+                break;
+            }
+            offset = offset.max(i.end());
+        }
+        Ok(Extent { offset, len })
+    }
+    fn read_slot(&mut self, slot: u64) -> Result<Option<Slot>, MsdbError> {
+        let mut superblock = vec![0; SUPERBLOCK_LEN];
+        if self
+            .file
+            .seek(SeekFrom::Start(slot * SLOT_SIZE))
+            .and_then(|_| self.file.read_exact(&mut superblock))
+            .is_err()
+        {
+            return Ok(None);
+        }
+        let (content, superblock_checksum) = superblock.split_at(SUPERBLOCK_LEN - CHECKSUM_LEN);
+        if &content[..8] != MAGIC || checksum(content)[..] != *superblock_checksum {
+            return Ok(None);
+        }
+        if content[8] != FORMAT_VERSION && content[8] != PLAINTEXT_TABLE_VERSION {
+            return Err(MsdbError::CorruptBlock(format!(
+                "Unsupported container version {}",
+                content[8]
+            )));
+        }
+        let read_u64 = |i: usize| u64::from_le_bytes(content[i..i + 8].try_into().unwrap());
+        let generation = read_u64(16);
+        let table_extent = Extent {
+            offset: read_u64(24),
+            len: read_u64(32),
+        };
+        //The checksum is not keyed, so the extent is not trusted either
+        if table_extent.offset.checked_add(table_extent.len).is_none() {
+            return Err(MsdbError::CorruptBlock(format!(
+                "Invalid table extent in {}",
+                self.path
+            )));
+        }
+        let table = match self.read_extent(table_extent) {
+            Ok(i) if checksum(&i)[..] == content[40..40 + CHECKSUM_LEN] => i,
+            //Table of an interrupted commit
+            _ => return Ok(None),
+        };
+        let table = if content[8] == PLAINTEXT_TABLE_VERSION {
+            decode_table(&table).map(|i| (i, Vec::new()))
+        } else {
+            split_table(&table)
+        };
+        let (table, sealed) = table
+            .ok_or_else(|| MsdbError::CorruptBlock(format!("Invalid table in {}", self.path)))?;
+        Ok(Some((generation, table_extent, table, sealed)))
+    }
+    fn file_len(&self) -> Result<u64, MsdbError> {
+        self.file
+            .metadata()
+            .map(|i| i.len())
+            .map_err(|e| MsdbError::from_io(&self.path, e))
+    }
+    fn read_extent(&mut self, extent: Extent) -> Result<Vec<u8>, MsdbError> {
+        if extent.end() > self.file_len()? {
+            return Err(MsdbError::CorruptBlock(format!(
+                "Extent beyond end of {}",
+                self.path
+            )));
+        }
+        let mut data = vec![0; extent.len as usize];
+        if extent.len > 0 {
+            self.file
+                .seek(SeekFrom::Start(extent.offset))
+                .and_then(|_| self.file.read_exact(&mut data))
+                .map_err(|e| MsdbError::from_io(&self.path, e))?;
+        }
+        Ok(data)
+    }
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), MsdbError> {
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.write_all(data))
+            .map_err(|e| MsdbError::from_io(&self.path, e))
+    }
+    fn sync(&mut self) -> Result<(), MsdbError> {
+        self.file
+            .sync_data()
+            .map_err(|e| MsdbError::from_io(&self.path, e))
+    }
+    /// Refuse names in the sealed part of the table until it is unlocked
+    fn check_unlocked(&self, name: &str) -> Result<(), MsdbError> {
+        if self.sealed.is_some() && !is_public(name) {
+            return Err(MsdbError::InvalidArgument(format!(
+                "{} is locked, unable to write {}",
+                self.path, name
+            )));
+        }
+        Ok(())
+    }
+    /// Length of the plaintext part, plaintext part, then the sealed part (nonce and ciphertext,
+    /// empty if there is nothing to seal)
+    fn encode_tables(&self) -> Result<Vec<u8>, MsdbError> {
+        let (public, private): (Table, Table) = self
+            .table
+            .iter()
+            .map(|(i, j)| (i.clone(), *j))
+            .partition(|(i, _)| is_public(i));
+        let public = encode_table(&public);
+        let mut result = (public.len() as u32).to_le_bytes().to_vec();
+        result.extend_from_slice(&public);
+        match (&self.sealed, &self.key) {
+            (Some(sealed), _) => result.extend_from_slice(sealed),
+            (None, _) if private.is_empty() => {}
+            (None, Some(key)) => {
+                let nonce = secretbox::gen_nonce();
+                result.extend_from_slice(nonce.as_ref());
+                result.extend_from_slice(&secretbox::seal(&encode_table(&private), &nonce, key));
+            }
+            (None, None) => {
+                return Err(MsdbError::InvalidArgument(format!(
+                    "{} is locked, unable to write its table",
+                    self.path
+                )))
+            }
+        }
+        Ok(result)
+    }
+}
+impl Storage for Container {
+    fn get(&mut self, name: &str) -> Result<Vec<u8>, MsdbError> {
+        let extent = *self
+            .table
+            .get(name)
+            .ok_or_else(|| MsdbError::MissingFile(format!("{}:{}", self.path, name)))?;
+        self.read_extent(extent)
+    }
+    /// Staged content goes straight into free space
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<(), MsdbError> {
+        self.check_unlocked(name)?;
+        self.table.remove(name);
+        let extent = self.allocate(data.len() as u64)?;
+        self.write_at(extent.offset, data)?;
+        self.table.insert(name.to_string(), extent);
+        Ok(())
+    }
+    fn delete(&mut self, name: &str) -> Result<(), MsdbError> {
+        self.check_unlocked(name)?;
+        self.table.remove(name);
+        Ok(())
+    }
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, MsdbError> {
+        Ok(self
+            .table
+            .range(prefix.to_string()..)
+            .map(|i| i.0)
+            .take_while(|i| i.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+    fn has_staged_changes(&self) -> bool {
+        self.table != self.committed
+    }
+    fn commit(&mut self) -> Result<(), MsdbError> {
+        let table = self.encode_tables()?;
+        let table_extent = self.allocate(table.len() as u64)?;
+        self.write_at(table_extent.offset, &table)?;
+        self.sync()?;
+        let generation = self.generation + 1;
+        let mut superblock = Vec::with_capacity(SUPERBLOCK_LEN);
+        superblock.extend_from_slice(MAGIC);
+        superblock.push(FORMAT_VERSION);
+        superblock.extend_from_slice(&[0; 7]);
+        superblock.extend_from_slice(&generation.to_le_bytes());
+        superblock.extend_from_slice(&table_extent.offset.to_le_bytes());
+        superblock.extend_from_slice(&table_extent.len.to_le_bytes());
+        superblock.extend_from_slice(&checksum(&table));
+        superblock.extend_from_slice(&checksum(&superblock));
+        self.write_at((generation % 2) * SLOT_SIZE, &superblock)?;
+        self.sync()?;
+        self.generation = generation;
+        self.table_extent = table_extent;
+        self.committed = self.table.clone();
+        Ok(())
+    }
+    /// Decrypt the sealed part of the table
+    fn unlock(&mut self, key: &DatabaseKey) -> Result<(), MsdbError> {
+        let key = key.container_key();
+        if let Some(sealed) = &self.sealed {
+            let private = open_table(sealed, &key).ok_or_else(|| {
+                MsdbError::CorruptBlock(format!("Unable to decrypt table of {}", self.path))
+            })?;
+            self.committed.extend(private.clone());
+            self.table.extend(private);
+            self.sealed = None;
+        }
+        self.key = Some(key);
+        Ok(())
+    }
+    fn name(&self) -> &str {
+        &self.path
+    }
+    fn size(&mut self, name: &str) -> Result<u64, MsdbError> {
+        self.table
+            .get(name)
+            .map(|i| i.len)
+            .ok_or_else(|| MsdbError::MissingFile(format!("{}:{}", self.path, name)))
+    }
+    fn contains(&mut self, name: &str) -> bool {
+        self.table.contains_key(name)
+    }
+}
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut state = generichash::State::new(CHECKSUM_LEN, None).unwrap();
+    state.update(data).unwrap();
+    state.finalize().unwrap().as_ref().try_into().unwrap()
+}
+fn is_public(name: &str) -> bool {
+    PUBLIC_NAMES.contains(&name) || name.starts_with(PUBLIC_PREFIX)
+}
+/// `count`, then `name length`, `name`, `offset` and `length` of each file (little endian)
+fn encode_table(table: &Table) -> Vec<u8> {
+    let mut result = (table.len() as u32).to_le_bytes().to_vec();
+    for (name, extent) in table {
+        result.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        result.extend_from_slice(name.as_bytes());
+        result.extend_from_slice(&extent.offset.to_le_bytes());
+        result.extend_from_slice(&extent.len.to_le_bytes());
+    }
+    result
+}
+fn decode_table(data: &[u8]) -> Option<Table> {
+    let mut rest = data;
+    let mut take = |len: usize| {
+        if rest.len() < len {
+            return None;
+        }
+        let (current, remaining) = rest.split_at(len);
+        rest = remaining;
+        Some(current)
+    };
+    let count = u32::from_le_bytes(take(4)?.try_into().ok()?);
+    let mut table = Table::new();
+    for _ in 0..count {
+        let name_len = u16::from_le_bytes(take(2)?.try_into().ok()?);
+        let name = String::from_utf8(take(name_len as usize)?.to_vec()).ok()?;
+        let offset = u64::from_le_bytes(take(8)?.try_into().ok()?);
+        let len = u64::from_le_bytes(take(8)?.try_into().ok()?);
+        offset.checked_add(len)?;
+        table.insert(name, Extent { offset, len });
+    }
+    Some(table)
+}
+/// Plaintext and sealed part of a table written by [`Container::encode_tables`]
+fn split_table(data: &[u8]) -> Option<(Table, Vec<u8>)> {
+    let public_len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let public = data.get(4..4 + public_len)?;
+    Some((decode_table(public)?, data[4 + public_len..].to_vec()))
+}
+fn open_table(sealed: &[u8], key: &secretbox::Key) -> Option<Table> {
+    let nonce = secretbox::Nonce::from_slice(sealed.get(..secretbox::NONCEBYTES)?)?;
+    let table = secretbox::open(&sealed[secretbox::NONCEBYTES..], &nonce, key).ok()?;
+    decode_table(&table)
+}
+#[cfg(test)]
+mod tests {
+    use super::{checksum, Container, Extent, FORMAT_VERSION, MAGIC};
+    use crate::blockencrypt::DatabaseKey;
+    use crate::storage::Storage;
+    use sodiumoxide::crypto::secretbox;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    #[test]
+    fn commit_and_reopen() {
+        let path = "containertest.msdbc";
+        let key = DatabaseKey::new(secretbox::gen_key());
+        let mut container = Container::create(path).unwrap();
+        container.unlock(&key).unwrap();
+        container.put("keyslots/0", b"slot").unwrap();
+        container.put("metadata", b"first").unwrap();
+        container.put("0011/2233/0.blk", b"block").unwrap();
+        container.commit().unwrap();
+        container.put("metadata", b"second").unwrap();
+        container.delete("0011/2233/0.blk").unwrap();
+        //Staged changes are lost without commit
+        let mut reopened = Container::open(path).unwrap();
+        assert_eq!(reopened.list("").unwrap(), vec!["keyslots/0"]);
+        assert!(reopened.put("metadata", b"locked").is_err());
+        reopened.unlock(&key).unwrap();
+        assert_eq!(reopened.get("metadata").unwrap(), b"first");
+        assert_eq!(
+            reopened.list("").unwrap(),
+            vec!["0011/2233/0.blk", "keyslots/0", "metadata"]
+        );
+        container.commit().unwrap();
+        let mut reopened = Container::open(path).unwrap();
+        assert!(reopened
+            .unlock(&DatabaseKey::new(secretbox::gen_key()))
+            .is_err());
+        reopened.unlock(&key).unwrap();
+        assert_eq!(reopened.get("metadata").unwrap(), b"second");
+        assert!(!reopened.contains("0011/2233/0.blk"));
+        assert!(reopened
+            .read_extent(Extent {
+                offset: 1 << 40,
+                len: 1 << 40
+            })
+            .is_err());
+        let content = fs::read(path).unwrap();
+        let find = |name: &[u8]| content.windows(name.len()).any(|i| i == name);
+        assert!(find(b"keyslots/0"));
+        assert!(!find(b"metadata") && !find(b"0011/2233"));
+        assert!(Container::create(path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+    #[test]
+    fn overflowing_table_extent() {
+        let path = "containeroverflow.msdbc";
+        Container::create(path).unwrap();
+        //Newer superblock in slot 0 with a table extent past the end of address space
+        let mut superblock = MAGIC.to_vec();
+        superblock.push(FORMAT_VERSION);
+        superblock.extend_from_slice(&[0; 7]);
+        superblock.extend_from_slice(&2u64.to_le_bytes());
+        superblock.extend_from_slice(&u64::MAX.to_le_bytes());
+        superblock.extend_from_slice(&2u64.to_le_bytes());
+        superblock.extend_from_slice(&checksum(b""));
+        superblock.extend_from_slice(&checksum(&superblock));
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.write_all(&superblock).unwrap();
+        drop(file);
+        assert!(Container::open(path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+//! Credentials which unlock a key slot
+use super::blockencrypt::Kdf;
+use super::error::MsdbError;
+use sodiumoxide::crypto::secretbox;
+use std::fs::File;
+use std::io::Read;
+/// Environment variable read by [`Credential::from_env`]
+pub const PASSWORD_ENV: &str = "MSDB_PASSWORD";
+pub enum Credential {
+    /// Password, stretched with the password hashing parameters and salt of key slot
+    Password(String),
+    /// Raw 32-byte key, used directly without `pwhash`
+    Keyfile(secretbox::Key),
+}
+impl Credential {
+    pub fn password(password: &str) -> Self {
+        Self::Password(password.to_string())
+    }
+    /// Read password from a file. A trailing line break is ignored.
+    pub fn from_password_file(filename: &str) -> Result<Self, MsdbError> {
+        let file = File::open(filename).map_err(|e| MsdbError::from_io(filename, e))?;
+        Self::from_reader(filename, file)
+    }
+    /// Read password from an inherited file descriptor until EOF. A trailing line break is ignored.
+    ///
+    /// The descriptor is left open. Standard input, output and error (0 to 2) are refused, since
+    /// they are used for commands and messages.
+    #[cfg(unix)]
+    pub fn from_password_fd(fd: i32) -> Result<Self, MsdbError> {
+        use std::mem::ManuallyDrop;
+        use std::os::unix::io::FromRawFd;
+        if fd <= 2 {
+            return Err(MsdbError::InvalidArgument(format!(
+                "File descriptor {} cannot be used for password. Please use a descriptor above 2",
+                fd
+            )));
+        }
+        //The descriptor belongs to the caller, so it must not be closed when the file is dropped
+        let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        Self::from_reader(&format!("file descriptor {}", fd), &mut *file)
+    }
+    #[cfg(not(unix))]
+    pub fn from_password_fd(_fd: i32) -> Result<Self, MsdbError> {
+        Err(MsdbError::InvalidArgument(String::from(
+            "--password-fd is not supported on this platform",
+        )))
+    }
+    /// Read password from `MSDB_PASSWORD`, if set
+    pub fn from_env() -> Option<Self> {
+        std::env::var(PASSWORD_ENV).ok().map(Self::Password)
+    }
+    /// Read a raw key from a file of exactly 32 bytes
+    pub fn from_keyfile(filename: &str) -> Result<Self, MsdbError> {
+        let mut content = Vec::new();
+        File::open(filename)
+            .and_then(|mut i| i.read_to_end(&mut content))
+            .map_err(|e| MsdbError::from_io(filename, e))?;
+        secretbox::Key::from_slice(&content)
+            .map(Self::Keyfile)
+            .ok_or_else(|| {
+                MsdbError::InvalidArgument(format!(
+                    "Keyfile {} must be exactly {} bytes",
+                    filename,
+                    secretbox::KEYBYTES
+                ))
+            })
+    }
+    pub const fn is_keyfile(&self) -> bool {
+        matches!(self, Self::Keyfile(_))
+    }
+    /// Key used to wrap the data key. `kdf` and `salt` are ignored for keyfiles.
+    pub fn wrapping_key(&self, kdf: &Kdf, salt: &[u8]) -> Result<secretbox::Key, MsdbError> {
+        match self {
+            Self::Password(password) => kdf.derive(password, salt),
+            Self::Keyfile(key) => Ok(key.clone()),
+        }
+    }
+    fn from_reader<R: Read>(source: &str, mut reader: R) -> Result<Self, MsdbError> {
+        let mut password = String::new();
+        reader
+            .read_to_string(&mut password)
+            .map_err(|e| MsdbError::from_io(source, e))?;
+        if password.ends_with('\n') {
+            password.pop();
+            if password.ends_with('\r') {
+                password.pop();
+            }
+        }
+        Ok(Self::Password(password))
+    }
+}
+#[cfg(all(test, unix))]
+mod tests {
+    use super::Credential;
+    use std::io::{pipe, Read, Write};
+    use std::os::unix::io::AsRawFd;
+    #[test]
+    fn password_from_pipe() {
+        let (mut reader, mut writer) = pipe().unwrap();
+        writer.write_all(b"password\n").unwrap();
+        drop(writer);
+        match Credential::from_password_fd(reader.as_raw_fd()).unwrap() {
+            Credential::Password(i) => assert_eq!(i, "password"),
+            Credential::Keyfile(_) => panic!("Pipe read as keyfile"),
+        }
+        //Still open: reading reaches EOF instead of failing with a bad descriptor
+        assert_eq!(reader.read(&mut [0; 1]).unwrap(), 0);
+    }
+    #[test]
+    fn standard_streams_refused() {
+        for fd in 0..=2 {
+            assert!(Credential::from_password_fd(fd).is_err());
+        }
+    }
+}
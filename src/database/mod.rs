@@ -0,0 +1,745 @@
+use super::blobstore;
+use super::blockencrypt::{DatabaseKey, Kdf};
+use super::blocks;
+use super::credential::Credential;
+use super::db_commands::{self, DatabaseLocation, Structure, VacuumReport, METADATA_INDEX_LEN};
+use super::error::MsdbError;
+use super::keyslot;
+use super::metadata;
+use super::padding::Padding;
+use super::storage::{self, Storage};
+use super::tokenizer;
+use super::trust::TrustFile;
+use super::utils;
+use super::VERSION_NUMBER;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+/// An unlocked database.
+///
+/// Owns everything needed to operate on a database: its storage, the main metadata, the structure cache and the data key.
+/// Nothing will be written to disk until `sync` is called.
+pub struct Database {
+    location: DatabaseLocation,
+    storage: Box<dyn Storage>,
+    password: DatabaseKey,
+    keyslot: u32,
+    main_metadata: metadata::Metadata,
+    structure_cache: HashMap<[u8; METADATA_INDEX_LEN as usize], Structure>,
+    trust: Option<TrustFile>,
+}
+impl Database {
+    /// Create a database and unlock it
+    pub fn create(database_name: &str, password: &str) -> Result<Self, MsdbError> {
+        Self::create_with_credential(
+            database_name,
+            &Credential::password(password),
+            Kdf::default(),
+        )
+    }
+    /// Create a database whose first key slot is unlocked by credential
+    ///
+    /// `kdf` is the password hashing cost of the slot, ignored for keyfiles.
+    pub fn create_with_credential(
+        database_name: &str,
+        credential: &Credential,
+        kdf: Kdf,
+    ) -> Result<Self, MsdbError> {
+        Self::create_in(storage::create(database_name)?, credential, kdf)
+    }
+    /// Create a database inside empty storage, such as [`storage::MemoryStorage`]
+    pub fn create_in(
+        mut storage: Box<dyn Storage>,
+        credential: &Credential,
+        kdf: Kdf,
+    ) -> Result<Self, MsdbError> {
+        sodiumoxide::init().expect("Unable to initialize SoldiumMoxide");
+        let key = utils::new_database(storage.as_mut(), credential, kdf, VERSION_NUMBER)?;
+        Self::unlock(storage, 0, key)
+    }
+    /// Decrypt(Load) a database
+    pub fn open(database_name: &str, password: &str) -> Result<Self, MsdbError> {
+        Self::open_with_credential(database_name, &Credential::password(password))
+    }
+    /// Decrypt(Load) a database with a password or keyfile
+    pub fn open_with_credential(
+        database_name: &str,
+        credential: &Credential,
+    ) -> Result<Self, MsdbError> {
+        Self::open_in(storage::open(database_name)?, credential)
+    }
+    /// Decrypt(Load) a database inside storage
+    pub fn open_in(
+        mut storage: Box<dyn Storage>,
+        credential: &Credential,
+    ) -> Result<Self, MsdbError> {
+        sodiumoxide::init().expect("Unable to initialize SoldiumMoxide");
+        let (keyslot, key) = utils::derive_database_key(storage.as_mut(), credential)?;
+        Self::unlock(storage, keyslot, key)
+    }
+    fn unlock(
+        mut storage: Box<dyn Storage>,
+        keyslot: u32,
+        password: DatabaseKey,
+    ) -> Result<Self, MsdbError> {
+        storage.unlock(&password)?;
+        let main_metadata = utils::select_database(storage.as_mut(), &password, VERSION_NUMBER)?;
+        let mut location = DatabaseLocation::new();
+        location.select_root(storage.name().to_string());
+        Ok(Self {
+            location,
+            storage,
+            password,
+            keyslot,
+            main_metadata,
+            structure_cache: HashMap::new(),
+            trust: None,
+        })
+    }
+    /// Check the database against a trust file, which then records every later `sync`
+    pub fn set_trust_file(&mut self, mut trust: TrustFile) -> Result<(), MsdbError> {
+        trust.check(self.password.id(), self.epoch()?)?;
+        self.trust = Some(trust);
+        Ok(())
+    }
+    /// Whether the database still accepts blocks written by older versions, which carry no epoch
+    /// and are not bound to their location. Such databases are opened as they are; call `upgrade`
+    /// to re-encrypt them.
+    pub fn needs_upgrade(&self) -> bool {
+        !utils::is_upgraded(&self.main_metadata)
+    }
+    /// Number of `sync`s done on the database
+    pub fn epoch(&self) -> Result<u64, MsdbError> {
+        utils::read_epoch(&self.main_metadata, utils::EPOCH_ATTRIBUTE)
+    }
+    fn record_epoch(&mut self) -> Result<(), MsdbError> {
+        let epoch = self.epoch()?;
+        match self.trust.as_mut() {
+            Some(i) => i.record(self.password.id(), epoch),
+            None => Ok(()),
+        }
+    }
+    /// Change the password of the key slot which unlocked the database
+    pub fn change_password(&mut self, password: &str) -> Result<(), MsdbError> {
+        self.change_credential(&Credential::password(password))
+    }
+    /// Change the credential (password or keyfile) of the key slot which unlocked the database
+    pub fn change_credential(&mut self, credential: &Credential) -> Result<(), MsdbError> {
+        keyslot::change_credential(
+            self.storage.as_mut(),
+            self.keyslot,
+            credential,
+            self.password.data_key(),
+        )
+    }
+    /// Number of the key slot which unlocked the database
+    pub const fn keyslot(&self) -> u32 {
+        self.keyslot
+    }
+    pub fn keyslots(&mut self) -> Result<Vec<(u32, keyslot::KeySlot)>, MsdbError> {
+        keyslot::list(self.storage.as_mut())
+    }
+    /// Add a key slot unlocked by credential, returning its number
+    pub fn add_keyslot(
+        &mut self,
+        label: &str,
+        credential: &Credential,
+        kdf: Kdf,
+    ) -> Result<u32, MsdbError> {
+        keyslot::add(
+            self.storage.as_mut(),
+            label,
+            credential,
+            kdf,
+            self.password.data_key(),
+        )
+    }
+    /// Re-hash the password of current key slot with new cost parameters
+    pub fn retune(&mut self, password: &Credential, kdf: Kdf) -> Result<(), MsdbError> {
+        keyslot::retune(
+            self.storage.as_mut(),
+            self.keyslot,
+            password,
+            kdf,
+            self.password.data_key(),
+        )
+    }
+    pub fn label_keyslot(&mut self, keyslot: u32, label: &str) -> Result<(), MsdbError> {
+        keyslot::set_label(
+            self.storage.as_mut(),
+            keyslot,
+            label,
+            self.password.data_key(),
+        )
+    }
+    /// Remove a key slot, so its password can no longer unlock the database
+    pub fn revoke_keyslot(&mut self, keyslot: u32) -> Result<(), MsdbError> {
+        keyslot::revoke(self.storage.as_mut(), keyslot, self.password.data_key())
+    }
+    /// Drop everything inside cache. Changes not synced will be lost.
+    pub fn close(self) {}
+    pub const fn location(&self) -> &DatabaseLocation {
+        &self.location
+    }
+    pub const fn main_metadata(&self) -> &metadata::Metadata {
+        &self.main_metadata
+    }
+    /// Execute a database command, as if it was typed in the command line interface
+    ///
+    /// Arguments are split by [`tokenizer::split`].
+    pub fn run_command(&mut self, command: &str) -> Result<(), MsdbError> {
+        self.run_arguments(&tokenizer::split(command)?)
+    }
+    /// Execute a database command which is already split into arguments
+    pub fn run_arguments(&mut self, arguments: &[String]) -> Result<(), MsdbError> {
+        db_commands::run_commands(
+            arguments,
+            &mut self.main_metadata,
+            &mut self.location,
+            &self.password,
+            self.storage.as_mut(),
+            &mut self.structure_cache,
+        )?;
+        self.record_epoch()
+    }
+    pub fn create_structure(&mut self, structure_name: &str) -> Result<(), MsdbError> {
+        if self.main_metadata.sub_data().get(structure_name).is_some() {
+            return Err(MsdbError::AlreadyExists(format!(
+                "Structure {}",
+                structure_name
+            )));
+        }
+        db_commands::create_structure(
+            structure_name,
+            &self.password,
+            self.storage.as_mut(),
+            &mut self.main_metadata,
+            None,
+        )
+    }
+    /// Select a structure and load its metadata and object list into cache
+    pub fn select_structure(&mut self, structure_name: &str) -> Result<(), MsdbError> {
+        self.location.deselect_structure();
+        db_commands::select_structure(
+            structure_name,
+            &self.password,
+            self.storage.as_mut(),
+            &mut self.main_metadata,
+            &mut self.location,
+            &mut self.structure_cache,
+        )
+    }
+    pub fn delete_structure(&mut self, structure_name: &str) -> Result<(), MsdbError> {
+        db_commands::delete_structure(
+            structure_name,
+            &self.password,
+            self.storage.as_mut(),
+            &mut self.main_metadata,
+            &mut self.structure_cache,
+        )?;
+        //It is no longer cached
+        if self.location.current_structure_pretty_name() == Some(structure_name) {
+            self.location.deselect_structure();
+        }
+        Ok(())
+    }
+    /// Create a object in current structure
+    pub fn create_object(&mut self, object_name: &str) -> Result<(), MsdbError> {
+        self.require_structure()?;
+        if db_commands::find_object(object_name, &self.location, &self.structure_cache).is_some() {
+            return Err(MsdbError::AlreadyExists(format!("Object {}", object_name)));
+        }
+        db_commands::create_object(
+            object_name,
+            &mut self.main_metadata,
+            &self.password,
+            self.storage.as_mut(),
+            &mut self.location,
+            &mut self.structure_cache,
+        )
+    }
+    /// Select a object in current structure
+    pub fn select_object(&mut self, object_name: &str) -> Result<(), MsdbError> {
+        self.require_structure()?;
+        self.location.deselect_object();
+        db_commands::select_object(object_name, &mut self.location, &mut self.structure_cache)
+    }
+    /// Delete a object in current structure
+    pub fn delete_object(&mut self, object_name: &str) -> Result<(), MsdbError> {
+        self.require_structure()?;
+        db_commands::delete_object(object_name, &mut self.location, &mut self.structure_cache)
+    }
+    /// Delete a object in current structure together with its cells in every field, then sync
+    ///
+    /// Refused if there are changes not synced yet, which would otherwise be committed together
+    /// with the wipe. Returns the number of cells and bytes reclaimed.
+    pub fn wipe_object(&mut self, object_name: &str) -> Result<(usize, usize), MsdbError> {
+        self.require_structure()?;
+        let result = db_commands::wipe_object(
+            object_name,
+            &mut self.main_metadata,
+            &mut self.location,
+            &self.password,
+            self.storage.as_mut(),
+            &mut self.structure_cache,
+        )?;
+        self.record_epoch()?;
+        Ok(result)
+    }
+    /// Create a cell in current object
+    ///
+    /// `cell_type` is one of `literal`, `blob`, `link` and `revlink`.
+    pub fn create_cell(
+        &mut self,
+        field_name: &str,
+        cell_type: &str,
+        cell_content: &str,
+    ) -> Result<(), MsdbError> {
+        self.require_object()?;
+        if field_name == "list" {
+            return Err(MsdbError::InvalidArgument(String::from(
+                "You cannot create a cell with reserved name `list`. Please try another name.",
+            )));
+        }
+        db_commands::create_cell(
+            field_name,
+            cell_type,
+            cell_content,
+            &mut self.main_metadata,
+            &self.password,
+            self.storage.as_mut(),
+            &mut self.location,
+            &mut self.structure_cache,
+        )
+    }
+    /// Change cell content / cell type in current object. Without `cell_type`, the cell keeps its
+    /// type.
+    pub fn alter_cell(
+        &mut self,
+        field_name: &str,
+        cell_type: Option<&str>,
+        cell_content: &str,
+    ) -> Result<(), MsdbError> {
+        self.require_object()?;
+        db_commands::alter_cell(
+            field_name,
+            cell_type,
+            cell_content,
+            &mut self.main_metadata,
+            &self.password,
+            self.storage.as_mut(),
+            &self.location,
+            &mut self.structure_cache,
+        )
+    }
+    /// Delete a cell in current object
+    pub fn delete_cell(&mut self, field_name: &str) -> Result<(), MsdbError> {
+        self.require_object()?;
+        db_commands::delete_cell(
+            field_name,
+            &mut self.main_metadata,
+            self.storage.as_mut(),
+            &self.location,
+            &mut self.structure_cache,
+        )
+    }
+    /// Put everything from reader into a blob cell of current object, replacing the cell if there
+    /// is one. Content is encrypted chunk by chunk, so it is never held in memory as a whole.
+    /// Returns its length.
+    pub fn write_blob(
+        &mut self,
+        field_name: &str,
+        reader: &mut impl Read,
+    ) -> Result<u64, MsdbError> {
+        self.require_object()?;
+        if field_name == "list" {
+            return Err(MsdbError::InvalidArgument(String::from(
+                "You cannot create a cell with reserved name `list`. Please try another name.",
+            )));
+        }
+        db_commands::write_blob(
+            field_name,
+            reader,
+            &mut self.main_metadata,
+            &self.password,
+            self.storage.as_mut(),
+            &mut self.location,
+            &mut self.structure_cache,
+        )
+    }
+    /// Write content of a literal or blob cell of current object into writer, returning its length.
+    /// Streamed and shared blobs are decrypted chunk by chunk.
+    pub fn read_blob(
+        &mut self,
+        field_name: &str,
+        writer: &mut impl Write,
+    ) -> Result<u64, MsdbError> {
+        self.require_object()?;
+        db_commands::read_blob(
+            field_name,
+            writer,
+            &self.main_metadata,
+            &self.password,
+            self.storage.as_mut(),
+            &self.location,
+            &mut self.structure_cache,
+        )
+    }
+    /// Load a field (or every field if `field_name` is `None`) of current structure into cache
+    pub fn load(&mut self, field_name: Option<&str>) -> Result<(), MsdbError> {
+        self.require_structure()?;
+        db_commands::load(
+            field_name,
+            &self.location,
+            &self.password,
+            self.storage.as_mut(),
+            &mut self.structure_cache,
+        )
+    }
+    /// Exit current structure/object/cell
+    pub fn leave(&mut self) {
+        db_commands::leave(&mut self.location);
+    }
+    /// Write database changes onto disk
+    pub fn sync(&mut self) -> Result<(), MsdbError> {
+        db_commands::sync(
+            &mut self.main_metadata,
+            &self.password,
+            self.storage.as_mut(),
+            &mut self.structure_cache,
+        )?;
+        self.record_epoch()
+    }
+    /// Sync, then re-encrypt legacy blocks so that every block is authenticated with its location
+    ///
+    /// Returns the number of blocks re-encrypted, 0 if the database is already upgraded.
+    pub fn upgrade(&mut self) -> Result<usize, MsdbError> {
+        self.sync()?;
+        let blocks = db_commands::upgrade(
+            &mut self.main_metadata,
+            &self.password,
+            self.storage.as_mut(),
+        )?;
+        self.record_epoch()?;
+        Ok(blocks)
+    }
+    /// Sync, then rewrite every structure densely and remove files which are no longer referenced
+    ///
+    /// Only works in database root, since structure cache is emptied.
+    pub fn vacuum(&mut self) -> Result<VacuumReport, MsdbError> {
+        if self.location.current_structure_identifier().is_some() {
+            return Err(MsdbError::InvalidArgument(String::from(
+                "Please return to root before vacuum",
+            )));
+        }
+        self.sync()?;
+        let report = db_commands::vacuum(
+            &mut self.main_metadata,
+            &self.password,
+            self.storage.as_mut(),
+            &mut self.structure_cache,
+        )?;
+        self.record_epoch()?;
+        Ok(report)
+    }
+    /// Whether new blob cells go into the deduplicating blob store
+    pub fn dedup(&self) -> bool {
+        blobstore::is_enabled(&self.main_metadata)
+    }
+    /// Switch the blob store on or off, written at next `sync`. Stored blobs stay where they are.
+    pub fn set_dedup(&mut self, enabled: bool) {
+        blobstore::set_enabled(&mut self.main_metadata, enabled);
+    }
+    /// Length-hiding padding of blocks
+    pub fn padding(&self) -> Result<Padding, MsdbError> {
+        Padding::from_metadata(&self.main_metadata)
+    }
+    /// Pad blocks written from now on; main metadata is written padded at next `sync`, other
+    /// blocks when they are rewritten (`vacuum` rewrites every structure)
+    pub fn set_padding(&mut self, padding: Padding) {
+        padding.set(&mut self.main_metadata);
+    }
+    /// Names of structures inside database
+    pub fn structures(&self) -> Vec<&str> {
+        self.main_metadata
+            .sub_data()
+            .keys()
+            .map(String::as_str)
+            .collect()
+    }
+    /// Names of objects inside current structure
+    pub fn objects(&self) -> Result<Vec<&str>, MsdbError> {
+        let mut result = Vec::new();
+        for i in &self.current_structure()?.list.cells {
+            if let blocks::Cell::Literal(j, _) = i {
+                result.push(j.as_str());
+            }
+        }
+        Ok(result)
+    }
+    /// Cells of current object inside cached fields, together with their field names
+    pub fn cells(&self) -> Result<Vec<(&str, &blocks::Cell)>, MsdbError> {
+        self.require_object()?;
+        let current_object = self.location.current_object_identifier().unwrap();
+        let structure = self.current_structure()?;
+        let mut result = Vec::new();
+        for (field_name, field_identifier) in structure.metadata.sub_data() {
+            let field = hex::decode(field_identifier)
+                .ok()
+                .and_then(|i| structure.cached_block.get(&i[..]));
+            if let Some(field) = field {
+                for i in &field.cells {
+                    if i.identifier() == current_object {
+                        result.push((field_name.as_str(), i));
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+    /// Cell of current object inside a field. The field will be loaded if it is not cached.
+    pub fn cell(&mut self, field_name: &str) -> Result<Option<&blocks::Cell>, MsdbError> {
+        self.require_object()?;
+        let field_identifier = self
+            .current_structure()?
+            .metadata
+            .sub_data()
+            .get(field_name)
+            .and_then(|i| hex::decode(i).ok())
+            .ok_or_else(|| MsdbError::NotFound(format!("Field {}", field_name)))?;
+        if !self
+            .current_structure()?
+            .cached_block
+            .contains_key(&field_identifier[..])
+        {
+            self.load(Some(field_name))?;
+        }
+        let current_object = self.location.current_object_identifier().unwrap();
+        Ok(self
+            .current_structure()?
+            .cached_block
+            .get(&field_identifier[..])
+            .and_then(|i| i.cells.iter().find(|j| j.identifier() == current_object)))
+    }
+    /// Properties of main metadata (inside database root) or structure metadata (inside a structure)
+    pub fn properties(&self) -> Result<&HashMap<String, String>, MsdbError> {
+        if self.location.current_structure_identifier().is_none() {
+            Ok(self.main_metadata.attribute())
+        } else if self.location.current_object_identifier().is_none() {
+            Ok(self.current_structure()?.metadata.attribute())
+        } else {
+            Err(MsdbError::InvalidArgument(String::from(
+                "currently properties only works with main metadata and structure metadata",
+            )))
+        }
+    }
+    /// Create a property in main metadata (inside database root) or structure metadata (inside a structure)
+    pub fn set_property(&mut self, name: &str, value: &str) -> Result<(), MsdbError> {
+        utils::check_property_name(name)?;
+        if self.location.current_structure_identifier().is_none() {
+            self.main_metadata.new_attribute(name, value);
+            Ok(())
+        } else if self.location.current_object_identifier().is_none() {
+            let current_structure = self.location.current_structure_identifier().unwrap();
+            self.structure_cache
+                .get_mut(&current_structure)
+                .ok_or_else(|| MsdbError::NotFound(String::from("Current structure")))?
+                .metadata
+                .new_attribute(name, value);
+            Ok(())
+        } else {
+            Err(MsdbError::InvalidArgument(String::from(
+                "currently properties only works with main metadata and structure metadata",
+            )))
+        }
+    }
+    fn current_structure(&self) -> Result<&Structure, MsdbError> {
+        self.require_structure()?;
+        self.structure_cache
+            .get(&self.location.current_structure_identifier().unwrap())
+            .ok_or_else(|| MsdbError::NotFound(String::from("Current structure")))
+    }
+    fn require_structure(&self) -> Result<(), MsdbError> {
+        if self.location.current_structure_identifier().is_none() {
+            Err(MsdbError::InvalidArgument(String::from(
+                "Please select a structure first",
+            )))
+        } else {
+            Ok(())
+        }
+    }
+    fn require_object(&self) -> Result<(), MsdbError> {
+        if self.location.current_object_identifier().is_none() {
+            Err(MsdbError::InvalidArgument(String::from(
+                "Please select a object first",
+            )))
+        } else {
+            Ok(())
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::Database;
+    use crate::blockencrypt::Kdf;
+    use crate::blocks::Cell;
+    use crate::credential::Credential;
+    use crate::storage::MemoryStorage;
+    use sodiumoxide::crypto::secretbox;
+    use sodiumoxide::randombytes::randombytes;
+    fn memory_database() -> Database {
+        Database::create_in(
+            Box::new(MemoryStorage::new("memory")),
+            &Credential::Keyfile(secretbox::gen_key()),
+            Kdf::default(),
+        )
+        .unwrap()
+    }
+    /// Cells of an object: field, type and content
+    type Cells<'a> = &'a [(&'a str, &'a str, &'a str)];
+    /// Create `people` with an object for each of `objects`, then sync
+    fn people(database: &mut Database, objects: &[(&str, Cells)]) {
+        database.create_structure("people").unwrap();
+        database.select_structure("people").unwrap();
+        for (object, cells) in objects {
+            database.create_object(object).unwrap();
+            database.select_object(object).unwrap();
+            for (field, cell_type, content) in *cells {
+                database.create_cell(field, cell_type, content).unwrap();
+            }
+            database.leave();
+        }
+        database.sync().unwrap();
+        //Select again, so that no field is cached
+        database.select_structure("people").unwrap();
+    }
+    /// Token of a field inside structure `people`, prefixed by the token of the structure
+    fn field_directory(database: &Database, field: &str) -> String {
+        let structure = database.main_metadata.sub_data().get("people").unwrap();
+        let metadata = &database.current_structure().unwrap().metadata;
+        format!("{}/{}", structure, metadata.sub_data().get(field).unwrap())
+    }
+    /// Size of every cached field of current structure once written into blocks
+    fn encoded_len(database: &mut Database) -> usize {
+        let structure = database.location.current_structure_identifier().unwrap();
+        let structure = database.structure_cache.get_mut(&structure).unwrap();
+        let mut result = 0;
+        for field in structure.cached_block.values_mut() {
+            field.queue.clear();
+            field.cell_to_raw(Some(65536), 512);
+            result += field.queue.iter().map(Vec::len).sum::<usize>();
+        }
+        result
+    }
+    #[test]
+    fn wipe() {
+        let mut database = memory_database();
+        people(
+            &mut database,
+            &[
+                (
+                    "alice",
+                    &[("name", "literal", "Alice"), ("age", "int", "30")],
+                ),
+                ("bob", &[("name", "literal", "Bob"), ("age", "int", "40")]),
+            ],
+        );
+        database.load(None).unwrap();
+        let bytes_before = encoded_len(&mut database);
+        database.select_structure("people").unwrap();
+        //Changes not synced would be committed together with the wipe
+        database.create_object("carol").unwrap();
+        assert!(database.wipe_object("alice").is_err());
+        database.select_structure("people").unwrap();
+        let (cells, bytes) = database.wipe_object("alice").unwrap();
+        assert_eq!(cells, 2);
+        assert_eq!(bytes, bytes_before - encoded_len(&mut database));
+        //Every cell left on disk belongs to bob
+        database.select_structure("people").unwrap();
+        assert_eq!(database.objects().unwrap(), ["bob"]);
+        database.select_object("bob").unwrap();
+        let bob = database.location.current_object_identifier().unwrap();
+        database.load(None).unwrap();
+        let structure = database.current_structure().unwrap();
+        let cells: Vec<_> = structure
+            .cached_block
+            .values()
+            .flat_map(|i| &i.cells)
+            .collect();
+        assert_eq!(cells.len(), 2);
+        assert!(cells.iter().all(|i| i.identifier() == bob));
+    }
+    #[test]
+    fn vacuum() {
+        let photo = hex::encode(randombytes(40000));
+        let mut database = memory_database();
+        people(
+            &mut database,
+            &[
+                (
+                    "alice",
+                    &[("photo", "literal", &photo), ("nickname", "literal", "Al")],
+                ),
+                ("bob", &[("photo", "literal", "none")]),
+            ],
+        );
+        let photo_directory = field_directory(&database, "photo");
+        let nickname_directory = field_directory(&database, "nickname");
+        assert!(database
+            .storage
+            .contains(&format!("{}/1.blk", photo_directory)));
+        database.delete_object("alice").unwrap();
+        database.leave();
+        let report = database.vacuum().unwrap();
+        assert_eq!((report.cells_dropped, report.fields_dropped), (2, 1));
+        assert!(report.files_after < report.files_before);
+        assert!(database
+            .storage
+            .contains(&format!("{}/0.blk", photo_directory)));
+        assert!(!database
+            .storage
+            .contains(&format!("{}/1.blk", photo_directory)));
+        assert!(database
+            .storage
+            .list(&format!("{}/", nickname_directory))
+            .unwrap()
+            .is_empty());
+        database.select_structure("people").unwrap();
+        assert_eq!(database.objects().unwrap(), ["bob"]);
+        database.select_object("bob").unwrap();
+        assert!(matches!(
+            database.cell("photo").unwrap(),
+            Some(Cell::Literal(i, _)) if i == "none"
+        ));
+        assert!(database.cell("nickname").is_err());
+    }
+    #[test]
+    fn alter() {
+        let mut database = memory_database();
+        people(
+            &mut database,
+            &[(
+                "alice",
+                &[("age", "int", "30"), ("name", "literal", "Alice")],
+            )],
+        );
+        database.select_object("alice").unwrap();
+        assert!(database.cells().unwrap().is_empty());
+        //The field is loaded, and the cell stays an integer
+        database.alter_cell("age", None, "31").unwrap();
+        assert!(matches!(
+            database.cell("age").unwrap(),
+            Some(Cell::Integer(31, _))
+        ));
+        assert!(database.alter_cell("age", None, "old").is_err());
+        assert!(database.alter_cell("age", Some("unknown"), "32").is_err());
+        assert!(matches!(
+            database.cell("age").unwrap(),
+            Some(Cell::Integer(31, _))
+        ));
+        database.alter_cell("name", Some("int"), "5").unwrap();
+        assert!(matches!(
+            database.cell("name").unwrap(),
+            Some(Cell::Integer(5, _))
+        ));
+    }
+}
@@ -1,24 +1,43 @@
 use super::binary_io;
-use super::blockencrypt;
+use super::blobstore;
+use super::blockencrypt::{self, DatabaseKey};
 use super::blocks;
+use super::compression::Compression;
+use super::error::MsdbError;
 use super::metadata;
+use super::padding::Padding;
+use super::schema::{FieldSchema, Schema};
+use super::storage::Storage;
+use super::stream;
+use super::utils;
 use rand::{distributions::Uniform, Rng};
-use sodiumoxide::crypto::secretbox;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::fs;
-use std::path;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
 pub const METADATA_INDEX_LEN: u32 = 8;
+/// Cell types accepted by `new` and `alter`
+pub const CELL_TYPES: [&str; 9] = [
+    "literal",
+    "blob",
+    "link",
+    "revlink",
+    "int",
+    "float",
+    "bool",
+    "timestamp",
+    "uuid",
+];
 fn random_metadata_identifier() -> [u8; METADATA_INDEX_LEN as usize] {
     let mut rng = rand::thread_rng();
     let range = Uniform::new(0, 255);
-    let vals: Vec<u8> = (0..u8::max_value()).map(|_| rng.sample(&range)).collect();
+    let vals: Vec<u8> = (0..u8::MAX).map(|_| rng.sample(range)).collect();
     vals[0..METADATA_INDEX_LEN as usize].try_into().unwrap()
 }
 fn random_blocks_identifier() -> [u8; blocks::CELL_IDENTIFIER_LENGTH as usize] {
     let mut rng = rand::thread_rng();
     let range = Uniform::new(0, 255);
-    let vals: Vec<u8> = (0..u8::max_value()).map(|_| rng.sample(&range)).collect();
+    let vals: Vec<u8> = (0..u8::MAX).map(|_| rng.sample(range)).collect();
     vals[0..blocks::CELL_IDENTIFIER_LENGTH as usize]
         .try_into()
         .unwrap()
@@ -50,6 +69,11 @@ pub struct DatabaseLocation {
     current_object: Option<([u8; METADATA_INDEX_LEN as usize], String)>,
     current_cell: Option<([u8; METADATA_INDEX_LEN as usize], String)>,
 }
+impl Default for DatabaseLocation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl DatabaseLocation {
     pub const fn new() -> Self {
         Self {
@@ -105,10 +129,7 @@ impl DatabaseLocation {
         self.root_folder.as_ref()
     }
     pub fn current_structure_identifier(&self) -> Option<[u8; METADATA_INDEX_LEN as usize]> {
-        match self.current_structure {
-            None => None,
-            Some((i, _)) => Some(i),
-        }
+        self.current_structure.as_ref().map(|(i, _)| *i)
     }
     pub fn current_structure_pretty_name(&self) -> Option<&str> {
         match self.current_structure.as_ref() {
@@ -117,10 +138,7 @@ impl DatabaseLocation {
         }
     }
     pub fn current_cell_identifier(&self) -> Option<[u8; METADATA_INDEX_LEN as usize]> {
-        match self.current_cell {
-            None => None,
-            Some((i, _)) => Some(i),
-        }
+        self.current_cell.as_ref().map(|(i, _)| *i)
     }
     pub fn current_cell_pretty_name(&self) -> Option<&str> {
         match self.current_cell.as_ref() {
@@ -129,10 +147,7 @@ impl DatabaseLocation {
         }
     }
     pub fn current_object_identifier(&self) -> Option<[u8; METADATA_INDEX_LEN as usize]> {
-        match self.current_object {
-            None => None,
-            Some((i, _)) => Some(i),
-        }
+        self.current_object.as_ref().map(|(i, _)| *i)
     }
     pub fn current_object_pretty_name(&self) -> Option<&str> {
         match self.current_object.as_ref() {
@@ -143,13 +158,13 @@ impl DatabaseLocation {
 }
 impl std::fmt::Display for DatabaseLocation {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        if self.root_folder == None {
+        if self.root_folder.is_none() {
             write!(f, "")
-        } else if self.current_structure == None {
+        } else if self.current_structure.is_none() {
             write!(f, "~",)
-        } else if self.current_object == None {
+        } else if self.current_object.is_none() {
             write!(f, "{}", self.current_structure_pretty_name().unwrap())
-        } else if self.current_cell == None {
+        } else if self.current_cell.is_none() {
             write!(
                 f,
                 "{}/{}",
@@ -168,469 +183,596 @@ impl std::fmt::Display for DatabaseLocation {
     }
 }
 fn into_hex_metadata(identifier: [u8; METADATA_INDEX_LEN as usize]) -> String {
-    hex::encode(identifier.to_vec())
+    hex::encode(identifier)
 }
 fn into_hex_block(identifier: [u8; blocks::CELL_IDENTIFIER_LENGTH as usize]) -> String {
-    hex::encode(identifier.to_vec())
+    hex::encode(identifier)
 }
-fn from_hex_metadata(encoded_string: &str) -> [u8; METADATA_INDEX_LEN as usize] {
-    let result = hex::decode(encoded_string).expect("Error when decoding identifier");
-    result[0..METADATA_INDEX_LEN as usize].try_into().unwrap()
+fn from_hex_metadata(encoded_string: &str) -> Result<[u8; METADATA_INDEX_LEN as usize], MsdbError> {
+    hex::decode(encoded_string)
+        .ok()
+        .and_then(|i| i.get(0..METADATA_INDEX_LEN as usize)?.try_into().ok())
+        .ok_or_else(|| {
+            MsdbError::CorruptBlock(format!("Error when decoding identifier {}", encoded_string))
+        })
 }
-fn from_hex_blocks(encoded_string: &str) -> [u8; blocks::CELL_IDENTIFIER_LENGTH as usize] {
-    let result = hex::decode(encoded_string).expect("Error when decoding identifier");
-    result[0..blocks::CELL_IDENTIFIER_LENGTH as usize]
-        .try_into()
-        .unwrap()
+fn from_hex_blocks(
+    encoded_string: &str,
+) -> Result<[u8; blocks::CELL_IDENTIFIER_LENGTH as usize], MsdbError> {
+    hex::decode(encoded_string)
+        .ok()
+        .and_then(|i| {
+            i.get(0..blocks::CELL_IDENTIFIER_LENGTH as usize)?
+                .try_into()
+                .ok()
+        })
+        .ok_or_else(|| {
+            MsdbError::CorruptBlock(format!("Error when decoding identifier {}", encoded_string))
+        })
 }
 /// Execute database commands
 pub fn run_commands(
-    argument: &str,
+    arguments: &[String],
     main_metadata: &mut metadata::Metadata,
     current_location: &mut DatabaseLocation,
-    password: &secretbox::Key,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
     structure_cache: &mut HashMap<[u8; blocks::CELL_IDENTIFIER_LENGTH as usize], Structure>,
-) {
-    let mut parsed_command = argument.split_whitespace();
-    match parsed_command.next().unwrap() {
-        "new" => {
-            match parsed_command.next() {
-                None => {
-                    println!("You need to have at least one argument for 'new' command");
-                }
-                Some(i) => match i {
-                    "struct" => match parsed_command.next() {
-                        None => println!("You need to specify structure name"),
-                        Some(j) => {
-                            if main_metadata.sub_data().get(&j.to_string()).is_some() {
-                                println!("Structure {} already exists", j);
+) -> Result<(), MsdbError> {
+    let mut parsed_command = arguments.iter().map(String::as_str);
+    let command = match parsed_command.next() {
+        Some(i) => i,
+        None => return Ok(()),
+    };
+    match command {
+        "new" => match parsed_command.next() {
+            None => Err(MsdbError::InvalidArgument(String::from(
+                "You need to have at least one argument for 'new' command",
+            ))),
+            Some(i) => match i {
+                "struct" => match parsed_command.next() {
+                    None => Err(MsdbError::InvalidArgument(String::from(
+                        "You need to specify structure name",
+                    ))),
+                    Some(j) => {
+                        if main_metadata.sub_data().get(j).is_some() {
+                            Err(MsdbError::AlreadyExists(format!("Structure {}", j)))
+                        } else {
+                            create_structure(j, password, storage, main_metadata, None)?;
+                            println!(
+                                "Structure {}[identifier {}] created.",
+                                j,
+                                main_metadata.sub_data().get(j).unwrap()
+                            );
+                            Ok(())
+                        }
+                    }
+                },
+                j => match current_location.current_structure_identifier() {
+                    None => Err(MsdbError::InvalidArgument(String::from("You cannot create a object under root.\nPlease select/create a structure before creating object."))),
+                    Some(_) => {
+                        if current_location.current_object_identifier().is_none() {
+                            if find_object(j, current_location, structure_cache).is_some() {
+                                Err(MsdbError::AlreadyExists(format!("Object {}", j)))
                             } else {
-                                create_structure(
+                                //Create a new object
+                                create_object(
                                     j,
-                                    password,
                                     main_metadata,
+                                    password,
+                                    storage,
                                     current_location,
-                                    None,
+                                    structure_cache,
+                                )?;
+                                println!(
+                                    "Object {}[identifier: {}] created.",
+                                    j,
+                                    into_hex_block(
+                                        find_object(j, current_location, structure_cache).unwrap()
+                                    )
                                 );
+                                Ok(())
                             }
-                        }
-                    },
-                    j => match current_location.current_structure_identifier() {
-                        None => println!("You cannot create a object under root.\nPlease select/create a structure before creating object."),
-                        Some(_) => {
-                            if current_location.current_object_identifier().is_none() {
-                                let exist = {
-                                    //Check if the object exist
-                                    let cells = &structure_cache
-                                        .get(
-                                            &current_location
-                                                .current_structure_identifier()
-                                                .expect(
-                                                    "Unable to read current structure identifier",
-                                                ),
-                                        )
-                                        .expect("Unable to read current structure from cache")
-                                        .list
-                                        .cells;
-                                    let mut object_exist = false;
-                                    //TODO: Optimize this code
-                                    for k in cells {
-                                        if let blocks::Cell::Literal(l, _) = k {
-                                            if !object_exist && j == l {
-                                                object_exist = true;
-                                            }
+                        } else if j == "list" {
+                            Err(MsdbError::InvalidArgument(String::from("You cannot create a cell with reserved name `list`. Please try another name.")))
+                        } else {
+                            //Create a new cell
+                            let cell_type = {
+                                if let Some(k) = parsed_command.next() {
+                                    let k = k.to_ascii_lowercase();
+                                    match k.as_str() {
+                                        i if CELL_TYPES.contains(&i) => k,
+                                        _ => {
+                                            return Err(MsdbError::InvalidArgument(format!(
+                                                "Cell type {} not reconized. Available: {}",
+                                                k,
+                                                CELL_TYPES.join(", ")
+                                            )))
                                         }
                                     }
-                                    object_exist
-                                };
-                                if exist {
-                                    println!("Object already exists. Please try another name.");
                                 } else {
-                                    //Create a new object
-                                    create_object(j, current_location, structure_cache);
-                                };
-                            } else if j == "list" {
-                                println!("You cannot create a cell with reserved name `list`. Please try another name.");
-                            } else {
-                                //Create a new cell
-                                let cell_type = {
-                                    if let Some(k) = parsed_command.next() {
-                                        let k = k.to_ascii_lowercase();
-                                        match k.as_str() {
-                                            //TODO: Support Incomplete Block
-                                            "literal" | "blob" | "link" | "revlink" => k,
-                                            _ => {
-                                                println!("Cell type not reconized. Treated as Literal Cell.");
-                                                "literal".to_string()
-                                            }
-                                        }
-                                    } else {
-                                        println!("Creating a cell with no type is discouraged. Treated as Literal Cell.");
-                                        "literal".to_string()
-                                    }
-                                };
-                                let cell_content = {
-                                    if let Some(k) = parsed_command.next() {
-                                        k
-                                    } else {
-                                        println!("Creating a cell with no content is discouraged.");
-                                        "0"
-                                    }
-                                };
-                                create_cell(
+                                    println!("Creating a cell with no type is discouraged. Treated as Literal Cell.");
+                                    "literal".to_string()
+                                }
+                            };
+                            let cell_content = {
+                                if let Some(k) = parsed_command.next() {
+                                    k
+                                } else {
+                                    println!("Creating a cell with no content is discouraged.");
+                                    "0"
+                                }
+                            };
+                            let current_structure =
+                                current_location.current_structure_identifier().unwrap();
+                            let field_exists = structure_cache[&current_structure]
+                                .metadata
+                                .sub_data()
+                                .contains_key(j);
+                            create_cell(
+                                j,
+                                &cell_type,
+                                cell_content,
+                                main_metadata,
+                                password,
+                                storage,
+                                current_location,
+                                structure_cache,
+                            )?;
+                            if !field_exists {
+                                println!(
+                                    "Field {}[identifier: {}] created.",
                                     j,
-                                    &cell_type,
-                                    cell_content,
-                                    password,
-                                    current_location,
-                                    structure_cache,
+                                    structure_cache[&current_structure].metadata.sub_data()[j]
                                 );
                             }
+                            Ok(())
                         }
-                    },
+                    }
                 },
-            };
-        }
+            },
+        },
         "alter" => {
-            if current_location.current_object_identifier() == None {
-                println!("You cannot alter a cell outside objects");
+            if current_location.current_object_identifier().is_none() {
+                Err(MsdbError::InvalidArgument(String::from(
+                    "You cannot alter a cell outside objects",
+                )))
             } else {
-                match parsed_command.next() {
-                    None => println!("alter command requires three arguments"),
-                    Some(i) => match parsed_command.next() {
-                        None => println!("alter command requires three arguments"),
-                        Some(j) => match parsed_command.next() {
-                            None => println!("alter command requires three arguments"),
-                            Some(k) => {
-                                alter_cell(i, j, k, password, current_location, structure_cache)
-                            }
-                        },
-                    },
+                match (
+                    parsed_command.next(),
+                    parsed_command.next(),
+                    parsed_command.next(),
+                ) {
+                    (Some(i), Some(j), Some(k)) => alter_cell(
+                        i,
+                        Some(j),
+                        k,
+                        main_metadata,
+                        password,
+                        storage,
+                        current_location,
+                        structure_cache,
+                    ),
+                    //Keep cell type
+                    (Some(i), Some(j), None) => alter_cell(
+                        i,
+                        None,
+                        j,
+                        main_metadata,
+                        password,
+                        storage,
+                        current_location,
+                        structure_cache,
+                    ),
+                    _ => Err(MsdbError::InvalidArgument(String::from(
+                        "Usage: alter CELLNAME [CELLTYPE] CELLCONTENT",
+                    ))),
                 }
             }
         }
+        "read" => {
+            if current_location.current_object_identifier().is_none() {
+                return Err(MsdbError::InvalidArgument(String::from(
+                    "Please select a object before reading any cell",
+                )));
+            }
+            let mut field_name = None;
+            let mut format = ReadFormat::Text;
+            while let Some(i) = parsed_command.next() {
+                format = match i {
+                    "--hex" => ReadFormat::Hex,
+                    "--base64" => ReadFormat::Base64,
+                    "--raw" => ReadFormat::Raw,
+                    ">" => ReadFormat::File(parsed_command.next().ok_or_else(|| {
+                        MsdbError::InvalidArgument(String::from("`>` requires a filename"))
+                    })?),
+                    j if field_name.is_none() => {
+                        field_name = Some(j.to_string());
+                        continue;
+                    }
+                    j => return Err(MsdbError::InvalidArgument(format!("Unexpected {}", j))),
+                };
+            }
+            //Inside a cell, fallback to current cell
+            let field_name = field_name
+                .or_else(|| current_location.current_cell_pretty_name().map(String::from))
+                .ok_or_else(|| {
+                    MsdbError::InvalidArgument(String::from(
+                        "Usage: read FIELD [--hex | --base64 | --raw | > FILENAME]",
+                    ))
+                })?;
+            read_cell(
+                &field_name,
+                &format,
+                main_metadata,
+                current_location,
+                password,
+                storage,
+                structure_cache,
+            )
+        }
         "ls" => {
-            if current_location.current_structure_identifier() == None {
+            if current_location.current_structure_identifier().is_none() {
                 ugly_print_structure(main_metadata);
-            } else if current_location.current_object_identifier() == None {
+            } else if current_location.current_object_identifier().is_none() {
                 ugly_print_objects(current_location, structure_cache);
             } else {
                 ugly_print_cell(current_location, structure_cache, main_metadata);
             }
+            Ok(())
         }
         "debls" => {
-            if current_location.current_structure_identifier() == None {
+            if current_location.current_structure_identifier().is_none() {
                 debug_print_structure(main_metadata);
-            } else if current_location.current_object_identifier() == None {
+            } else if current_location.current_object_identifier().is_none() {
                 debug_print_objects(current_location, structure_cache);
             } else {
                 debug_print_cell(current_location, structure_cache);
             }
+            Ok(())
+        }
+        "leave" => {
+            leave(current_location);
+            Ok(())
         }
-        "leave" => leave(current_location),
         "select" => match parsed_command.next() {
-            None => println!("select command requires exactly one command"),
+            None => Err(MsdbError::InvalidArgument(String::from(
+                "select command requires exactly one command",
+            ))),
             Some(i) => {
-                if current_location.current_structure_identifier() == None {
+                if current_location.current_structure_identifier().is_none() {
                     select_structure(
                         i,
                         password,
+                        storage,
                         main_metadata,
                         current_location,
                         structure_cache,
-                    );
-                } else if current_location.current_object_identifier() == None {
-                    select_object(i, current_location, structure_cache);
-                } else if current_location.current_cell_identifier() == None {
-                    let field_identifier = from_hex_blocks(
+                    )?;
+                    println!("Structure {}[{}]", i, main_metadata.sub_data()[i]);
+                    Ok(())
+                } else if current_location.current_object_identifier().is_none() {
+                    select_object(i, current_location, structure_cache)
+                } else if current_location.current_cell_identifier().is_none() {
+                    let field_identifier = from_hex_metadata(
                         structure_cache
                             .get(&current_location.current_structure_identifier().unwrap())
                             .unwrap()
                             .metadata
                             .sub_data()
-                            .get(&i.to_string())
-                            .expect("Unable to find field"),
-                    );
+                            .get(i)
+                            .ok_or_else(|| MsdbError::NotFound(format!("Field {}", i)))?,
+                    )?;
                     current_location.select_cell((field_identifier, i.to_string()));
+                    Ok(())
                 } else {
-                    println!("Please return to root before select");
+                    Err(MsdbError::InvalidArgument(String::from(
+                        "Please return to root before select",
+                    )))
                 }
             }
         },
         "clean" => {
+            //Commands inside a structure expect it to be cached
+            if current_location.current_structure_identifier().is_some() {
+                return Err(MsdbError::InvalidArgument(String::from(
+                    "Please return to root before clean",
+                )));
+            }
             clear_cache(structure_cache);
+            Ok(())
         }
         "sync" => {
-            if main_metadata.has_modified() {
-                println!("Writing main metadata to disk...");
-                {
-                    let main_metadata_vec = main_metadata.to_vec();
-                    let data = blockencrypt::encrypt_block(&main_metadata_vec, password);
-                    let filename = format!("{}/metadata", current_location.root_folder().unwrap());
-                    binary_io::write_with_nonce(&filename, &data.0, data.1);
-                }
-                main_metadata.set_not_modified();
+            sync(main_metadata, password, storage, structure_cache)?;
+            println!(
+                "Synced. Epoch {}.",
+                utils::read_epoch(main_metadata, utils::EPOCH_ATTRIBUTE)?
+            );
+            Ok(())
+        }
+        "upgrade" => {
+            sync(main_metadata, password, storage, structure_cache)?;
+            if utils::is_upgraded(main_metadata) {
+                println!("Database is already upgraded.");
             } else {
-                println!("Main metadata not modified; ignoring.");
-            }
-            for i in structure_cache {
-                println!("Writing {} structure to disk...", into_hex_metadata(*i.0));
-                {
-                    println!("|-Cell list");
-                    //TODO: custom cell size
-                    i.1.list.cell_to_raw(None, 512);
-                    for j in &i.1.list.queue {
-                        let data = blockencrypt::encrypt_block(j, password);
-                        let filename = format!(
-                            "{}/{}/{}",
-                            current_location.root_folder().unwrap(),
-                            into_hex_metadata(*i.0),
-                            i.1.metadata.sub_data().get(&String::from("list")).unwrap()
-                        );
-                        binary_io::write_with_nonce(&filename, &data.0, data.1);
-                    }
-                }
-                {
-                    println!("|-Field Cache");
-                    //TODO: custom cell size
-                    for j in &mut i.1.cached_block {
-                        let folder_name = format!(
-                            "{}/{}/{}",
-                            current_location.root_folder().unwrap(),
-                            into_hex_metadata(*i.0),
-                            into_hex_block(*j.0),
-                        );
-                        if !path::Path::new(&folder_name).is_dir() {
-                            fs::create_dir(&folder_name).expect("Unable to create cell folder");
-                        }
-                        j.1.cell_to_raw(Some(65536), 512);
-                        for (current_num, k) in j.1.queue.iter().enumerate() {
-                            let data = blockencrypt::encrypt_block(k, password);
-                            let filename = format!("{}/{}.blk", folder_name, current_num);
-                            binary_io::write_with_nonce(&filename, &data.0, data.1);
-                        }
-                    }
-                }
-                {
-                    if i.1.metadata.has_modified() {
-                        println!("|-Structure Metadata");
-                        //Save metadata
-                        let metadata_sync_vec = i.1.metadata.to_vec();
-                        let data = blockencrypt::encrypt_block(&metadata_sync_vec, password);
-                        let filename = format!(
-                            "{}/{}/metadata",
-                            current_location.root_folder().unwrap(),
-                            into_hex_metadata(*i.0)
-                        );
-                        binary_io::write_with_nonce(&filename, &data.0, data.1);
-                        i.1.metadata.set_not_modified();
-                    } else {
-                        println!("|-Metadata(Ignored)")
-                    }
-                }
+                let blocks = upgrade(main_metadata, password, storage)?;
+                println!("{} legacy blocks re-encrypted.", blocks);
             }
+            Ok(())
         }
         "load" => {
             //Load a set of cells into cache
-            if current_location.current_structure_identifier() == None {
-                println!("Please select a structure before loading any cell");
-            } else if current_location.current_object_identifier() == None {
-                match parsed_command.next() {
-                    None => {
-                        println!("Loading every field inside structure...");
-                        let mut result: Vec<(
-                            [u8; blocks::CELL_IDENTIFIER_LENGTH as usize],
-                            blocks::BlockQueue,
-                        )> = Vec::new();
-                        for (i, j) in structure_cache
-                            .get(&current_location.current_structure_identifier().unwrap())
-                            .unwrap()
-                            .metadata
-                            .sub_data()
-                        {
-                            if i.as_str() != "list" {
-                                print!(" Loading {} ({})", i, j);
-                                let structure_directory = format!(
-                                    "{}/{}/{}",
-                                    current_location.root_folder().unwrap(),
-                                    into_hex_metadata(
-                                        current_location.current_structure_identifier().unwrap()
-                                    ),
-                                    j
-                                );
-                                let mut temp_block = blocks::BlockQueue::new();
-                                //TODO: 加入缓存部分Cell的功能
-                                let mut current_num = 0;
-                                while path::Path::new(&format!(
-                                    "{}/{}.blk",
-                                    structure_directory, current_num
-                                ))
-                                .is_file()
-                                {
-                                    let block = binary_io::read_with_nonce(&format!(
-                                        "{}/{}.blk",
-                                        structure_directory, current_num
-                                    ));
-                                    temp_block.import_from_vec(blockencrypt::decrypt_block(
-                                        &block.0, password, block.1,
-                                    ));
-                                    temp_block.raw_to_cell(512);
-                                    print!(".");
-                                    current_num += 1;
-                                }
-                                println!();
-                                result.push((from_hex_metadata(j), temp_block));
-                            }
-                        }
-                        for i in result {
-                            structure_cache
-                                .get_mut(&current_location.current_structure_identifier().unwrap())
-                                .unwrap()
-                                .cached_block
-                                .insert(i.0, i.1);
-                        }
-                    }
-                    Some(k) => {
-                        let mut result: Vec<(
-                            [u8; blocks::CELL_IDENTIFIER_LENGTH as usize],
-                            blocks::BlockQueue,
-                        )> = Vec::new();
-                        for (i, j) in structure_cache
-                            .get(&current_location.current_structure_identifier().unwrap())
-                            .unwrap()
-                            .metadata
-                            .sub_data()
-                        {
-                            if i.as_str() == k {
-                                print!(" Loading {} ({})", i, j);
-                                let structure_directory = format!(
-                                    "{}/{}/{}",
-                                    current_location.root_folder().unwrap(),
-                                    into_hex_metadata(
-                                        current_location.current_structure_identifier().unwrap()
-                                    ),
-                                    j
-                                );
-                                let mut temp_block = blocks::BlockQueue::new();
-                                //TODO: 加入缓存部分Cell的功能
-                                let mut current_num = 0;
-                                while path::Path::new(&format!(
-                                    "{}/{}.blk",
-                                    structure_directory, current_num
-                                ))
-                                .is_file()
-                                {
-                                    let block = binary_io::read_with_nonce(&format!(
-                                        "{}/{}.blk",
-                                        structure_directory, current_num
-                                    ));
-                                    temp_block.import_from_vec(blockencrypt::decrypt_block(
-                                        &block.0, password, block.1,
-                                    ));
-                                    print!(".");
-                                    current_num += 1;
-                                }
-                                println!();
-                                result.push((from_hex_metadata(j), temp_block));
-                            }
-                        }
-                        for i in result {
-                            structure_cache
-                                .get_mut(&current_location.current_structure_identifier().unwrap())
-                                .unwrap()
-                                .cached_block
-                                .insert(i.0, i.1);
-                        }
-                    }
-                }
+            if current_location.current_structure_identifier().is_none() {
+                Err(MsdbError::InvalidArgument(String::from(
+                    "Please select a structure before loading any cell",
+                )))
             } else {
-                print!(
-                    " Loading {} ({})",
-                    current_location.current_cell_pretty_name().unwrap(),
-                    into_hex_metadata(current_location.current_cell_identifier().unwrap())
-                );
-                let structure_directory = format!(
-                    "{}/{}/{}",
-                    current_location.root_folder().unwrap(),
-                    into_hex_metadata(current_location.current_structure_identifier().unwrap()),
-                    into_hex_metadata(current_location.current_cell_identifier().unwrap())
-                );
-                let mut temp_block = blocks::BlockQueue::new();
-                //TODO: 加入缓存部分Cell的功能
-                let mut current_num = 0;
-                while path::Path::new(&format!("{}/{}.blk", structure_directory, current_num))
-                    .is_file()
-                {
-                    let block = binary_io::read_with_nonce(&format!(
-                        "{}/{}.blk",
-                        structure_directory, current_num
-                    ));
-                    temp_block
-                        .import_from_vec(blockencrypt::decrypt_block(&block.0, password, block.1));
-                    print!(".");
-                    current_num += 1;
-                }
-                structure_cache
-                    .get_mut(&current_location.current_structure_identifier().unwrap())
-                    .unwrap()
-                    .cached_block
-                    .insert(
-                        current_location.current_cell_identifier().unwrap(),
-                        temp_block,
-                    );
+                //Inside a object, fallback to current cell when no field is specified
+                let field_name = parsed_command
+                    .next()
+                    .or_else(|| current_location.current_cell_pretty_name())
+                    .map(ToString::to_string);
+                load(
+                    field_name.as_deref(),
+                    current_location,
+                    password,
+                    storage,
+                    structure_cache,
+                )
             }
         }
-        "pwd" => println!("{}", current_location),
+        "pwd" => {
+            println!("{}", current_location);
+            Ok(())
+        }
         "del" => {
             if let Some(i) = parsed_command.next() {
-                if current_location.current_structure_identifier() == None {
-                    delete_structure(i, current_location, main_metadata);
-                } else if current_location.current_object_identifier() == None {
-                    delete_object(i, current_location, structure_cache);
-                } else if current_location.current_cell_identifier() == None {
-                    delete_cell(i, current_location, structure_cache);
+                if current_location.current_structure_identifier().is_none() {
+                    delete_structure(i, password, storage, main_metadata, structure_cache)
+                } else if current_location.current_object_identifier().is_none() {
+                    delete_object(i, current_location, structure_cache)
+                } else if current_location.current_cell_identifier().is_none() {
+                    delete_cell(i, main_metadata, storage, current_location, structure_cache)
                 } else {
-                    println!("Please `leave` the cell before deleting it");
+                    Err(MsdbError::InvalidArgument(String::from(
+                        "Please `leave` the cell before deleting it",
+                    )))
                 }
             } else {
-                println!("`del` command requires exactly one parameter");
+                Err(MsdbError::InvalidArgument(String::from(
+                    "`del` command requires exactly one parameter",
+                )))
+            }
+        }
+        "vacuum" => {
+            if current_location.current_structure_identifier().is_some() {
+                return Err(MsdbError::InvalidArgument(String::from(
+                    "Please return to root before vacuum",
+                )));
             }
+            sync(main_metadata, password, storage, structure_cache)?;
+            let report = vacuum(main_metadata, password, storage, structure_cache)?;
+            println!(
+                "Vacuum complete. {} cells and {} fields dropped.",
+                report.cells_dropped, report.fields_dropped
+            );
+            println!(
+                "Before: {} files, {} bytes. After: {} files, {} bytes.",
+                report.files_before, report.bytes_before, report.files_after, report.bytes_after
+            );
+            Ok(())
         }
+        "padding" => match parsed_command.next() {
+            None => {
+                println!("Padding: {}", Padding::from_metadata(main_metadata)?);
+                Ok(())
+            }
+            Some(i) => {
+                let padding = Padding::from_name(i)?;
+                padding.set(main_metadata);
+                Ok(())
+            }
+        },
+        "dedup" => match parsed_command.next() {
+            None => {
+                println!(
+                    "Blob store is {}.",
+                    if blobstore::is_enabled(main_metadata) {
+                        "on"
+                    } else {
+                        "off"
+                    }
+                );
+                Ok(())
+            }
+            Some(i @ ("on" | "off")) => {
+                blobstore::set_enabled(main_metadata, i == "on");
+                Ok(())
+            }
+            Some(_) => Err(MsdbError::InvalidArgument(String::from(
+                "Usage: dedup [on | off]",
+            ))),
+        },
+        "wipe" => match parsed_command.next() {
+            None => Err(MsdbError::InvalidArgument(String::from(
+                "`wipe` command requires exactly one parameter",
+            ))),
+            Some(i) => {
+                if current_location.current_structure_identifier().is_none()
+                    || current_location.current_object_identifier().is_some()
+                {
+                    return Err(MsdbError::InvalidArgument(String::from(
+                        "Please select the structure of object before wiping it",
+                    )));
+                }
+                let (cells, bytes) = wipe_object(
+                    i,
+                    main_metadata,
+                    current_location,
+                    password,
+                    storage,
+                    structure_cache,
+                )?;
+                println!(
+                    "Object {} wiped. {} cells ({} bytes) reclaimed.",
+                    i, cells, bytes
+                );
+                Ok(())
+            }
+        },
         "unload" => {
             if let Some(i) = parsed_command.next() {
-                structure_cache
-                    .remove(&from_hex_metadata(main_metadata.sub_data().get(i).unwrap()));
+                let identifier = main_metadata
+                    .sub_data()
+                    .get(i)
+                    .ok_or_else(|| MsdbError::NotFound(format!("Structure {}", i)))?;
+                let identifier = from_hex_metadata(identifier)?;
+                if current_location.current_structure_identifier() == Some(identifier) {
+                    return Err(MsdbError::InvalidArgument(format!(
+                        "Please `leave` structure {} before unloading it",
+                        i
+                    )));
+                }
+                structure_cache.remove(&identifier);
+                Ok(())
             } else {
-                println!("`unload` command requires exactly one parameter");
+                Err(MsdbError::InvalidArgument(String::from(
+                    "`unload` command requires exactly one parameter",
+                )))
             }
         }
-        "setprop" => {
-            if let Some(name) = parsed_command.next() {
-                if let Some(value) = parsed_command.next() {
-                    if current_location.current_structure_identifier() == None {
-                        main_metadata.new_attribute(name, value);
-                    } else if current_location.current_object_identifier() == None {
-                        structure_cache
-                            .get_mut(&current_location.current_structure_identifier().unwrap())
-                            .unwrap()
-                            .metadata
-                            .new_attribute(name, value);
-                    } else {
-                        println!("currently `setprop` only works with main metadata and structure metadata");
+        "schema" => {
+            let structure = current_location
+                .current_structure_identifier()
+                .ok_or_else(|| {
+                    MsdbError::InvalidArgument(String::from(
+                        "Please select a structure before using schema",
+                    ))
+                })?;
+            let structure_metadata = &mut structure_cache.get_mut(&structure).unwrap().metadata;
+            let usage = || {
+                MsdbError::InvalidArgument(String::from(
+                    "Usage: schema [show | set FIELD TYPES [--required] [--default VALUE] | unset FIELD | strict on/off]",
+                ))
+            };
+            match parsed_command.next() {
+                None | Some("show") => {
+                    let schema = Schema::from_metadata(structure_metadata);
+                    println!(
+                        "Strict mode: {}",
+                        if schema.strict { "on" } else { "off" }
+                    );
+                    if schema.fields.is_empty() {
+                        println!("No field declared.");
                     }
-                } else {
-                    println!("`setprop` command requires exactly two parameter");
+                    for (i, j) in &schema.fields {
+                        println!("{}: {}", i, j);
+                    }
+                    Ok(())
                 }
-            } else {
-                println!("`setprop` command requires exactly two parameter");
+                Some("set") => {
+                    let field_name = parsed_command.next().ok_or_else(usage)?;
+                    if field_name == "list" {
+                        return Err(MsdbError::InvalidArgument(String::from(
+                            "`list` is a reserved name",
+                        )));
+                    }
+                    let types = parsed_command.next().ok_or_else(usage)?;
+                    let field_schema = parse_field_schema(types, &mut parsed_command)?;
+                    Schema::set_field(structure_metadata, field_name, &field_schema);
+                    Ok(())
+                }
+                Some("unset") => {
+                    let field_name = parsed_command.next().ok_or_else(usage)?;
+                    if !Schema::from_metadata(structure_metadata)
+                        .fields
+                        .contains_key(field_name)
+                    {
+                        return Err(MsdbError::NotFound(format!("Field {} in schema", field_name)));
+                    }
+                    Schema::remove_field(structure_metadata, field_name);
+                    Ok(())
+                }
+                Some("strict") => {
+                    let strict = match parsed_command.next() {
+                        Some("on") => true,
+                        Some("off") => false,
+                        _ => return Err(usage()),
+                    };
+                    Schema::set_strict(structure_metadata, strict);
+                    Ok(())
+                }
+                Some(_) => Err(usage()),
+            }
+        }
+        "compression" => {
+            let structure = current_location
+                .current_structure_identifier()
+                .ok_or_else(|| {
+                    MsdbError::InvalidArgument(String::from(
+                        "Please select a structure before using compression",
+                    ))
+                })?;
+            let structure_metadata = &mut structure_cache.get_mut(&structure).unwrap().metadata;
+            let usage = || {
+                MsdbError::InvalidArgument(String::from(
+                    "Usage: compression [show | set none/lz4 [FIELD] | unset FIELD]",
+                ))
+            };
+            match parsed_command.next() {
+                None | Some("show") => {
+                    println!(
+                        "Structure: {}",
+                        Compression::of_field(structure_metadata, None)?
+                    );
+                    for (i, j) in Compression::fields(structure_metadata) {
+                        println!("{}: {}", i, j);
+                    }
+                    Ok(())
+                }
+                Some("set") => {
+                    let compression =
+                        Compression::from_name(parsed_command.next().ok_or_else(usage)?)?;
+                    Compression::set(structure_metadata, parsed_command.next(), compression);
+                    Ok(())
+                }
+                Some("unset") => {
+                    Compression::unset(structure_metadata, parsed_command.next().ok_or_else(usage)?);
+                    Ok(())
+                }
+                Some(_) => Err(usage()),
             }
         }
+        "setprop" => match (parsed_command.next(), parsed_command.next()) {
+            (Some(name), Some(value)) => {
+                utils::check_property_name(name)?;
+                if current_location.current_structure_identifier().is_none() {
+                    main_metadata.new_attribute(name, value);
+                    Ok(())
+                } else if current_location.current_object_identifier().is_none() {
+                    structure_cache
+                        .get_mut(&current_location.current_structure_identifier().unwrap())
+                        .unwrap()
+                        .metadata
+                        .new_attribute(name, value);
+                    Ok(())
+                } else {
+                    Err(MsdbError::InvalidArgument(String::from(
+                        "currently `setprop` only works with main metadata and structure metadata",
+                    )))
+                }
+            }
+            _ => Err(MsdbError::InvalidArgument(String::from(
+                "`setprop` command requires exactly two parameter",
+            ))),
+        },
         "getprop" => {
-            if current_location.current_structure_identifier() == None {
+            if current_location.current_structure_identifier().is_none() {
                 for (i, j) in main_metadata.attribute() {
                     println!("{}={}", i, j);
                 }
-            } else if current_location.current_object_identifier() == None {
+                Ok(())
+            } else if current_location.current_object_identifier().is_none() {
                 for (i, j) in structure_cache
                     .get_mut(&current_location.current_structure_identifier().unwrap())
                     .unwrap()
@@ -639,23 +781,24 @@ pub fn run_commands(
                 {
                     println!("{}={}", i, j);
                 }
+                Ok(())
             } else {
-                println!(
-                    "currently `getprop` only works with main metadata and structure metadata"
-                );
+                Err(MsdbError::InvalidArgument(String::from(
+                    "currently `getprop` only works with main metadata and structure metadata",
+                )))
             }
         }
         "show" => {
-            if current_location.current_structure_identifier() == None {
+            if current_location.current_structure_identifier().is_none() {
                 println!("{}", main_metadata);
-            } else if current_location.current_object_identifier() == None {
+            } else if current_location.current_object_identifier().is_none() {
                 println!(
                     "{}",
                     structure_cache
                         .get(&current_location.current_structure_identifier().unwrap())
                         .unwrap()
                 );
-            } else if current_location.current_cell_identifier() == None {
+            } else if current_location.current_cell_identifier().is_none() {
                 for i in structure_cache
                     .get(&current_location.current_structure_identifier().unwrap())
                     .unwrap()
@@ -663,67 +806,301 @@ pub fn run_commands(
                     .values()
                 {
                     for j in &i.cells {
-                        if &current_location.current_object_identifier().unwrap()
-                            == match j {
-                                blocks::Cell::Literal(_, k)
-                                | blocks::Cell::Blob(_, k)
-                                | blocks::Cell::Link(_, _, k) => k,
-                                blocks::Cell::BlobIncomplete(_, k)
-                                | blocks::Cell::LiteralIncomplete(_, k) => &k.identifier,
-                            }
-                        {
+                        if current_location.current_object_identifier().unwrap() == j.identifier() {
                             println!("{}\n", j);
                         }
                     }
                 }
             } else {
-                println!("PLEASEIMPLEMENT");
+                //Cell of current object in current field
+                let field_identifier = current_location.current_cell_identifier().unwrap();
+                let field_name = current_location.current_cell_pretty_name().unwrap();
+                let field = structure_cache
+                    .get(&current_location.current_structure_identifier().unwrap())
+                    .unwrap()
+                    .cached_block
+                    .get(&field_identifier)
+                    .ok_or_else(|| {
+                        MsdbError::InvalidArgument(format!(
+                            "Please `load {}` before showing its cell",
+                            field_name
+                        ))
+                    })?;
+                let object = current_location.current_object_identifier().unwrap();
+                let cell = field
+                    .cells
+                    .iter()
+                    .find(|i| i.identifier() == object)
+                    .ok_or_else(|| MsdbError::NotFound(format!("Cell {}", field_name)))?;
+                println!("{}", cell);
+            }
+            Ok(())
+        }
+        i => Err(MsdbError::UnknownCommand(i.to_string())),
+    }
+}
+/// Write main metadata, every cached structure and every cached block onto disk
+///
+/// Everything is committed to storage at once, so an interrupted sync never leaves the database half-written.
+/// Every sync moves the database to a new epoch, which is recorded for each structure and field written.
+pub fn sync(
+    main_metadata: &mut metadata::Metadata,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+    structure_cache: &mut HashMap<[u8; METADATA_INDEX_LEN as usize], Structure>,
+) -> Result<(), MsdbError> {
+    let epoch = utils::read_epoch(main_metadata, utils::EPOCH_ATTRIBUTE)? + 1;
+    let padding = Padding::from_metadata(main_metadata)?;
+    //Metadata is only replaced after commit, so a failed sync leaves recorded epochs intact
+    let mut new_main_metadata = main_metadata.clone();
+    new_main_metadata.set_attribute(utils::EPOCH_ATTRIBUTE, &epoch.to_string());
+    let mut new_structure_metadata = Vec::new();
+    for i in structure_cache.iter_mut() {
+        let mut structure_metadata = i.1.metadata.clone();
+        {
+            //TODO: custom cell size
+            i.1.list.queue.clear();
+            i.1.list.cell_to_raw(None, 512);
+            let filename = format!(
+                "{}/{}",
+                into_hex_metadata(*i.0),
+                i.1.metadata.sub_data().get("list").unwrap()
+            );
+            let compression = Compression::of_field(&i.1.metadata, None)?;
+            for j in &i.1.list.queue {
+                binary_io::write_compressed_block(
+                    storage,
+                    &filename,
+                    0,
+                    epoch,
+                    j,
+                    password,
+                    compression,
+                    &padding,
+                )?;
+            }
+            //An empty list has no block; a stale one would bring deleted objects back
+            if i.1.list.queue.is_empty() {
+                binary_io::remove_with_nonce(storage, &filename)?;
+            }
+        }
+        {
+            //TODO: custom cell size
+            for j in &mut i.1.cached_block {
+                let field_token = into_hex_block(*j.0);
+                let folder_name = format!("{}/{}", into_hex_metadata(*i.0), field_token);
+                let field_name =
+                    i.1.metadata
+                        .sub_data()
+                        .iter()
+                        .find(|(_, l)| **l == field_token)
+                        .map(|(k, _)| k.as_str());
+                let compression = Compression::of_field(&i.1.metadata, field_name)?;
+                j.1.queue.clear();
+                j.1.cell_to_raw(Some(65536), 512);
+                for (current_num, k) in j.1.queue.iter().enumerate() {
+                    let filename = format!("{}/{}.blk", folder_name, current_num);
+                    binary_io::write_compressed_block(
+                        storage,
+                        &filename,
+                        current_num as u64,
+                        epoch,
+                        k,
+                        password,
+                        compression,
+                        &padding,
+                    )?;
+                }
+                //Blocks left over from a larger field
+                let mut current_num = j.1.queue.len();
+                while storage.contains(&format!("{}/{}.blk", folder_name, current_num)) {
+                    binary_io::remove_with_nonce(
+                        storage,
+                        &format!("{}/{}.blk", folder_name, current_num),
+                    )?;
+                    current_num += 1;
+                }
+                structure_metadata
+                    .set_attribute(&utils::epoch_key(&field_token), &epoch.to_string());
+            }
+        }
+        {
+            let filename = format!("{}/metadata", into_hex_metadata(*i.0));
+            binary_io::write_block(
+                storage,
+                &filename,
+                0,
+                epoch,
+                &structure_metadata.to_vec(),
+                password,
+                &padding,
+            )?;
+            new_main_metadata.set_attribute(
+                &utils::epoch_key(&into_hex_metadata(*i.0)),
+                &epoch.to_string(),
+            );
+            new_structure_metadata.push((*i.0, structure_metadata));
+        }
+    }
+    binary_io::write_block(
+        storage,
+        "metadata",
+        0,
+        epoch,
+        &new_main_metadata.to_vec(),
+        password,
+        &padding,
+    )?;
+    storage.commit()?;
+    *main_metadata = new_main_metadata;
+    main_metadata.set_not_modified();
+    for (i, mut j) in new_structure_metadata {
+        j.set_not_modified();
+        structure_cache.get_mut(&i).unwrap().metadata = j;
+    }
+    Ok(())
+}
+/// Re-encrypt every block still in the legacy `secretbox` format with location-bound AEAD
+///
+/// Main metadata is then marked, so legacy blocks are refused afterwards. Pending changes must be synced first.
+/// Returns the number of blocks re-encrypted.
+pub fn upgrade(
+    main_metadata: &mut metadata::Metadata,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+) -> Result<usize, MsdbError> {
+    if utils::is_upgraded(main_metadata) {
+        return Ok(0);
+    }
+    let mut legacy_blocks = Vec::new();
+    for i in main_metadata.sub_data().values() {
+        find_legacy_blocks(storage, i, &mut legacy_blocks)?;
+    }
+    let padding = Padding::from_metadata(main_metadata)?;
+    //Legacy blocks have never been rewritten, so they belong to epoch 0
+    for (filename, index) in &legacy_blocks {
+        let block = binary_io::read_block(storage, filename, *index, 0, password)?;
+        binary_io::write_block(storage, filename, *index, 0, &block, password, &padding)?;
+    }
+    let epoch = utils::read_epoch(main_metadata, utils::EPOCH_ATTRIBUTE)? + 1;
+    let mut new_main_metadata = main_metadata.clone();
+    new_main_metadata.set_attribute(utils::EPOCH_ATTRIBUTE, &epoch.to_string());
+    new_main_metadata.set_attribute(utils::AEAD_ATTRIBUTE, utils::AEAD_REQUIRED);
+    binary_io::write_block(
+        storage,
+        "metadata",
+        0,
+        epoch,
+        &new_main_metadata.to_vec(),
+        password,
+        &padding,
+    )?;
+    storage.commit()?;
+    *main_metadata = new_main_metadata;
+    main_metadata.set_not_modified();
+    password.require_aead();
+    Ok(legacy_blocks.len())
+}
+/// Collect blocks of a structure whose header is in legacy format
+fn find_legacy_blocks(
+    storage: &mut dyn Storage,
+    structure_token: &str,
+    result: &mut Vec<(String, u64)>,
+) -> Result<(), MsdbError> {
+    for filename in storage.list(&format!("{}/", structure_token))? {
+        if let Some(block_name) = filename.strip_suffix(".nonce") {
+            let header = storage.get(&filename)?;
+            if blockencrypt::is_legacy_header(&header) {
+                let index = block_name
+                    .rsplit('/')
+                    .next()
+                    .and_then(|i| i.strip_suffix(".blk"))
+                    .and_then(|i| i.parse().ok())
+                    .unwrap_or(0);
+                result.push((block_name.to_string(), index));
             }
         }
-        i => panic!("Unknown command {}", i),
     }
+    Ok(())
+}
+/// Load fields of current structure into cache
+///
+/// Every field except `list` will be loaded if `field_name` is `None`.
+pub fn load(
+    field_name: Option<&str>,
+    current_location: &DatabaseLocation,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+    structure_cache: &mut HashMap<[u8; METADATA_INDEX_LEN as usize], Structure>,
+) -> Result<(), MsdbError> {
+    let current_structure = current_location.current_structure_identifier().unwrap();
+    let mut result: Vec<([u8; METADATA_INDEX_LEN as usize], blocks::BlockQueue)> = Vec::new();
+    let structure_metadata = &structure_cache.get(&current_structure).unwrap().metadata;
+    for (i, j) in structure_metadata.sub_data() {
+        if i.as_str() != "list" && field_name.is_none_or(|k| k == i) {
+            let field_directory = format!("{}/{}", into_hex_metadata(current_structure), j);
+            let epoch = utils::read_epoch(structure_metadata, &utils::epoch_key(j))?;
+            let temp_block = read_field(&field_directory, epoch, password, storage)?;
+            result.push((from_hex_metadata(j)?, temp_block));
+        }
+    }
+    if let (Some(i), true) = (field_name, result.is_empty()) {
+        return Err(MsdbError::NotFound(format!("Field {}", i)));
+    }
+    for i in result {
+        structure_cache
+            .get_mut(&current_structure)
+            .unwrap()
+            .cached_block
+            .insert(i.0, i.1);
+    }
+    Ok(())
+}
+/// Read every block of the field inside `field_directory`
+fn read_field(
+    field_directory: &str,
+    epoch: u64,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+) -> Result<blocks::BlockQueue, MsdbError> {
+    let mut result = blocks::BlockQueue::new();
+    //TODO: 加入缓存部分Cell的功能
+    let mut current_num = 0;
+    while storage.contains(&format!("{}/{}.blk", field_directory, current_num)) {
+        result.import_from_vec(binary_io::read_block(
+            storage,
+            &format!("{}/{}.blk", field_directory, current_num),
+            current_num,
+            epoch,
+            password,
+        )?);
+        current_num += 1;
+    }
+    result.raw_to_cell(512)?;
+    Ok(result)
 }
 /// Create a structure in root.
-/// it will *panic* if:
-/// 1. Current location does not have a root folder
-/// 2. Unable to create structure directory (eg. due to insufficient permission)
+///
+/// Its metadata is staged in storage, and becomes durable at next `sync`.
 pub fn create_structure(
     structure_name: &str,
-    password: &secretbox::Key,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
     main_metadata: &mut metadata::Metadata,
-    current_location: &mut DatabaseLocation,
     default_cell_list_size: Option<u32>,
-) {
-    let default_cell_list_size = if let Some(i) = default_cell_list_size {
-        i
-    } else {
-        32
-    };
+) -> Result<(), MsdbError> {
+    let default_cell_list_size = default_cell_list_size.unwrap_or(32);
     //Create a structure token
     let structure_token = {
         let mut tk = random_metadata_identifier();
-        let mut structure_directory = format!(
-            "{}/{}",
-            current_location.root_folder().unwrap(),
-            into_hex_metadata(tk)
-        );
-        while path::Path::new(&structure_directory).exists() {
+        while !storage
+            .list(&format!("{}/", into_hex_metadata(tk)))?
+            .is_empty()
+        {
             tk = random_metadata_identifier();
-            structure_directory = format!(
-                "{}/{}",
-                current_location.root_folder().unwrap(),
-                into_hex_metadata(tk)
-            );
         }
         tk
     };
-    //Create structure directory
-    std::fs::create_dir(format!(
-        "{}/{}",
-        current_location.root_folder().unwrap(),
-        into_hex_metadata(structure_token)
-    ))
-    .expect("Unable to create structure directory");
     //Insert structure directory into main metadata
     main_metadata.new_sub_data(structure_name, &into_hex_metadata(structure_token));
     //Write structure metadata
@@ -738,27 +1115,34 @@ pub fn create_structure(
         let list_identifier = random_metadata_identifier();
         structure_data.new_sub_data(&String::from("list"), &into_hex_metadata(list_identifier));
         //Write metadata into file
-        let metadata_vec = structure_data.into_vec();
-        let data = blockencrypt::encrypt_block(&metadata_vec, password);
-        let filename = format!(
-            "{}/{}/metadata",
-            current_location.root_folder().unwrap(),
-            into_hex_metadata(structure_token)
+        let epoch = utils::read_epoch(main_metadata, utils::EPOCH_ATTRIBUTE)?;
+        let padding = Padding::from_metadata(main_metadata)?;
+        binary_io::write_block(
+            storage,
+            &format!("{}/metadata", into_hex_metadata(structure_token)),
+            0,
+            epoch,
+            &structure_data.into_vec(),
+            password,
+            &padding,
+        )?;
+        main_metadata.set_attribute(
+            &utils::epoch_key(&into_hex_metadata(structure_token)),
+            &epoch.to_string(),
         );
-        binary_io::write_with_nonce(&filename, &data.0, data.1);
     }
-    println!(
-        "Structure {}[identifier {}] created.",
-        structure_name,
-        into_hex_metadata(structure_token)
-    );
+    Ok(())
 }
-/// Create a object in current structure
-fn create_object(
+/// Create an object in current structure, together with a cell for each field with a default value
+/// in schema
+pub fn create_object(
     object_name: &str,
+    main_metadata: &mut metadata::Metadata,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
     current_location: &mut DatabaseLocation,
     structure_cache: &mut HashMap<[u8; 8], Structure>,
-) {
+) -> Result<(), MsdbError> {
     //Create a object identifier
     let object_identifier = {
         let mut tk = random_blocks_identifier();
@@ -793,25 +1177,47 @@ fn create_object(
             object_name.to_string(),
             object_identifier,
         ));
-    println!(
-        "Object {}[identifier: {}] created.",
-        object_name,
-        into_hex_block(object_identifier)
+    let schema = Schema::from_metadata(
+        &structure_cache
+            .get(&current_location.current_structure_identifier().unwrap())
+            .unwrap()
+            .metadata,
     );
+    let defaults: Vec<(&String, &FieldSchema, &String)> = schema
+        .fields
+        .iter()
+        .filter_map(|(i, j)| Some((i, j, j.default.as_ref()?)))
+        .collect();
+    if defaults.is_empty() {
+        return Ok(());
+    }
+    current_location.select_object((object_identifier, object_name.to_string()));
+    let result = defaults.into_iter().try_for_each(|(i, j, k)| {
+        create_cell(
+            i,
+            j.default_type(),
+            k,
+            main_metadata,
+            password,
+            storage,
+            current_location,
+            structure_cache,
+        )
+    });
+    current_location.deselect_object();
+    result
 }
 /// Create a field in current structure
 fn create_field(
     field_name: &str,
-    password: &secretbox::Key,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
     current_location: &mut DatabaseLocation,
     structure_cache: &mut HashMap<[u8; 8], Structure>,
     default_cell_size: Option<u32>,
-) -> [u8; METADATA_INDEX_LEN as usize] {
-    let default_cell_size = if let Some(k) = default_cell_size {
-        k
-    } else {
-        32
-    };
+    padding: &Padding,
+) -> Result<[u8; METADATA_INDEX_LEN as usize], MsdbError> {
+    let default_cell_size = default_cell_size.unwrap_or(32);
     //Create a field identifier
     let field_identifier = {
         let mut identifier = random_metadata_identifier();
@@ -833,142 +1239,140 @@ fn create_field(
         .get_mut(&current_location.current_structure_identifier().unwrap())
         .unwrap()
         .metadata
-        .new_sub_data(
-            &field_name.to_string(),
-            &into_hex_metadata(field_identifier),
-        );
+        .new_sub_data(field_name, &into_hex_metadata(field_identifier));
     //Create a empty structure cache in structure cache
     structure_cache
         .get_mut(&current_location.current_structure_identifier().unwrap())
         .unwrap()
         .cached_block
         .insert(field_identifier, blocks::BlockQueue::new());
-    //Create field
-    fs::create_dir(format!(
-        "{}/{}/{}",
-        current_location.root_folder().unwrap(),
-        into_hex_metadata(current_location.current_structure_identifier().unwrap()),
-        into_hex_metadata(field_identifier)
-    ))
-    .expect("Unable to create directory");
     //Create a metadata, containing the default block size for current field
     {
         let mut field_metadata = metadata::Metadata::create();
         field_metadata.new_attribute("size", &format!("{}", default_cell_size));
-        let field_metadata_vec = field_metadata.into_vec();
-        let data = blockencrypt::encrypt_block(&field_metadata_vec, password);
         let filename = format!(
-            "{}/{}/{}/metadata",
-            current_location.root_folder().unwrap(),
+            "{}/{}/metadata",
             into_hex_metadata(current_location.current_structure_identifier().unwrap()),
             into_hex_metadata(field_identifier)
         );
-        binary_io::write_with_nonce(&filename, &data.0, data.1);
+        binary_io::write_block(
+            storage,
+            &filename,
+            0,
+            0,
+            &field_metadata.into_vec(),
+            password,
+            padding,
+        )?;
     }
-    println!(
-        "Field {}[identifier: {}] created.",
-        field_name,
-        into_hex_metadata(field_identifier)
-    );
-    field_identifier
+    Ok(field_identifier)
 }
 /// Change current structure to desired structure
 /// Also load structure metadata and cell list into cache
-fn select_structure(
+pub fn select_structure(
     structure_name: &str,
-    password: &secretbox::Key,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
     main_metadata: &mut metadata::Metadata,
     current_location: &mut DatabaseLocation,
     structure_cache: &mut HashMap<[u8; 8], Structure>,
-) {
-    let structure_token_without_unwarp = main_metadata.sub_data().get(structure_name);
-    if let Some(structure_token) = structure_token_without_unwarp {
-        let structure_metadata = {
-            let structure_list_path = format!(
-                "{}/{}/metadata",
-                current_location.root_folder().unwrap(),
-                structure_token
-            );
-            let structure_list_raw = binary_io::read_with_nonce(&structure_list_path);
-            let structure_list_vec =
-                blockencrypt::decrypt_block(&structure_list_raw.0, password, structure_list_raw.1);
-            metadata::Metadata::from_vec(structure_list_vec)
-        };
-        let cell_list = {
-            let block_list_path = format!(
-                "{}/{}/{}",
-                current_location.root_folder().unwrap(),
-                structure_token,
-                structure_metadata
-                    .sub_data()
-                    .get(&String::from("list"))
-                    .unwrap()
-            );
-            if path::Path::new(&block_list_path).is_file() {
-                //Read cell list
-                let block_list_raw = binary_io::read_with_nonce(&block_list_path);
-                let block_list_vec =
-                    blockencrypt::decrypt_block(&block_list_raw.0, password, block_list_raw.1);
-                blocks::BlockQueue::from_vec(
-                    block_list_vec,
-                    structure_metadata
-                        .attribute()
-                        .get(&String::from("size"))
-                        .unwrap()
-                        .parse()
-                        .unwrap(),
-                )
-            } else {
-                //Create a new cell list
-                blocks::BlockQueue::new()
-            }
-        };
-        structure_cache.insert(
-            from_hex_metadata(structure_token),
-            Structure {
-                metadata: structure_metadata,
-                list: cell_list,
-                cached_block: HashMap::new(),
-            },
-        );
-        current_location.select_structure((
-            from_hex_metadata(structure_token),
-            structure_name.to_string(),
-        ));
-        println!("Structure {}[{}]", structure_name, structure_token);
-    } else {
-        println!(
-            "Unable to select structure {}: No such structure",
-            structure_name
+) -> Result<(), MsdbError> {
+    let structure_token = main_metadata
+        .sub_data()
+        .get(structure_name)
+        .ok_or_else(|| MsdbError::NotFound(format!("Structure {}", structure_name)))?;
+    let epoch = utils::read_epoch(main_metadata, &utils::epoch_key(structure_token))?;
+    structure_cache.insert(
+        from_hex_metadata(structure_token)?,
+        read_structure(structure_token, epoch, password, storage)?,
+    );
+    current_location.select_structure((
+        from_hex_metadata(structure_token)?,
+        structure_name.to_string(),
+    ));
+    Ok(())
+}
+/// Read metadata and cell list of a structure, without any field
+fn read_structure(
+    structure_token: &str,
+    epoch: u64,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+) -> Result<Structure, MsdbError> {
+    let structure_metadata = {
+        let structure_list_vec = binary_io::read_block(
+            storage,
+            &format!("{}/metadata", structure_token),
+            0,
+            epoch,
+            password,
+        )?;
+        metadata::Metadata::from_vec(structure_list_vec)?
+    };
+    let cell_list = {
+        let block_list_path = format!(
+            "{}/{}",
+            structure_token,
+            structure_metadata.sub_data().get("list").ok_or_else(|| {
+                MsdbError::CorruptBlock(String::from("Structure metadata does not have a list"))
+            })?
         );
+        if storage.contains(&block_list_path) {
+            //Read cell list
+            let block_list_vec =
+                binary_io::read_block(storage, &block_list_path, 0, epoch, password)?;
+            blocks::BlockQueue::from_vec(
+                block_list_vec,
+                structure_metadata
+                    .attribute()
+                    .get("size")
+                    .and_then(|i| i.parse().ok())
+                    .ok_or_else(|| {
+                        MsdbError::CorruptBlock(String::from(
+                            "Unable to read cell size from structure metadata",
+                        ))
+                    })?,
+            )?
+        } else {
+            //Create a new cell list
+            blocks::BlockQueue::new()
+        }
+    };
+    Ok(Structure {
+        metadata: structure_metadata,
+        list: cell_list,
+        cached_block: HashMap::new(),
+    })
+}
+/// Find the identifier of an object inside current structure
+pub fn find_object(
+    object_name: &str,
+    current_location: &DatabaseLocation,
+    structure_cache: &HashMap<[u8; 8], Structure>,
+) -> Option<[u8; blocks::CELL_IDENTIFIER_LENGTH as usize]> {
+    let object_list = &structure_cache
+        .get(&current_location.current_structure_identifier()?)?
+        .list;
+    let mut id = None;
+    for individual in &object_list.cells {
+        if let blocks::Cell::Literal(x, y) = individual {
+            if x == object_name {
+                id = Some(*y);
+            }
+        }
     }
+    id
 }
 /// Change current object to desired object
-fn select_object(
+pub fn select_object(
     object_name: &str,
     current_location: &mut DatabaseLocation,
     structure_cache: &mut HashMap<[u8; 8], Structure>,
-) {
-    let object_identifier = {
-        let mut id: Option<[u8; blocks::CELL_IDENTIFIER_LENGTH as usize]> = None;
-        let object_list = &structure_cache
-            .get(&current_location.current_structure_identifier().unwrap())
-            .unwrap()
-            .list;
-        for individual in &object_list.cells {
-            if let blocks::Cell::Literal(x, y) = individual {
-                if x == object_name {
-                    id = Some(*y);
-                }
-            }
-        }
-        id
-    };
-    if let Some(id) = object_identifier {
-        current_location.select_object((id, object_name.to_string()));
-    } else {
-        println!("Unable to select structure {}: No such object", object_name);
-    }
+) -> Result<(), MsdbError> {
+    let id = find_object(object_name, current_location, structure_cache)
+        .ok_or_else(|| MsdbError::NotFound(format!("Object {}", object_name)))?;
+    current_location.select_object((id, object_name.to_string()));
+    Ok(())
 }
 /// Remove all cached block inside structures
 ///
@@ -981,32 +1385,127 @@ fn clear_cache(structure_cache: &mut HashMap<[u8; 8], Structure>) {
         }
     }
 }
-fn alter_cell(
+/// Change a cell of current object in place
+///
+/// The field is loaded if it is not in cache. The new content is checked before anything changes,
+/// so the old value is kept when it is invalid. Without `cell_type`, the cell keeps its type.
+#[allow(clippy::too_many_arguments)]
+pub fn alter_cell(
     cell_name: &str,
-    cell_type: &str,
+    cell_type: Option<&str>,
     cell_content: &str,
-    password: &secretbox::Key,
-    current_location: &mut DatabaseLocation,
+    main_metadata: &mut metadata::Metadata,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+    current_location: &DatabaseLocation,
     structure_cache: &mut HashMap<[u8; 8], Structure>,
-) {
-    //First, delete the cell
-    delete_cell(cell_name, current_location, structure_cache);
-    //Then, create a new cell
-    create_cell(
+) -> Result<(), MsdbError> {
+    let current_object = current_location.current_object_identifier().unwrap();
+    let structure_token =
+        into_hex_metadata(current_location.current_structure_identifier().unwrap());
+    let field_identifier = cached_field(
         cell_name,
-        cell_type,
-        cell_content,
-        password,
         current_location,
+        password,
+        storage,
         structure_cache,
-    );
+    )?;
+    let structure = structure_cache
+        .get_mut(&current_location.current_structure_identifier().unwrap())
+        .unwrap();
+    let schema = Schema::from_metadata(&structure.metadata);
+    let field = structure.cached_block.get_mut(&field_identifier).unwrap();
+    let existing_type = field
+        .cells
+        .iter()
+        .find(|i| i.identifier() == current_object)
+        .ok_or_else(|| MsdbError::NotFound(format!("Cell {}", cell_name)))?
+        .type_name();
+    let cell_type = match cell_type.map(str::to_ascii_lowercase) {
+        Some(i) if CELL_TYPES.contains(&i.as_str()) => i,
+        Some(i) => {
+            return Err(MsdbError::InvalidArgument(format!(
+                "Cell type {} not reconized",
+                i
+            )))
+        }
+        None => existing_type.to_string(),
+    };
+    let new_cell = new_cell(
+        &cell_type,
+        cell_content,
+        current_object,
+        main_metadata,
+        password,
+        storage,
+        &structure_token,
+    )?;
+    if let Err(e) = schema.check_cell(cell_name, new_cell.type_name(), true) {
+        release_blobs(
+            std::iter::once(&new_cell),
+            &structure_token,
+            main_metadata,
+            storage,
+        )?;
+        return Err(e);
+    }
+    release_blobs(
+        field
+            .cells
+            .iter()
+            .filter(|i| i.identifier() == current_object),
+        &structure_token,
+        main_metadata,
+        storage,
+    )?;
+    field.replace_cell(new_cell);
+    Ok(())
+}
+/// Identifier of a field in current structure, loading the field if it is not in cache
+fn cached_field(
+    field_name: &str,
+    current_location: &DatabaseLocation,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+    structure_cache: &mut HashMap<[u8; 8], Structure>,
+) -> Result<[u8; METADATA_INDEX_LEN as usize], MsdbError> {
+    let current_structure = current_location.current_structure_identifier().unwrap();
+    let field_identifier = from_hex_metadata(
+        structure_cache
+            .get(&current_structure)
+            .unwrap()
+            .metadata
+            .sub_data()
+            .get(field_name)
+            .ok_or_else(|| MsdbError::NotFound(format!("Field {}", field_name)))?,
+    )?;
+    if !structure_cache
+        .get(&current_structure)
+        .unwrap()
+        .cached_block
+        .contains_key(&field_identifier)
+    {
+        load(
+            Some(field_name),
+            current_location,
+            password,
+            storage,
+            structure_cache,
+        )?;
+    }
+    Ok(field_identifier)
 }
-/// Delete cell
-fn delete_cell(
+/// Delete cell, releasing its content if it is a streamed or shared blob
+pub fn delete_cell(
     cell_name: &str,
+    main_metadata: &mut metadata::Metadata,
+    storage: &mut dyn Storage,
     current_location: &DatabaseLocation,
     structure_cache: &mut HashMap<[u8; 8], Structure>,
-) {
+) -> Result<(), MsdbError> {
+    let current_object = current_location.current_object_identifier().unwrap();
+    let structure_token =
+        into_hex_metadata(current_location.current_structure_identifier().unwrap());
     let field = structure_cache
         .get_mut(
             &current_location
@@ -1014,167 +1513,1049 @@ fn delete_cell(
                 .expect("Unable to find current structure identifier"),
         )
         .expect("Unable to read structure cache metadata");
-    let field_identifier = field.metadata.sub_data().get(cell_name);
-    if let Some(identifier) = field_identifier {
+    let identifier = field
+        .metadata
+        .sub_data()
+        .get(cell_name)
+        .ok_or_else(|| MsdbError::NotFound(format!("Field {}", cell_name)))?;
+    Schema::from_metadata(&field.metadata).check_delete(cell_name)?;
+    let field = field
+        .cached_block
+        .get_mut(&from_hex_metadata(identifier)?)
+        .ok_or_else(|| {
+            MsdbError::InvalidArgument(format!(
+                "Please load field {} before deleting cell",
+                cell_name
+            ))
+        })?;
+    release_blobs(
         field
-            .cached_block
-            .get_mut(&from_hex_metadata(identifier))
-            .unwrap()
-            .delete_cell(current_location.current_object_identifier().unwrap());
-    } else {
-        println!("Cannot delete cell {}: cell field not exist", cell_name);
+            .cells
+            .iter()
+            .filter(|i| i.identifier() == current_object),
+        &structure_token,
+        main_metadata,
+        storage,
+    )?;
+    field.delete_cell(current_object);
+    Ok(())
+}
+/// Stage removal of the streams of cells, and drop their references into the blob store.
+/// Returns the total length of their blobs.
+fn release_blobs<'a>(
+    cells: impl Iterator<Item = &'a blocks::Cell>,
+    structure_token: &str,
+    main_metadata: &mut metadata::Metadata,
+    storage: &mut dyn Storage,
+) -> Result<u64, MsdbError> {
+    let mut len = 0;
+    for i in cells {
+        match i {
+            blocks::Cell::Stream(j, k, _) => {
+                stream::remove(storage, structure_token, j)?;
+                len += k;
+            }
+            blocks::Cell::Shared(j, k, _) => {
+                blobstore::release(main_metadata, storage, j)?;
+                len += k;
+            }
+            _ => {}
+        }
     }
+    Ok(len)
 }
 /// Delete object
-fn delete_object(
+pub fn delete_object(
     object_name: &str,
     current_location: &mut DatabaseLocation,
     structure_cache: &mut HashMap<[u8; 8], Structure>,
-) {
-    let field = structure_cache
+) -> Result<(), MsdbError> {
+    if find_object(object_name, current_location, structure_cache).is_none() {
+        return Err(MsdbError::NotFound(format!("Object {}", object_name)));
+    }
+    structure_cache
         .get_mut(
             &current_location
                 .current_structure_identifier()
                 .expect("Unable to find current structure identifier"),
         )
-        .expect("Unable to read structure cache metadata");
-    field
-        .cached_block
-        .get_mut(&from_hex_metadata(
-            field.metadata.sub_data().get("list").unwrap(),
-        ))
-        .unwrap()
+        .expect("Unable to read structure cache metadata")
+        .list
         .delete_literal_cell_based_on_content(object_name);
+    Ok(())
 }
-fn delete_structure(
-    structure_name: &str,
+/// Delete an object together with its cells in every field, then sync
+///
+/// Refused while anything differs from what is on disk, so the sync commits the wipe alone and
+/// never changes made before it. Fields not in cache are loaded first. Returns the number of cells
+/// and bytes reclaimed.
+pub fn wipe_object(
+    object_name: &str,
+    main_metadata: &mut metadata::Metadata,
     current_location: &mut DatabaseLocation,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+    structure_cache: &mut HashMap<[u8; 8], Structure>,
+) -> Result<(usize, usize), MsdbError> {
+    let identifier = find_object(object_name, current_location, structure_cache)
+        .ok_or_else(|| MsdbError::NotFound(format!("Object {}", object_name)))?;
+    if has_unsynced_changes(main_metadata, password, storage, structure_cache)? {
+        return Err(MsdbError::InvalidArgument(String::from(
+            "Please `sync` before wiping an object",
+        )));
+    }
+    let current_structure = current_location.current_structure_identifier().unwrap();
+    //Cached fields are the same as on disk, so only the others are loaded
+    let unloaded_fields: Vec<String> = {
+        let structure = structure_cache.get(&current_structure).unwrap();
+        structure
+            .metadata
+            .sub_data()
+            .iter()
+            .filter(|(i, j)| {
+                i.as_str() != "list"
+                    && from_hex_metadata(j)
+                        .map_or(true, |k| !structure.cached_block.contains_key(&k))
+            })
+            .map(|(i, _)| i.clone())
+            .collect()
+    };
+    for i in &unloaded_fields {
+        load(
+            Some(i),
+            current_location,
+            password,
+            storage,
+            structure_cache,
+        )?;
+    }
+    let structure_token = into_hex_metadata(current_structure);
+    let mut cells = 0;
+    let mut bytes = 0;
+    for field in structure_cache
+        .get_mut(&current_structure)
+        .unwrap()
+        .cached_block
+        .values_mut()
+    {
+        let (cells_before, bytes_before) = (field.cells.len(), encoded_len(field));
+        bytes += release_blobs(
+            field.cells.iter().filter(|i| i.identifier() == identifier),
+            &structure_token,
+            main_metadata,
+            storage,
+        )? as usize;
+        field.delete_cell(identifier);
+        cells += cells_before - field.cells.len();
+        bytes += bytes_before - encoded_len(field);
+    }
+    delete_object(object_name, current_location, structure_cache)?;
+    sync(main_metadata, password, storage, structure_cache)?;
+    Ok((cells, bytes))
+}
+/// Whether `sync` would write anything other than a new epoch: staged files, or cache which
+/// differs from blocks on disk
+fn has_unsynced_changes(
+    main_metadata: &metadata::Metadata,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+    structure_cache: &mut HashMap<[u8; METADATA_INDEX_LEN as usize], Structure>,
+) -> Result<bool, MsdbError> {
+    if storage.has_staged_changes() {
+        return Ok(true);
+    }
+    let epoch = utils::read_epoch(main_metadata, utils::EPOCH_ATTRIBUTE)?;
+    let stored_metadata = binary_io::read_block(storage, "metadata", 0, epoch, password)?;
+    if metadata::Metadata::from_vec(stored_metadata)?.to_vec() != main_metadata.to_vec() {
+        return Ok(true);
+    }
+    for (identifier, structure) in structure_cache.iter_mut() {
+        let structure_token = into_hex_metadata(*identifier);
+        //Structures created since last sync are not on disk yet
+        if !storage.contains(&format!("{}/metadata", structure_token)) {
+            return Ok(true);
+        }
+        let epoch = utils::read_epoch(main_metadata, &utils::epoch_key(&structure_token))?;
+        let mut stored = read_structure(&structure_token, epoch, password, storage)?;
+        if stored.metadata.to_vec() != structure.metadata.to_vec() {
+            return Ok(true);
+        }
+        for list in [&mut stored.list, &mut structure.list] {
+            list.queue.clear();
+            list.cell_to_raw(None, 512);
+        }
+        if stored.list.queue != structure.list.queue {
+            return Ok(true);
+        }
+        for (field_identifier, field) in &mut structure.cached_block {
+            let field_token = into_hex_block(*field_identifier);
+            let epoch = utils::read_epoch(&structure.metadata, &utils::epoch_key(&field_token))?;
+            let mut stored_field = read_field(
+                &format!("{}/{}", structure_token, field_token),
+                epoch,
+                password,
+                storage,
+            )?;
+            for field in [&mut stored_field, field] {
+                field.queue.clear();
+                field.cell_to_raw(Some(65536), 512);
+            }
+            if stored_field.queue != field.queue {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+/// Size of a field once written into blocks
+fn encoded_len(field: &mut blocks::BlockQueue) -> usize {
+    field.queue.clear();
+    field.cell_to_raw(Some(65536), 512);
+    field.queue.iter().map(Vec::len).sum()
+}
+/// How `read` outputs content of a literal or blob cell
+pub enum ReadFormat<'a> {
+    /// Literals are printed as they are, blobs as hex
+    Text,
+    Hex,
+    Base64,
+    /// Raw bytes to stdout
+    Raw,
+    /// Raw bytes to a file
+    File(&'a str),
+}
+/// Print a cell of current object. Links are followed to the cell they point to.
+///
+/// The field is loaded if it is not in cache.
+pub fn read_cell(
+    field_name: &str,
+    format: &ReadFormat,
+    main_metadata: &metadata::Metadata,
+    current_location: &DatabaseLocation,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+    structure_cache: &mut HashMap<[u8; 8], Structure>,
+) -> Result<(), MsdbError> {
+    let current_structure = current_location.current_structure_identifier().unwrap();
+    let current_object = current_location.current_object_identifier().unwrap();
+    let field_identifier = cached_field(
+        field_name,
+        current_location,
+        password,
+        storage,
+        structure_cache,
+    )?;
+    let cell = structure_cache
+        .get(&current_structure)
+        .unwrap()
+        .cached_block
+        .get(&field_identifier)
+        .unwrap()
+        .cells
+        .iter()
+        .find(|i| i.identifier() == current_object)
+        .ok_or_else(|| MsdbError::NotFound(format!("Cell {}", field_name)))?;
+    match cell {
+        blocks::Cell::Literal(i, _) => output_text(i, format),
+        blocks::Cell::Blob(i, _) => output_bytes(i, format),
+        blocks::Cell::Stream(i, _, _) => {
+            let id = *i;
+            output_stream(format, |writer| {
+                stream::read(
+                    storage,
+                    password,
+                    &into_hex_metadata(current_structure),
+                    &id,
+                    writer,
+                )
+            })
+        }
+        blocks::Cell::Shared(i, _, _) => {
+            let hash = *i;
+            output_stream(format, |writer| {
+                blobstore::read(main_metadata, storage, password, &hash, writer)
+            })
+        }
+        blocks::Cell::Link(i, j, _) => {
+            let direction = match i {
+                blocks::LinkType::Forward => "Forward",
+                blocks::LinkType::Reverse => "Reverse",
+            };
+            let target = match j {
+                blocks::LinkTarget::SameBlock(k) => (current_structure, field_identifier, *k),
+                blocks::LinkTarget::AnotherField(k, l) => (current_structure, *k, *l),
+                blocks::LinkTarget::AnotherStruct(k, l, m) => (*k, *l, *m),
+            };
+            let (target_name, target_content) =
+                resolve_link(target, main_metadata, password, storage, structure_cache)?;
+            println!("{} link to {}", direction, target_name);
+            println!("{}", target_content);
+            Ok(())
+        }
+        blocks::Cell::LiteralIncomplete(i, _) | blocks::Cell::BlobIncomplete(i, _) => {
+            output_bytes(i, format)
+        }
+        blocks::Cell::Integer(..)
+        | blocks::Cell::Float(..)
+        | blocks::Cell::Boolean(..)
+        | blocks::Cell::Timestamp(..)
+        | blocks::Cell::Uuid(..) => output_text(&cell.typed_value().unwrap(), format),
+    }
+}
+/// Write content of a literal or blob cell of current object into writer, returning its length.
+/// Streamed and shared blobs are decrypted chunk by chunk.
+///
+/// The field is loaded if it is not in cache.
+pub fn read_blob(
+    field_name: &str,
+    writer: &mut impl Write,
+    main_metadata: &metadata::Metadata,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+    current_location: &DatabaseLocation,
+    structure_cache: &mut HashMap<[u8; 8], Structure>,
+) -> Result<u64, MsdbError> {
+    let current_structure = current_location.current_structure_identifier().unwrap();
+    let current_object = current_location.current_object_identifier().unwrap();
+    let field_identifier = cached_field(
+        field_name,
+        current_location,
+        password,
+        storage,
+        structure_cache,
+    )?;
+    let content = match structure_cache
+        .get(&current_structure)
+        .unwrap()
+        .cached_block
+        .get(&field_identifier)
+        .unwrap()
+        .cells
+        .iter()
+        .find(|i| i.identifier() == current_object)
+        .ok_or_else(|| MsdbError::NotFound(format!("Cell {}", field_name)))?
+    {
+        blocks::Cell::Literal(i, _) => i.as_bytes(),
+        blocks::Cell::Blob(i, _) => i,
+        blocks::Cell::Stream(i, _, _) => {
+            let id = *i;
+            return stream::read(
+                storage,
+                password,
+                &into_hex_metadata(current_structure),
+                &id,
+                writer,
+            );
+        }
+        blocks::Cell::Shared(i, _, _) => {
+            let hash = *i;
+            return blobstore::read(main_metadata, storage, password, &hash, writer);
+        }
+        i => {
+            return Err(MsdbError::InvalidArgument(format!(
+                "Cell {} is a {} cell, not a blob",
+                field_name,
+                i.type_name()
+            )))
+        }
+    };
+    writer
+        .write_all(content)
+        .map_err(|e| MsdbError::from_io("blob destination", e))?;
+    Ok(content.len() as u64)
+}
+/// Print text as it is, or its bytes in other formats
+fn output_text(content: &str, format: &ReadFormat) -> Result<(), MsdbError> {
+    match format {
+        ReadFormat::Text => {
+            println!("{}", content);
+            Ok(())
+        }
+        _ => output_bytes(content.as_bytes(), format),
+    }
+}
+fn output_bytes(content: &[u8], format: &ReadFormat) -> Result<(), MsdbError> {
+    match format {
+        ReadFormat::Text | ReadFormat::Hex => println!("{}", hex::encode(content)),
+        ReadFormat::Base64 => println!("{}", utils::base64_encode(content)),
+        ReadFormat::Raw => {
+            let mut stdout = std::io::stdout();
+            stdout
+                .write_all(content)
+                .and_then(|_| stdout.flush())
+                .map_err(|e| MsdbError::from_io("stdout", e))?;
+        }
+        ReadFormat::File(i) => {
+            binary_io::write_all(i, content)?;
+            println!("{} bytes written to {}.", content.len(), i);
+        }
+    }
+    Ok(())
+}
+/// Output a streamed or shared blob chunk by chunk, in the same formats as [`output_bytes`].
+/// `read` decrypts the blob into the writer it is given.
+fn output_stream(
+    format: &ReadFormat,
+    read: impl FnOnce(&mut dyn Write) -> Result<u64, MsdbError>,
+) -> Result<(), MsdbError> {
+    match format {
+        ReadFormat::Text | ReadFormat::Hex | ReadFormat::Base64 => {
+            let mut output = EncodedStdout {
+                base64: matches!(format, ReadFormat::Base64),
+                pending: Vec::new(),
+            };
+            read(&mut output)?;
+            output.finish()
+        }
+        ReadFormat::Raw => {
+            let mut stdout = std::io::stdout();
+            read(&mut stdout)?;
+            stdout.flush().map_err(|e| MsdbError::from_io("stdout", e))
+        }
+        ReadFormat::File(i) => {
+            let mut file = BufWriter::new(File::create(i).map_err(|e| MsdbError::from_io(i, e))?);
+            let len = read(&mut file).and_then(|len| {
+                file.flush()
+                    .map(|_| len)
+                    .map_err(|e| MsdbError::from_io(i, e))
+            });
+            match len {
+                Ok(len) => {
+                    println!("{} bytes written to {}.", len, i);
+                    Ok(())
+                }
+                //Do not leave a truncated file behind
+                Err(e) => {
+                    drop(file);
+                    std::fs::remove_file(i).map_err(|e| MsdbError::from_io(i, e))?;
+                    Err(e)
+                }
+            }
+        }
+    }
+}
+/// Hex or base64 encoder in front of stdout, so that streamed blobs are printed chunk by chunk
+struct EncodedStdout {
+    base64: bool,
+    /// Bytes not printed yet
+    pending: Vec<u8>,
+}
+impl EncodedStdout {
+    fn print(&mut self, len: usize) -> std::io::Result<()> {
+        let encoded = if self.base64 {
+            utils::base64_encode(&self.pending[..len])
+        } else {
+            hex::encode(&self.pending[..len])
+        };
+        self.pending.drain(..len);
+        std::io::stdout().write_all(encoded.as_bytes())
+    }
+    /// Print what is left, and end the line
+    fn finish(mut self) -> Result<(), MsdbError> {
+        self.print(self.pending.len())
+            .map_err(|e| MsdbError::from_io("stdout", e))?;
+        println!();
+        Ok(())
+    }
+}
+impl Write for EncodedStdout {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        //Base64 is encoded 3 bytes at a time, the rest waits for next write
+        let len = if self.base64 {
+            self.pending.len() / 3 * 3
+        } else {
+            self.pending.len()
+        };
+        self.print(len)?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+/// Name (`structure/object's field`) and content of the cell at (structure, field, object)
+///
+/// Structures and fields outside cache are read without being cached.
+fn resolve_link(
+    (structure, field, object): ([u8; 8], [u8; 8], [u8; 8]),
+    main_metadata: &metadata::Metadata,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+    structure_cache: &HashMap<[u8; 8], Structure>,
+) -> Result<(String, String), MsdbError> {
+    let structure_token = into_hex_metadata(structure);
+    let field_token = into_hex_metadata(field);
+    let structure_name = main_metadata
+        .sub_data()
+        .iter()
+        .find(|i| *i.1 == structure_token)
+        .map(|i| i.0.clone())
+        .ok_or_else(|| MsdbError::NotFound(format!("Structure {}", structure_token)))?;
+    let uncached_structure = match structure_cache.get(&structure) {
+        Some(_) => None,
+        None => {
+            let epoch = utils::read_epoch(main_metadata, &utils::epoch_key(&structure_token))?;
+            Some(read_structure(&structure_token, epoch, password, storage)?)
+        }
+    };
+    let target_structure = uncached_structure
+        .as_ref()
+        .or_else(|| structure_cache.get(&structure))
+        .unwrap();
+    let field_name = target_structure
+        .metadata
+        .sub_data()
+        .iter()
+        .find(|i| *i.1 == field_token)
+        .map(|i| i.0.clone())
+        .ok_or_else(|| MsdbError::NotFound(format!("Field {}", field_token)))?;
+    let object_name = target_structure
+        .list
+        .cells
+        .iter()
+        .find_map(|i| match i {
+            blocks::Cell::Literal(j, k) if *k == object => Some(j.clone()),
+            _ => None,
+        })
+        .unwrap_or_else(|| into_hex_block(object));
+    let uncached_field = match target_structure.cached_block.get(&field) {
+        Some(_) => None,
+        None => {
+            let epoch =
+                utils::read_epoch(&target_structure.metadata, &utils::epoch_key(&field_token))?;
+            let field_directory = format!("{}/{}", structure_token, field_token);
+            let result = read_field(&field_directory, epoch, password, storage)?;
+            Some(result)
+        }
+    };
+    let target_field = uncached_field
+        .as_ref()
+        .or_else(|| target_structure.cached_block.get(&field))
+        .unwrap();
+    let content = match target_field.cells.iter().find(|i| i.identifier() == object) {
+        Some(blocks::Cell::Literal(i, _)) => format!("\"{}\"", i),
+        Some(blocks::Cell::Blob(i, _)) => format!("[Blob] {} bytes", i.len()),
+        Some(blocks::Cell::Stream(_, i, _) | blocks::Cell::Shared(_, i, _)) => {
+            format!("[Blob] {} bytes", i)
+        }
+        Some(i) => i.typed_value().unwrap_or_else(|| format!("{}", i)),
+        None => String::from("(cell does not exist)"),
+    };
+    Ok((
+        format!("{}/{}'s {}", structure_name, object_name, field_name),
+        content,
+    ))
+}
+/// Outcome of [`vacuum`]
+pub struct VacuumReport {
+    pub cells_dropped: usize,
+    pub fields_dropped: usize,
+    pub files_before: usize,
+    pub bytes_before: u64,
+    pub files_after: usize,
+    pub bytes_after: u64,
+}
+/// Rewrite every structure densely, dropping cells of deleted objects, fields left without cells
+/// and files which are no longer referenced
+///
+/// Changes not synced must be synced first; structure cache is emptied. Everything is committed at
+/// once by a final `sync`, so an interrupted vacuum leaves the database as it was.
+pub fn vacuum(
     main_metadata: &mut metadata::Metadata,
-) {
-    if let Some(identifier) = main_metadata.sub_data().get(structure_name) {
-        if std::fs::remove_dir(format!(
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+    structure_cache: &mut HashMap<[u8; METADATA_INDEX_LEN as usize], Structure>,
+) -> Result<VacuumReport, MsdbError> {
+    let (files_before, bytes_before) = storage_size(storage)?;
+    let mut cells_dropped = 0;
+    let mut fields_dropped = 0;
+    //Files which stay as they are; blocks of fields are written again by `sync`
+    let mut kept_files = HashSet::new();
+    //References into the blob store held by retained cells
+    let mut blob_references = HashMap::new();
+    structure_cache.clear();
+    for structure_token in main_metadata.sub_data().values() {
+        let epoch = utils::read_epoch(main_metadata, &utils::epoch_key(structure_token))?;
+        let mut structure = read_structure(structure_token, epoch, password, storage)?;
+        let live_objects: HashSet<_> = structure
+            .list
+            .cells
+            .iter()
+            .map(blocks::Cell::identifier)
+            .collect();
+        let mut empty_fields = Vec::new();
+        for (field_name, field_token) in structure.metadata.sub_data() {
+            if field_name.as_str() == "list" {
+                continue;
+            }
+            let field_directory = format!("{}/{}", structure_token, field_token);
+            let field_epoch =
+                utils::read_epoch(&structure.metadata, &utils::epoch_key(field_token))?;
+            let mut field = read_field(&field_directory, field_epoch, password, storage)?;
+            let cell_count = field.cells.len();
+            field
+                .cells
+                .retain(|i| live_objects.contains(&i.identifier()));
+            cells_dropped += cell_count - field.cells.len();
+            //Streams of dropped cells are not kept, nor are their references counted
+            for i in &field.cells {
+                match i {
+                    blocks::Cell::Stream(j, _, _) => {
+                        kept_files.extend(stream::files(storage, structure_token, j)?)
+                    }
+                    blocks::Cell::Shared(j, _, _) => *blob_references.entry(*j).or_insert(0) += 1,
+                    _ => {}
+                }
+            }
+            if field.cells.is_empty() {
+                empty_fields.push(field_name.clone());
+            } else {
+                kept_files.insert(format!("{}/metadata", field_directory));
+                structure
+                    .cached_block
+                    .insert(from_hex_metadata(field_token)?, field);
+            }
+        }
+        for i in empty_fields {
+            let field_token = structure.metadata.sub_data().get(&i).unwrap().clone();
+            structure
+                .metadata
+                .delete_attribute(&utils::epoch_key(&field_token));
+            structure.metadata.delete_sub_data(&i);
+            fields_dropped += 1;
+        }
+        kept_files.insert(format!("{}/metadata", structure_token));
+        kept_files.insert(format!(
             "{}/{}",
-            current_location.root_folder().unwrap(),
-            identifier
-        ))
-        .is_err()
-        {
-            println!("Error happends when removing structure")
-        } else {
-            main_metadata.delete_sub_data(structure_name);
+            structure_token,
+            structure.metadata.sub_data().get("list").unwrap()
+        ));
+        structure_cache.insert(from_hex_metadata(structure_token)?, structure);
+    }
+    blobstore::recount(main_metadata, storage, &blob_references)?;
+    //Files of structures; every other file (key slots, main metadata, blob store) is left alone
+    for i in storage.list("")? {
+        let in_structure = i
+            .split('/')
+            .next()
+            .is_some_and(|j| i.contains('/') && from_hex_metadata(j).is_ok());
+        if in_structure && !kept_files.contains(i.trim_end_matches(".nonce")) {
+            storage.delete(&i)?;
         }
+    }
+    sync(main_metadata, password, storage, structure_cache)?;
+    structure_cache.clear();
+    let (files_after, bytes_after) = storage_size(storage)?;
+    Ok(VacuumReport {
+        cells_dropped,
+        fields_dropped,
+        files_before,
+        bytes_before,
+        files_after,
+        bytes_after,
+    })
+}
+/// Number of files and their total size
+fn storage_size(storage: &mut dyn Storage) -> Result<(usize, u64), MsdbError> {
+    let names = storage.list("")?;
+    let mut bytes = 0;
+    for i in &names {
+        bytes += storage.size(i)?;
+    }
+    Ok((names.len(), bytes))
+}
+/// Delete a structure together with every block inside
+///
+/// Removal is staged in storage, and becomes durable at next `sync`.
+pub fn delete_structure(
+    structure_name: &str,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+    main_metadata: &mut metadata::Metadata,
+    structure_cache: &mut HashMap<[u8; 8], Structure>,
+) -> Result<(), MsdbError> {
+    let identifier = main_metadata
+        .sub_data()
+        .get(structure_name)
+        .ok_or_else(|| MsdbError::NotFound(format!("Structure {}", structure_name)))?
+        .clone();
+    let shared = if blobstore::is_empty(main_metadata) {
+        Vec::new()
     } else {
-        println!(
-            "Unable to remove structure: structure {} does not exist",
-            structure_name
-        );
+        shared_blobs(
+            &identifier,
+            main_metadata,
+            password,
+            storage,
+            structure_cache,
+        )?
+    };
+    for i in &shared {
+        blobstore::release(main_metadata, storage, i)?;
+    }
+    for i in storage.list(&format!("{}/", identifier))? {
+        storage.delete(&i)?;
+    }
+    structure_cache.remove(&from_hex_metadata(&identifier)?);
+    main_metadata.delete_attribute(&utils::epoch_key(&identifier));
+    main_metadata.delete_sub_data(structure_name);
+    Ok(())
+}
+/// Blob store references held by cells of a structure. Cached fields may hold cells not synced
+/// yet, the other ones are read from storage.
+fn shared_blobs(
+    structure_token: &str,
+    main_metadata: &metadata::Metadata,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+    structure_cache: &HashMap<[u8; 8], Structure>,
+) -> Result<Vec<[u8; blobstore::HASH_LEN]>, MsdbError> {
+    let read_structure_result;
+    let structure = match structure_cache.get(&from_hex_metadata(structure_token)?) {
+        Some(i) => i,
+        None => {
+            let epoch = utils::read_epoch(main_metadata, &utils::epoch_key(structure_token))?;
+            read_structure_result = read_structure(structure_token, epoch, password, storage)?;
+            &read_structure_result
+        }
+    };
+    let mut result = Vec::new();
+    for (field_name, field_token) in structure.metadata.sub_data() {
+        if field_name.as_str() == "list" {
+            continue;
+        }
+        let read_field_result;
+        let field = match structure.cached_block.get(&from_hex_metadata(field_token)?) {
+            Some(i) => i,
+            None => {
+                let epoch = utils::read_epoch(&structure.metadata, &utils::epoch_key(field_token))?;
+                let field_directory = format!("{}/{}", structure_token, field_token);
+                read_field_result = read_field(&field_directory, epoch, password, storage)?;
+                &read_field_result
+            }
+        };
+        result.extend(field.cells.iter().filter_map(|i| match i {
+            blocks::Cell::Shared(j, _, _) => Some(*j),
+            _ => None,
+        }));
     }
+    Ok(result)
 }
 /// Create a cell in current object
-fn create_cell(
+#[allow(clippy::too_many_arguments)]
+pub fn create_cell(
     field_name: &str,
     cell_type: &str,
     cell_content: &str,
-    password: &secretbox::Key,
+    main_metadata: &mut metadata::Metadata,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
     current_location: &mut DatabaseLocation,
     structure_cache: &mut HashMap<[u8; 8], Structure>,
-) {
-    //Create a new cell
-    let insert_cell = {
-        match cell_type {
-            "literal" => blocks::Cell::Literal(
-                cell_content.to_string(),
-                current_location.current_object_identifier().unwrap(),
-            ),
-            "blob" => blocks::Cell::Blob(
-                binary_io::read_all(cell_content),
-                current_location.current_object_identifier().unwrap(),
-            ),
-            "link" | "revlink" => {
-                let link_target: Vec<&str> = cell_content.split('/').collect();
-                blocks::Cell::Link(
-                    if "link" == cell_type {
-                        blocks::LinkType::Forward
-                    } else {
-                        blocks::LinkType::Reverse
-                    },
-                    match link_target.len() {
-                        1 => blocks::LinkTarget::SameBlock(from_hex_blocks(
-                            &link_target[0].to_string(),
-                        )),
-                        2 => blocks::LinkTarget::AnotherField(
-                            from_hex_metadata(&link_target[0].to_string()),
-                            from_hex_blocks(&link_target[1].to_string()),
-                        ),
-                        3 => blocks::LinkTarget::AnotherStruct(
-                            from_hex_metadata(&link_target[0].to_string()),
-                            from_hex_metadata(&link_target[1].to_string()),
-                            from_hex_blocks(&link_target[2].to_string()),
-                        ),
-                        _ => panic!("Unexpected link format"),
-                    },
-                    current_location.current_object_identifier().unwrap(),
-                )
-            }
-            _ => {
-                println!("Warning: cell type not reconized. Treated as Literal Cell.");
-                blocks::Cell::Literal(
-                    cell_content.to_string(),
-                    current_location.current_object_identifier().unwrap(),
-                )
-            }
+) -> Result<(), MsdbError> {
+    let structure_token =
+        into_hex_metadata(current_location.current_structure_identifier().unwrap());
+    let padding = Padding::from_metadata(main_metadata)?;
+    let insert_cell = new_cell(
+        cell_type,
+        cell_content,
+        current_location.current_object_identifier().unwrap(),
+        main_metadata,
+        password,
+        storage,
+        &structure_token,
+    )?;
+    let structure_metadata = &structure_cache
+        .get(&current_location.current_structure_identifier().unwrap())
+        .unwrap()
+        .metadata;
+    let field_exists = structure_metadata.sub_data().contains_key(field_name);
+    let checked = Schema::from_metadata(structure_metadata).check_cell(
+        field_name,
+        insert_cell.type_name(),
+        field_exists,
+    );
+    let field_identifier = checked.and_then(|()| {
+        if field_exists {
+            cached_field(
+                field_name,
+                current_location,
+                password,
+                storage,
+                structure_cache,
+            )
+        } else {
+            create_field(
+                field_name,
+                password,
+                storage,
+                current_location,
+                structure_cache,
+                Some(cell_content.len().try_into().unwrap()),
+                &padding,
+            )
+        }
+    });
+    //A cell which cannot be inserted must not keep its stream or blob store reference
+    let field_identifier = match field_identifier {
+        Ok(i) => i,
+        Err(e) => {
+            release_blobs(
+                std::iter::once(&insert_cell),
+                &structure_token,
+                main_metadata,
+                storage,
+            )?;
+            return Err(e);
         }
     };
-    //Get field identifier
-    let field_identifier = if let Some(k) = structure_cache
-        .get(
-            &current_location
-                .current_structure_identifier()
-                .expect("Unable to find current structure identifier"),
+    //Insert cell into structure cache
+    structure_cache
+        .get_mut(&current_location.current_structure_identifier().unwrap())
+        .unwrap()
+        .cached_block
+        .get_mut(&field_identifier)
+        .unwrap()
+        .import_cell(insert_cell);
+    Ok(())
+}
+/// Put everything from reader into a blob cell of current object, replacing the cell if there is
+/// one. Content is encrypted into a stream (in the blob store if it is enabled) chunk by chunk, so
+/// it is never held in memory.
+pub fn write_blob(
+    field_name: &str,
+    reader: &mut impl Read,
+    main_metadata: &mut metadata::Metadata,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+    current_location: &mut DatabaseLocation,
+    structure_cache: &mut HashMap<[u8; 8], Structure>,
+) -> Result<u64, MsdbError> {
+    let current_structure = current_location.current_structure_identifier().unwrap();
+    let current_object = current_location.current_object_identifier().unwrap();
+    let structure_token = into_hex_metadata(current_structure);
+    let structure_metadata = &structure_cache.get(&current_structure).unwrap().metadata;
+    let field_exists = structure_metadata.sub_data().contains_key(field_name);
+    Schema::from_metadata(structure_metadata).check_cell(field_name, "blob", field_exists)?;
+    let padding = Padding::from_metadata(main_metadata)?;
+    let (cell, len) = if blobstore::is_enabled(main_metadata) {
+        let (hash, len) = blobstore::put(main_metadata, storage, password, reader)?;
+        (blocks::Cell::Shared(hash, len, current_object), len)
+    } else {
+        let (id, len) = stream::write(storage, password, &structure_token, reader, &padding)?;
+        (blocks::Cell::Stream(id, len, current_object), len)
+    };
+    let field_identifier = if field_exists {
+        cached_field(
+            field_name,
+            current_location,
+            password,
+            storage,
+            structure_cache,
         )
-        .expect("Unable to read structure cache metadata")
-        .metadata
-        .sub_data()
-        .get(field_name)
-    {
-        let result = k;
-        result.to_string()
     } else {
-        //Create a new field
-        into_hex_metadata(create_field(
+        create_field(
             field_name,
             password,
+            storage,
             current_location,
             structure_cache,
-            Some(cell_content.len().try_into().unwrap()),
-        ))
+            None,
+            &padding,
+        )
     };
-    //Insert cell into structure cache
-    if structure_cache
-        .get(&current_location.current_structure_identifier().unwrap())
+    let field_identifier = match field_identifier {
+        Ok(i) => i,
+        Err(e) => {
+            release_blobs(
+                std::iter::once(&cell),
+                &structure_token,
+                main_metadata,
+                storage,
+            )?;
+            return Err(e);
+        }
+    };
+    let field = structure_cache
+        .get_mut(&current_structure)
         .unwrap()
         .cached_block
-        .get(&from_hex_metadata(&field_identifier))
-        .is_none()
-    {
-        println!("Please cache the cell before writing");
-    } else {
-        structure_cache
-            .get_mut(&current_location.current_structure_identifier().unwrap())
-            .unwrap()
-            .cached_block
-            .get_mut(&from_hex_metadata(&field_identifier))
-            .unwrap()
-            .import_cell(insert_cell);
+        .get_mut(&field_identifier)
+        .unwrap();
+    release_blobs(
+        field
+            .cells
+            .iter()
+            .filter(|i| i.identifier() == current_object),
+        &structure_token,
+        main_metadata,
+        storage,
+    )?;
+    field.replace_cell(cell);
+    Ok(len)
+}
+/// Field declaration from `TYPES [--required] [--default VALUE]` of `schema set`
+///
+/// TYPES is `any` or a comma separated list. The default value must be valid for the first type.
+fn parse_field_schema<'a>(
+    types: &str,
+    options: &mut impl Iterator<Item = &'a str>,
+) -> Result<FieldSchema, MsdbError> {
+    let mut result = FieldSchema::default();
+    if types != "any" {
+        for i in types.split(',') {
+            let i = i.to_ascii_lowercase();
+            if !CELL_TYPES.contains(&i.as_str()) {
+                return Err(MsdbError::InvalidArgument(format!(
+                    "Cell type {} not reconized",
+                    i
+                )));
+            }
+            result.types.push(i);
+        }
     }
+    while let Some(i) = options.next() {
+        match i {
+            "--required" => result.required = true,
+            "--default" => {
+                result.default = Some(
+                    options
+                        .next()
+                        .ok_or_else(|| {
+                            MsdbError::InvalidArgument(String::from("`--default` requires a value"))
+                        })?
+                        .to_string(),
+                )
+            }
+            j => return Err(MsdbError::InvalidArgument(format!("Unexpected {}", j))),
+        }
+    }
+    match (&result.default, result.default_type()) {
+        (Some(_), "blob" | "link" | "revlink") => Err(MsdbError::InvalidArgument(String::from(
+            "Default value is not supported for blob and link cells",
+        ))),
+        (Some(i), j) => {
+            parse_cell(j, i, [0; blocks::CELL_IDENTIFIER_LENGTH as usize]).map(|_| result)
+        }
+        (None, _) if result.required => Err(MsdbError::InvalidArgument(String::from(
+            "A required field needs a default value, which is given to new objects",
+        ))),
+        (None, _) => Ok(result),
+    }
+}
+/// Build a cell like [`parse_cell`]. Blob files go into the blob store if it is enabled; otherwise
+/// those larger than a chunk are streamed instead of being read into memory.
+fn new_cell(
+    cell_type: &str,
+    cell_content: &str,
+    object: [u8; blocks::CELL_IDENTIFIER_LENGTH as usize],
+    main_metadata: &mut metadata::Metadata,
+    password: &DatabaseKey,
+    storage: &mut dyn Storage,
+    structure_token: &str,
+) -> Result<blocks::Cell, MsdbError> {
+    if cell_type != "blob" {
+        return parse_cell(cell_type, cell_content, object);
+    }
+    let mut file = File::open(cell_content).map_err(|e| MsdbError::from_io(cell_content, e))?;
+    if blobstore::is_enabled(main_metadata) {
+        let (hash, len) = blobstore::put(main_metadata, storage, password, &mut file)?;
+        return Ok(blocks::Cell::Shared(hash, len, object));
+    }
+    let len = file
+        .metadata()
+        .map_err(|e| MsdbError::from_io(cell_content, e))?
+        .len();
+    if len <= stream::CHUNK_SIZE as u64 {
+        return parse_cell(cell_type, cell_content, object);
+    }
+    let (id, len) = stream::write(
+        storage,
+        password,
+        structure_token,
+        &mut file,
+        &Padding::from_metadata(main_metadata)?,
+    )?;
+    Ok(blocks::Cell::Stream(id, len, object))
+}
+/// Build a cell of object from the arguments of `new` and `alter`
+fn parse_cell(
+    cell_type: &str,
+    cell_content: &str,
+    object: [u8; blocks::CELL_IDENTIFIER_LENGTH as usize],
+) -> Result<blocks::Cell, MsdbError> {
+    let invalid_value =
+        || MsdbError::InvalidArgument(format!("Invalid {} value {}", cell_type, cell_content));
+    Ok(match cell_type {
+        "literal" => blocks::Cell::Literal(cell_content.to_string(), object),
+        "blob" => blocks::Cell::Blob(binary_io::read_all(cell_content)?, object),
+        "int" => blocks::Cell::Integer(cell_content.parse().map_err(|_| invalid_value())?, object),
+        "float" => blocks::Cell::Float(cell_content.parse().map_err(|_| invalid_value())?, object),
+        "bool" => blocks::Cell::Boolean(
+            match cell_content.to_ascii_lowercase().as_str() {
+                "true" => true,
+                "false" => false,
+                _ => return Err(invalid_value()),
+            },
+            object,
+        ),
+        "timestamp" => blocks::Cell::Timestamp(
+            utils::parse_timestamp(cell_content).ok_or_else(invalid_value)?,
+            object,
+        ),
+        "uuid" => blocks::Cell::Uuid(
+            utils::parse_uuid(cell_content).ok_or_else(invalid_value)?,
+            object,
+        ),
+        "link" | "revlink" => {
+            let link_target: Vec<&str> = cell_content.split('/').collect();
+            let invalid_link =
+                |_| MsdbError::InvalidArgument(format!("Invalid link target {}", cell_content));
+            blocks::Cell::Link(
+                if "link" == cell_type {
+                    blocks::LinkType::Forward
+                } else {
+                    blocks::LinkType::Reverse
+                },
+                match link_target.len() {
+                    1 => blocks::LinkTarget::SameBlock(
+                        from_hex_blocks(link_target[0]).map_err(invalid_link)?,
+                    ),
+                    2 => blocks::LinkTarget::AnotherField(
+                        from_hex_metadata(link_target[0]).map_err(invalid_link)?,
+                        from_hex_blocks(link_target[1]).map_err(invalid_link)?,
+                    ),
+                    3 => blocks::LinkTarget::AnotherStruct(
+                        from_hex_metadata(link_target[0]).map_err(invalid_link)?,
+                        from_hex_metadata(link_target[1]).map_err(invalid_link)?,
+                        from_hex_blocks(link_target[2]).map_err(invalid_link)?,
+                    ),
+                    _ => {
+                        return Err(MsdbError::InvalidArgument(format!(
+                            "Unexpected link format {}",
+                            cell_content
+                        )))
+                    }
+                },
+                object,
+            )
+        }
+        _ => {
+            return Err(MsdbError::InvalidArgument(format!(
+                "Cell type {} not reconized. Available: {}",
+                cell_type,
+                CELL_TYPES.join(", ")
+            )))
+        }
+    })
 }
-
 /// Exit current structure/object/cell
-fn leave(current_location: &mut DatabaseLocation) {
-    if current_location.current_structure_identifier() != None {
-        if current_location.current_object_identifier() == None {
+pub fn leave(current_location: &mut DatabaseLocation) {
+    if current_location.current_structure_identifier().is_some() {
+        if current_location.current_object_identifier().is_none() {
             current_location.deselect_structure();
-        } else if current_location.current_cell_identifier() == None {
+        } else if current_location.current_cell_identifier().is_none() {
             current_location.deselect_object();
         } else {
             current_location.deselect_cell();
@@ -1274,132 +2655,83 @@ fn ugly_print_cell(
         };
         for k in &j.cells {
             //Get current field name and identifier
-            if match k {
-                blocks::Cell::Literal(_, l)
-                | blocks::Cell::Blob(_, l)
-                | blocks::Cell::Link(_, _, l) => *l,
-                blocks::Cell::LiteralIncomplete(_, l) | blocks::Cell::BlobIncomplete(_, l) => {
-                    l.identifier
-                }
-            } == current_object
-            {
+            if k.identifier() == current_object {
                 println!(
                     "{}",
                     match k {
                         blocks::Cell::Literal(m, _) => format!("{} : \"{}\"", current_field, m),
                         blocks::Cell::Blob(m, _) =>
                             format!("{}: {}", current_field, hex::encode(m)),
-                        blocks::Cell::Link(m, n, _) => {
-                            format!(
-                                "{}: {} Link to {}",
-                                current_field,
-                                match m {
-                                    blocks::LinkType::Forward => "Forward",
-                                    blocks::LinkType::Reverse => "Reverse",
-                                },
-                                match n {
-                                    blocks::LinkTarget::SameBlock(o) => {
-                                        let mut result_cell_name = String::new();
-                                        for p in &structure_cache
-                                            .get(
-                                                &current_location
-                                                    .current_structure_identifier()
-                                                    .unwrap(),
-                                            )
-                                            .unwrap()
-                                            .list
-                                            .cells
-                                        {
-                                            if let blocks::Cell::Literal(r, q) = p {
-                                                if q == o {
-                                                    result_cell_name = r.to_string();
-                                                }
-                                            }
-                                        }
-                                        format!("{}'s same cell", result_cell_name)
-                                    }
-                                    blocks::LinkTarget::AnotherField(o, p) => {
-                                        let mut result_cell_name = String::new();
-                                        for q in &structure_cache
-                                            .get(
-                                                &current_location
-                                                    .current_structure_identifier()
-                                                    .unwrap(),
-                                            )
-                                            .unwrap()
-                                            .list
-                                            .cells
-                                        {
-                                            if let blocks::Cell::Literal(s, r) = q {
-                                                if r == p {
-                                                    result_cell_name = s.to_string();
-                                                }
-                                            }
-                                        }
-                                        let result_field_name = &structure_cache
-                                            .get(
-                                                &current_location
-                                                    .current_structure_identifier()
-                                                    .unwrap(),
-                                            )
-                                            .unwrap()
-                                            .metadata
-                                            .sub_data()
-                                            .get(&into_hex_metadata(*o))
-                                            .unwrap();
-                                        format!("{}'s {}", result_cell_name, result_field_name)
-                                    }
-                                    blocks::LinkTarget::AnotherStruct(o, p, q) => {
-                                        let mut result_cell_name = String::new();
-                                        for r in &structure_cache
-                                            .get(
-                                                &current_location
-                                                    .current_structure_identifier()
-                                                    .unwrap(),
-                                            )
-                                            .unwrap()
-                                            .list
-                                            .cells
-                                        {
-                                            if let blocks::Cell::Literal(t, s) = r {
-                                                if s == q {
-                                                    result_cell_name = t.to_string();
-                                                }
-                                            }
-                                        }
-                                        let result_field_name = &structure_cache
-                                            .get(
-                                                &current_location
-                                                    .current_structure_identifier()
-                                                    .unwrap(),
-                                            )
-                                            .unwrap()
-                                            .metadata
-                                            .sub_data()
-                                            .get(&into_hex_metadata(*p))
-                                            .unwrap();
-                                        let result_struct_name = &main_metadata
-                                            .sub_data()
-                                            .get(&into_hex_metadata(*o))
-                                            .unwrap();
-                                        format!(
-                                            "{}/{}'s {}",
-                                            result_struct_name, result_cell_name, result_field_name
-                                        )
-                                    }
-                                }
-                            )
-                        }
+                        blocks::Cell::Stream(_, m, _) | blocks::Cell::Shared(_, m, _) =>
+                            format!("{}: [Blob] {} bytes", current_field, m),
+                        blocks::Cell::Link(m, n, _) => format!(
+                            "{}: {} Link to {}",
+                            current_field,
+                            match m {
+                                blocks::LinkType::Forward => "Forward",
+                                blocks::LinkType::Reverse => "Reverse",
+                            },
+                            link_name(n, *i, current_location, structure_cache, main_metadata)
+                        ),
                         blocks::Cell::BlobIncomplete(m, _) =>
                             format!("{}: [BlobIncomplete] {}", current_field, hex::encode(m)),
                         blocks::Cell::LiteralIncomplete(m, _) =>
                             format!("{}: [LiteralIncomplete] {}", current_field, hex::encode(m)),
+                        blocks::Cell::Integer(..)
+                        | blocks::Cell::Float(..)
+                        | blocks::Cell::Boolean(..)
+                        | blocks::Cell::Timestamp(..)
+                        | blocks::Cell::Uuid(..) =>
+                            format!("{} : {}", current_field, k.typed_value().unwrap()),
                     }
                 )
             }
         }
     }
 }
+/// `structure/object's field` a link points to, as far as names are in cache
+fn link_name(
+    target: &blocks::LinkTarget,
+    current_field: [u8; 8],
+    current_location: &DatabaseLocation,
+    structure_cache: &HashMap<[u8; 8], Structure>,
+    main_metadata: &metadata::Metadata,
+) -> String {
+    let current_structure = current_location.current_structure_identifier().unwrap();
+    let (structure, field, object) = match target {
+        blocks::LinkTarget::SameBlock(i) => (current_structure, current_field, *i),
+        blocks::LinkTarget::AnotherField(i, j) => (current_structure, *i, *j),
+        blocks::LinkTarget::AnotherStruct(i, j, k) => (*i, *j, *k),
+    };
+    let find_name = |sub_data: &HashMap<String, String>, token: String| {
+        sub_data
+            .iter()
+            .find(|i| *i.1 == token)
+            .map_or(token, |i| i.0.clone())
+    };
+    let structure_name = find_name(main_metadata.sub_data(), into_hex_metadata(structure));
+    match structure_cache.get(&structure) {
+        Some(i) => {
+            let object_name = i
+                .list
+                .cells
+                .iter()
+                .find_map(|j| match j {
+                    blocks::Cell::Literal(k, l) if *l == object => Some(k.clone()),
+                    _ => None,
+                })
+                .unwrap_or_else(|| into_hex_block(object));
+            let field_name = find_name(i.metadata.sub_data(), into_hex_metadata(field));
+            format!("{}/{}'s {}", structure_name, object_name, field_name)
+        }
+        None => format!(
+            "{}/{}'s {}",
+            structure_name,
+            into_hex_block(object),
+            into_hex_metadata(field)
+        ),
+    }
+}
 /// Debug version of printing cells inside current object
 fn debug_print_cell(
     current_location: &DatabaseLocation,
@@ -1431,15 +2763,7 @@ fn debug_print_cell(
         );
         for k in &j.cells {
             //Get current field name and identifier
-            if match k {
-                blocks::Cell::Literal(_, l)
-                | blocks::Cell::Blob(_, l)
-                | blocks::Cell::Link(_, _, l) => *l,
-                blocks::Cell::LiteralIncomplete(_, l) | blocks::Cell::BlobIncomplete(_, l) => {
-                    l.identifier
-                }
-            } == current_object
-            {
+            if k.identifier() == current_object {
                 println!(
                     "{}",
                     match k {
@@ -1447,6 +2771,18 @@ fn debug_print_cell(
                             format!("{} : [Literal] {}", current_field, m),
                         blocks::Cell::Blob(m, _) =>
                             format!("{}: [Blob] {}", current_field, hex::encode(m)),
+                        blocks::Cell::Stream(m, n, _) => format!(
+                            "{}: [Blob] stream {}, {} bytes",
+                            current_field,
+                            hex::encode(m),
+                            n
+                        ),
+                        blocks::Cell::Shared(m, n, _) => format!(
+                            "{}: [Blob] shared {}, {} bytes",
+                            current_field,
+                            hex::encode(m),
+                            n
+                        ),
                         blocks::Cell::Link(m, n, _) => {
                             format!(
                                 "{}: [Link] {} - {}",
@@ -1472,6 +2808,16 @@ fn debug_print_cell(
                             format!("{}: [BlobIncomplete] {}", current_field, hex::encode(m)),
                         blocks::Cell::LiteralIncomplete(m, _) =>
                             format!("{}: [LiteralIncomplete] {}", current_field, hex::encode(m)),
+                        blocks::Cell::Integer(..)
+                        | blocks::Cell::Float(..)
+                        | blocks::Cell::Boolean(..)
+                        | blocks::Cell::Timestamp(..)
+                        | blocks::Cell::Uuid(..) => format!(
+                            "{}: [{}] {}",
+                            current_field,
+                            k.type_name(),
+                            k.typed_value().unwrap()
+                        ),
                     }
                 )
             }
@@ -0,0 +1,72 @@
+/// Errors which may happen when operating on a database
+#[derive(Debug)]
+pub enum MsdbError {
+    /// Unable to decrypt a block, usually due to a wrong password
+    BadPassword,
+    /// Unable to derive key from password
+    KeyDerivation,
+    /// A file required by database does not exist
+    MissingFile(String),
+    /// Other I/O failures when reading or writing a file
+    Io(String, std::io::Error),
+    /// Content of a block (or metadata) cannot be parsed
+    CorruptBlock(String),
+    /// Unknown opcode found when parsing a block
+    BadOpcode(u8),
+    /// Database version is not equal than database manager version
+    VersionMismatch { database: u8, manager: u8 },
+    /// Command not recognized
+    UnknownCommand(String),
+    /// Structure / object / field does not exist
+    NotFound(String),
+    /// Structure / object already exists
+    AlreadyExists(String),
+    /// Command is called with invalid arguments or at invalid location
+    InvalidArgument(String),
+    /// Database epoch is lower than the one recorded in trust file
+    RolledBack { seen: u64, found: u64 },
+}
+impl MsdbError {
+    /// Translate an I/O error on `filename`
+    pub fn from_io(filename: &str, error: std::io::Error) -> Self {
+        if error.kind() == std::io::ErrorKind::NotFound {
+            Self::MissingFile(filename.to_string())
+        } else {
+            Self::Io(filename.to_string(), error)
+        }
+    }
+}
+impl std::fmt::Display for MsdbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::BadPassword => write!(f, "Invalid password"),
+            Self::KeyDerivation => write!(f, "Unable to derive key from password"),
+            Self::MissingFile(i) => write!(f, "File {} does not exist", i),
+            Self::Io(i, j) => write!(f, "Unable to access {}: {}", i, j),
+            Self::CorruptBlock(i) => write!(f, "Corrupt block: {}", i),
+            Self::BadOpcode(i) => write!(f, "Found unknown OPCODE {} in raw block", i),
+            Self::VersionMismatch { database, manager } => write!(
+                f,
+                "Database version({}) is not equal than database manager version({})",
+                database, manager
+            ),
+            Self::UnknownCommand(i) => write!(f, "Unknown command {}", i),
+            Self::NotFound(i) => write!(f, "{} does not exist", i),
+            Self::AlreadyExists(i) => write!(f, "{} already exists", i),
+            Self::InvalidArgument(i) => write!(f, "{}", i),
+            Self::RolledBack { seen, found } => write!(
+                f,
+                "Database has been rolled back to epoch {} (epoch {} was seen before)",
+                found, seen
+            ),
+        }
+    }
+}
+impl std::error::Error for MsdbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(_, i) => Some(i),
+            _ => None,
+        }
+    }
+}
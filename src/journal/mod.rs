@@ -0,0 +1,216 @@
+//! Write-ahead journal used by `sync`
+//!
+//! Every file is first written to `<file>.tmp` and flushed to disk. Then the list of pending
+//! changes is written to `<database>/journal`, which is the commit point. Finally every temp
+//! file is renamed into place and the journal is removed.
+//!
+//! If the program stops before the journal is written, the temp files are discarded (roll back);
+//! otherwise the renames are replayed (roll forward). See [`recover`].
+use super::binary_io;
+use super::error::MsdbError;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path;
+pub const JOURNAL_NAME: &str = "journal";
+pub const TEMP_SUFFIX: &str = ".tmp";
+/// Pending changes of one transaction. Paths are relative to database root.
+pub struct Journal {
+    root: String,
+    /// `true` for a write, `false` for a removal. Only the last change of a file is kept.
+    changes: BTreeMap<String, bool>,
+}
+impl Journal {
+    pub fn new(root: &str) -> Self {
+        Self {
+            root: root.to_string(),
+            changes: BTreeMap::new(),
+        }
+    }
+    /// Stage a file. It will not be visible until `commit`.
+    pub fn write(&mut self, filename: &str, data: &[u8]) -> Result<(), MsdbError> {
+        binary_io::write_all_synced(&self.temp_filename(filename), data)?;
+        self.changes.insert(filename.to_string(), true);
+        Ok(())
+    }
+    /// Stage removal of a file
+    pub fn remove(&mut self, filename: &str) {
+        if self.changes.insert(filename.to_string(), false) == Some(true) {
+            //Leftover temp files are cleaned up by `recover` anyway
+            let _ = fs::remove_file(self.temp_filename(filename));
+        }
+    }
+    /// Staged change of a file: `Some(true)` if written, `Some(false)` if removed
+    pub fn staged(&self, filename: &str) -> Option<bool> {
+        self.changes.get(filename).copied()
+    }
+    pub fn changes(&self) -> impl Iterator<Item = (&String, bool)> {
+        self.changes.iter().map(|(i, j)| (i, *j))
+    }
+    /// Where the staged content of a file is kept until `commit`
+    pub fn temp_filename(&self, filename: &str) -> String {
+        format!("{}/{}{}", self.root, filename, TEMP_SUFFIX)
+    }
+    /// Make every staged change visible on disk
+    pub fn commit(&mut self) -> Result<(), MsdbError> {
+        if self.changes.is_empty() {
+            return Ok(());
+        }
+        let mut directories = BTreeSet::new();
+        for (i, _) in self.changes().filter(|i| i.1) {
+            directories.insert(self.parent_directory(i));
+        }
+        for i in &directories {
+            binary_io::sync_directory(i)?;
+        }
+        let mut journal_content = String::new();
+        for (i, write) in self.changes() {
+            let operation = if write { "put" } else { "del" };
+            journal_content.push_str(&format!("{} {}\n", operation, i));
+        }
+        let journal_filename = format!("{}/{}", self.root, JOURNAL_NAME);
+        let temp_journal_filename = format!("{}{}", journal_filename, TEMP_SUFFIX);
+        binary_io::write_all_synced(&temp_journal_filename, journal_content.as_bytes())?;
+        rename(&temp_journal_filename, &journal_filename)?;
+        binary_io::sync_directory(&self.root)?;
+        replay(&self.root, &journal_content)?;
+        self.changes.clear();
+        Ok(())
+    }
+    fn parent_directory(&self, filename: &str) -> String {
+        parent_directory(&self.root, filename)
+    }
+}
+/// Bring a database back to a consistent state after an interrupted `sync`
+///
+/// Changes are rolled forward if the journal had been committed, otherwise rolled back. Returns
+/// whether they were rolled forward.
+pub fn recover(root: &str) -> Result<bool, MsdbError> {
+    let journal_filename = format!("{}/{}", root, JOURNAL_NAME);
+    if path::Path::new(&journal_filename).is_file() {
+        let journal_content = binary_io::read_all(&journal_filename)?;
+        let journal_content = String::from_utf8(journal_content)
+            .map_err(|_| MsdbError::CorruptBlock(String::from("Invalid journal")))?;
+        replay(root, &journal_content)?;
+        Ok(true)
+    } else {
+        remove_temp_files(root)?;
+        Ok(false)
+    }
+}
+fn replay(root: &str, journal_content: &str) -> Result<(), MsdbError> {
+    let mut directories = BTreeSet::new();
+    //Directories which may have been left empty by removals
+    let mut emptied_directories = BTreeSet::new();
+    for line in journal_content.lines() {
+        let (operation, filename) = match (line.get(..4), line.get(4..)) {
+            (Some(i), Some(j)) if !j.is_empty() => (i, j),
+            _ => return Err(MsdbError::CorruptBlock(String::from("Invalid journal"))),
+        };
+        let full_filename = format!("{}/{}", root, filename);
+        match operation {
+            "put " => {
+                let temp_filename = format!("{}{}", full_filename, TEMP_SUFFIX);
+                //A missing temp file has already been renamed
+                if path::Path::new(&temp_filename).is_file() {
+                    rename(&temp_filename, &full_filename)?;
+                }
+            }
+            "del " => {
+                if path::Path::new(&full_filename).is_file() {
+                    fs::remove_file(&full_filename)
+                        .map_err(|e| MsdbError::from_io(&full_filename, e))?;
+                }
+                let mut directory = filename;
+                while let Some(i) = directory.rfind('/') {
+                    directory = &directory[..i];
+                    emptied_directories.insert(format!("{}/{}", root, directory));
+                }
+            }
+            _ => return Err(MsdbError::CorruptBlock(String::from("Invalid journal"))),
+        }
+        directories.insert(parent_directory(root, filename));
+    }
+    for i in &directories {
+        binary_io::sync_directory(i)?;
+    }
+    //Deepest first; directories which still hold files are kept
+    for i in emptied_directories.iter().rev() {
+        let _ = fs::remove_dir(i);
+    }
+    let journal_filename = format!("{}/{}", root, JOURNAL_NAME);
+    fs::remove_file(&journal_filename).map_err(|e| MsdbError::from_io(&journal_filename, e))?;
+    binary_io::sync_directory(root)
+}
+fn remove_temp_files(directory: &str) -> Result<(), MsdbError> {
+    let entries = fs::read_dir(directory).map_err(|e| MsdbError::from_io(directory, e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| MsdbError::from_io(directory, e))?;
+        let entry_path = entry.path();
+        let entry_name = entry_path.to_string_lossy().to_string();
+        if entry_path.is_dir() {
+            remove_temp_files(&entry_name)?;
+        } else if entry_name.ends_with(TEMP_SUFFIX) {
+            fs::remove_file(&entry_path).map_err(|e| MsdbError::from_io(&entry_name, e))?;
+        }
+    }
+    Ok(())
+}
+fn rename(from: &str, to: &str) -> Result<(), MsdbError> {
+    fs::rename(from, to).map_err(|e| MsdbError::from_io(from, e))
+}
+fn parent_directory(root: &str, filename: &str) -> String {
+    match filename.rfind('/') {
+        Some(i) => format!("{}/{}", root, &filename[..i]),
+        None => root.to_string(),
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::{recover, Journal};
+    use std::fs;
+    use std::path::Path;
+    #[test]
+    fn roll_back_without_journal() {
+        fs::create_dir_all("journaldb_back/field").unwrap();
+        fs::write("journaldb_back/metadata", b"old").unwrap();
+        fs::write("journaldb_back/metadata.tmp", b"new").unwrap();
+        fs::write("journaldb_back/field/0.blk.tmp", b"new").unwrap();
+        assert!(!recover("journaldb_back").unwrap());
+        assert_eq!(fs::read("journaldb_back/metadata").unwrap(), b"old");
+        assert!(!Path::new("journaldb_back/metadata.tmp").exists());
+        assert!(!Path::new("journaldb_back/field/0.blk.tmp").exists());
+        fs::remove_dir_all("journaldb_back").unwrap();
+    }
+    #[test]
+    fn roll_forward_with_journal() {
+        fs::create_dir_all("journaldb_forward/field").unwrap();
+        fs::write("journaldb_forward/metadata", b"old").unwrap();
+        fs::write("journaldb_forward/field/1.blk", b"stale").unwrap();
+        //Crash right after the journal is written
+        fs::write("journaldb_forward/metadata.tmp", b"new").unwrap();
+        fs::write("journaldb_forward/field/0.blk", b"new").unwrap();
+        fs::write(
+            "journaldb_forward/journal",
+            b"put metadata\nput field/0.blk\ndel field/1.blk\n",
+        )
+        .unwrap();
+        assert!(recover("journaldb_forward").unwrap());
+        assert_eq!(fs::read("journaldb_forward/metadata").unwrap(), b"new");
+        assert_eq!(fs::read("journaldb_forward/field/0.blk").unwrap(), b"new");
+        assert!(!Path::new("journaldb_forward/field/1.blk").exists());
+        assert!(!Path::new("journaldb_forward/journal").exists());
+        fs::remove_dir_all("journaldb_forward").unwrap();
+    }
+    #[test]
+    fn commit() {
+        fs::create_dir_all("journaldb_commit").unwrap();
+        fs::write("journaldb_commit/metadata", b"old").unwrap();
+        let mut journal = Journal::new("journaldb_commit");
+        journal.write("metadata", b"new").unwrap();
+        journal.commit().unwrap();
+        assert_eq!(fs::read("journaldb_commit/metadata").unwrap(), b"new");
+        assert!(!Path::new("journaldb_commit/metadata.tmp").exists());
+        assert!(!Path::new("journaldb_commit/journal").exists());
+        fs::remove_dir_all("journaldb_commit").unwrap();
+    }
+}
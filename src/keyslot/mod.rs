@@ -0,0 +1,393 @@
+//! Key slots
+//!
+//! Every slot in `<database>/keyslots/` wraps the same data key with its own credential (password
+//! and salt, or a raw keyfile), so a credential can be revoked without changing everyone else's.
+//!
+//! Databases created before key slots keep `salt` (and `key`) in database root. They are read as
+//! slot 0, and converted on the first change to slots.
+use super::binary_io;
+use super::blockencrypt::{self, Kdf};
+use super::credential::Credential;
+use super::error::MsdbError;
+use super::metadata::Metadata;
+use super::storage::Storage;
+use sodiumoxide::crypto::pwhash;
+use sodiumoxide::crypto::secretbox;
+const KEYSLOT_FOLDER: &str = "keyslots";
+pub struct KeySlot {
+    label: String,
+    /// `None` for keyfile slots
+    kdf: Option<Kdf>,
+    salt: Vec<u8>,
+    wrapped_key: Vec<u8>,
+    nonce: secretbox::Nonce,
+}
+impl KeySlot {
+    fn new(
+        label: &str,
+        credential: &Credential,
+        kdf: Kdf,
+        key: &secretbox::Key,
+    ) -> Result<Self, MsdbError> {
+        check_label(label)?;
+        let salt = kdf.gen_salt();
+        let wrapped_key = blockencrypt::wrap_key(key, &credential.wrapping_key(&kdf, &salt)?);
+        Ok(Self {
+            label: label.to_string(),
+            kdf: if credential.is_keyfile() {
+                None
+            } else {
+                Some(kdf)
+            },
+            salt,
+            wrapped_key: wrapped_key.0,
+            nonce: wrapped_key.1,
+        })
+    }
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+    /// Whether the slot is unlocked by a keyfile instead of a password
+    pub const fn is_keyfile(&self) -> bool {
+        self.kdf.is_none()
+    }
+    /// Password hashing parameters. `None` for keyfile slots.
+    pub const fn kdf(&self) -> Option<Kdf> {
+        self.kdf
+    }
+    /// Try to unwrap data key with credential
+    fn unlock(&self, credential: &Credential) -> Result<secretbox::Key, MsdbError> {
+        let wrapping_key = match (self.kdf, credential.is_keyfile()) {
+            (Some(kdf), false) => credential.wrapping_key(&kdf, &self.salt)?,
+            (None, true) => credential.wrapping_key(&Kdf::default(), &self.salt)?,
+            _ => return Err(MsdbError::BadPassword),
+        };
+        blockencrypt::unwrap_key(&self.wrapped_key, &wrapping_key, self.nonce)
+    }
+    fn to_vec(&self) -> Vec<u8> {
+        let mut slot_metadata = Metadata::create();
+        slot_metadata.new_attribute("label", &self.label);
+        match self.kdf {
+            None => slot_metadata.new_attribute("kdf", "raw"),
+            Some(Kdf::Scrypt) => slot_metadata.new_attribute("kdf", "scrypt"),
+            Some(Kdf::Argon2id { opslimit, memlimit }) => {
+                slot_metadata.new_attribute("kdf", "argon2id");
+                slot_metadata.new_attribute("opslimit", &opslimit.to_string());
+                slot_metadata.new_attribute("memlimit", &memlimit.to_string());
+            }
+        }
+        slot_metadata.new_attribute("salt", &hex::encode(&self.salt));
+        slot_metadata.new_attribute("key", &hex::encode(&self.wrapped_key));
+        slot_metadata.new_attribute("nonce", &hex::encode(&self.nonce[..]));
+        slot_metadata.to_vec()
+    }
+    fn from_vec(filename: &str, slot: Vec<u8>) -> Result<Self, MsdbError> {
+        let corrupt = || MsdbError::CorruptBlock(format!("Invalid key slot {}", filename));
+        let slot_metadata = Metadata::from_vec(slot)?;
+        let attribute = |name: &str| -> Result<Vec<u8>, MsdbError> {
+            slot_metadata
+                .attribute()
+                .get(name)
+                .and_then(|i| hex::decode(i).ok())
+                .ok_or_else(corrupt)
+        };
+        let limit = |name: &str| -> Result<usize, MsdbError> {
+            slot_metadata
+                .attribute()
+                .get(name)
+                .and_then(|i| i.parse().ok())
+                .ok_or_else(corrupt)
+        };
+        let kdf = match slot_metadata.attribute().get("kdf").map(String::as_str) {
+            Some("raw") => None,
+            None | Some("scrypt") => Some(Kdf::Scrypt),
+            Some("argon2id") => Some(Kdf::Argon2id {
+                opslimit: limit("opslimit")?,
+                memlimit: limit("memlimit")?,
+            }),
+            Some(_) => return Err(corrupt()),
+        };
+        Ok(Self {
+            label: slot_metadata
+                .attribute()
+                .get("label")
+                .cloned()
+                .unwrap_or_default(),
+            kdf,
+            salt: attribute("salt")?,
+            wrapped_key: attribute("key")?,
+            nonce: secretbox::Nonce::from_slice(&attribute("nonce")?).ok_or_else(corrupt)?,
+        })
+    }
+}
+/// Every key slot of a database, sorted by slot number
+pub fn list(storage: &mut dyn Storage) -> Result<Vec<(u32, KeySlot)>, MsdbError> {
+    if !has_keyslots(storage) {
+        return Ok(vec![(0, legacy_slot(storage)?)]);
+    }
+    let mut result = Vec::new();
+    for filename in storage.list(&format!("{}/", KEYSLOT_FOLDER))? {
+        if let Ok(number) = filename[KEYSLOT_FOLDER.len() + 1..].parse() {
+            let slot = KeySlot::from_vec(&filename, storage.get(&filename)?)?;
+            result.push((number, slot));
+        }
+    }
+    result.sort_by_key(|i| i.0);
+    Ok(result)
+}
+/// Find the slot which can be unlocked with credential, returning slot number and data key
+pub fn unlock(
+    storage: &mut dyn Storage,
+    credential: &Credential,
+) -> Result<(u32, secretbox::Key), MsdbError> {
+    if !has_keyslots(storage) {
+        return legacy_unlock(storage, credential).map(|i| (0, i));
+    }
+    for (number, slot) in list(storage)? {
+        match slot.unlock(credential) {
+            Ok(key) => return Ok((number, key)),
+            Err(MsdbError::BadPassword) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(MsdbError::BadPassword)
+}
+/// Stage the first slot of a new database
+pub fn create(
+    storage: &mut dyn Storage,
+    credential: &Credential,
+    kdf: Kdf,
+    key: &secretbox::Key,
+) -> Result<(), MsdbError> {
+    let slot = KeySlot::new("default", credential, kdf, key)?;
+    storage.put(&format!("{}/0", KEYSLOT_FOLDER), &slot.to_vec())
+}
+/// Add a slot, returning its number
+pub fn add(
+    storage: &mut dyn Storage,
+    label: &str,
+    credential: &Credential,
+    kdf: Kdf,
+    key: &secretbox::Key,
+) -> Result<u32, MsdbError> {
+    require_unstaged(storage)?;
+    let slot = KeySlot::new(label, credential, kdf, key)?;
+    let slots = migrate(storage, key)?;
+    let number = slots.last().map_or(0, |i| i.0 + 1);
+    storage.put(&format!("{}/{}", KEYSLOT_FOLDER, number), &slot.to_vec())?;
+    storage.commit()?;
+    Ok(number)
+}
+/// Replace credential of a slot
+///
+/// The label and Argon2 parameters are kept; slots using scrypt are upgraded to default Argon2 parameters.
+pub fn change_credential(
+    storage: &mut dyn Storage,
+    number: u32,
+    credential: &Credential,
+    key: &secretbox::Key,
+) -> Result<(), MsdbError> {
+    require_unstaged(storage)?;
+    let slots = migrate(storage, key)?;
+    let old_slot = find(&slots, number)?;
+    let kdf = match old_slot.kdf {
+        Some(Kdf::Argon2id { opslimit, memlimit }) => Kdf::Argon2id { opslimit, memlimit },
+        _ => Kdf::default(),
+    };
+    let slot = KeySlot::new(&old_slot.label, credential, kdf, key)?;
+    storage.put(&format!("{}/{}", KEYSLOT_FOLDER, number), &slot.to_vec())?;
+    storage.commit()
+}
+/// Re-hash the password of a slot with new parameters. The password itself is kept.
+pub fn retune(
+    storage: &mut dyn Storage,
+    number: u32,
+    password: &Credential,
+    kdf: Kdf,
+    key: &secretbox::Key,
+) -> Result<(), MsdbError> {
+    require_unstaged(storage)?;
+    let slots = migrate(storage, key)?;
+    let old_slot = find(&slots, number)?;
+    if old_slot.is_keyfile() {
+        return Err(MsdbError::InvalidArgument(String::from(
+            "Keyfile slots do not use password hashing",
+        )));
+    }
+    if old_slot.unlock(password)? != *key {
+        return Err(MsdbError::BadPassword);
+    }
+    let slot = KeySlot::new(&old_slot.label, password, kdf, key)?;
+    storage.put(&format!("{}/{}", KEYSLOT_FOLDER, number), &slot.to_vec())?;
+    storage.commit()
+}
+pub fn set_label(
+    storage: &mut dyn Storage,
+    number: u32,
+    label: &str,
+    key: &secretbox::Key,
+) -> Result<(), MsdbError> {
+    check_label(label)?;
+    require_unstaged(storage)?;
+    let mut slots = migrate(storage, key)?;
+    let slot = slots
+        .iter_mut()
+        .find(|i| i.0 == number)
+        .ok_or_else(|| MsdbError::NotFound(format!("Key slot {}", number)))?;
+    slot.1.label = label.to_string();
+    storage.put(&format!("{}/{}", KEYSLOT_FOLDER, number), &slot.1.to_vec())?;
+    storage.commit()
+}
+/// Remove a slot. The last slot cannot be revoked.
+pub fn revoke(
+    storage: &mut dyn Storage,
+    number: u32,
+    key: &secretbox::Key,
+) -> Result<(), MsdbError> {
+    require_unstaged(storage)?;
+    let slots = migrate(storage, key)?;
+    find(&slots, number)?;
+    if slots.len() == 1 {
+        return Err(MsdbError::InvalidArgument(String::from(
+            "You cannot revoke the last key slot",
+        )));
+    }
+    storage.delete(&format!("{}/{}", KEYSLOT_FOLDER, number))?;
+    storage.commit()
+}
+/// Slots are committed on their own, which would also commit whatever else is staged (e.g. files
+/// of a deleted structure, while main metadata still refers to them)
+fn require_unstaged(storage: &dyn Storage) -> Result<(), MsdbError> {
+    if storage.has_staged_changes() {
+        Err(MsdbError::InvalidArgument(String::from(
+            "Please `sync` before changing key slots",
+        )))
+    } else {
+        Ok(())
+    }
+}
+fn find(slots: &[(u32, KeySlot)], number: u32) -> Result<&KeySlot, MsdbError> {
+    slots
+        .iter()
+        .find(|i| i.0 == number)
+        .map(|i| &i.1)
+        .ok_or_else(|| MsdbError::NotFound(format!("Key slot {}", number)))
+}
+/// Labels are listed one per line
+fn check_label(label: &str) -> Result<(), MsdbError> {
+    if label.contains(char::is_control) {
+        Err(MsdbError::InvalidArgument(String::from(
+            "Key slot label cannot contain control characters",
+        )))
+    } else {
+        Ok(())
+    }
+}
+/// Whether slot files exist
+fn has_keyslots(storage: &mut dyn Storage) -> bool {
+    storage
+        .list(&format!("{}/", KEYSLOT_FOLDER))
+        .map(|entries| {
+            entries
+                .iter()
+                .any(|i| i[KEYSLOT_FOLDER.len() + 1..].parse::<u32>().is_ok())
+        })
+        .unwrap_or(false)
+}
+/// Stage conversion of a database without key slots into slot 0, returning every slot
+fn migrate(
+    storage: &mut dyn Storage,
+    key: &secretbox::Key,
+) -> Result<Vec<(u32, KeySlot)>, MsdbError> {
+    if has_keyslots(storage) {
+        return list(storage);
+    }
+    let mut slot = legacy_slot(storage)?;
+    if slot.wrapped_key.is_empty() {
+        //Without `key` header, data key is the key derived from password. Wrap it with itself.
+        let wrapped_key = blockencrypt::wrap_key(key, key);
+        slot.wrapped_key = wrapped_key.0;
+        slot.nonce = wrapped_key.1;
+    }
+    storage.put(&format!("{}/0", KEYSLOT_FOLDER), &slot.to_vec())?;
+    storage.delete("salt")?;
+    binary_io::remove_with_nonce(storage, "key")?;
+    Ok(vec![(0, slot)])
+}
+/// Read `salt` and `key` in database root. `wrapped_key` is empty if there is no `key` header.
+fn legacy_slot(storage: &mut dyn Storage) -> Result<KeySlot, MsdbError> {
+    let salt_vec = storage.get("salt")?;
+    let salt = pwhash::Salt::from_slice(&salt_vec[..]).ok_or_else(|| {
+        MsdbError::CorruptBlock(format!("Invalid salt file {}/salt", storage.name()))
+    })?;
+    let (wrapped_key, nonce) = if storage.contains("key") {
+        let (wrapped_key, nonce) = binary_io::read_with_nonce(storage, "key")?;
+        let nonce = secretbox::Nonce::from_slice(&nonce).ok_or_else(|| {
+            MsdbError::CorruptBlock(format!("Invalid nonce file {}/key.nonce", storage.name()))
+        })?;
+        (wrapped_key, nonce)
+    } else {
+        (Vec::new(), secretbox::Nonce([0; secretbox::NONCEBYTES]))
+    };
+    Ok(KeySlot {
+        label: String::from("default"),
+        kdf: Some(Kdf::Scrypt),
+        salt: salt[..].to_vec(),
+        wrapped_key,
+        nonce,
+    })
+}
+fn legacy_unlock(
+    storage: &mut dyn Storage,
+    credential: &Credential,
+) -> Result<secretbox::Key, MsdbError> {
+    if credential.is_keyfile() {
+        return Err(MsdbError::BadPassword);
+    }
+    let slot = legacy_slot(storage)?;
+    let password_key = credential.wrapping_key(&Kdf::Scrypt, &slot.salt)?;
+    if slot.wrapped_key.is_empty() {
+        Ok(password_key)
+    } else {
+        blockencrypt::unwrap_key(&slot.wrapped_key, &password_key, slot.nonce)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::{add, list, revoke, unlock};
+    use crate::blockencrypt::Kdf;
+    use crate::credential::Credential;
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::{utils, MsdbError, VERSION_NUMBER};
+    use sodiumoxide::crypto::secretbox;
+    #[test]
+    fn add_and_revoke() {
+        let first = Credential::password("first");
+        let second = Credential::Keyfile(secretbox::gen_key());
+        let mut storage = MemoryStorage::new("keyslotdb");
+        let key = utils::new_database(&mut storage, &first, Kdf::default(), VERSION_NUMBER)
+            .unwrap()
+            .data_key()
+            .clone();
+        assert_eq!(
+            add(&mut storage, "second", &second, Kdf::default(), &key).unwrap(),
+            1
+        );
+        assert_eq!(unlock(&mut storage, &second).unwrap().0, 1);
+        assert_eq!(unlock(&mut storage, &first).unwrap().1, key);
+        revoke(&mut storage, 0, &key).unwrap();
+        assert!(matches!(
+            unlock(&mut storage, &first),
+            Err(MsdbError::BadPassword)
+        ));
+        assert!(revoke(&mut storage, 1, &key).is_err());
+        assert_eq!(list(&mut storage).unwrap().len(), 1);
+        add(&mut storage, "a=b; $c", &first, Kdf::default(), &key).unwrap();
+        assert_eq!(list(&mut storage).unwrap()[1].1.label(), "a=b; $c");
+        assert!(add(&mut storage, "a\nb", &first, Kdf::default(), &key).is_err());
+        //Unrelated staged changes are not committed along with a slot
+        storage.delete("metadata").unwrap();
+        assert!(add(&mut storage, "third", &first, Kdf::default(), &key).is_err());
+        assert_eq!(list(&mut storage).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,30 @@
+//! Mobile Secure DataBase
+//!
+//! Use [`Database`] to create, unlock and operate on a database.
+pub mod archive;
+pub mod binary_io;
+pub mod blobstore;
+pub mod blockencrypt;
+pub mod blocks;
+pub mod compression;
+pub mod container;
+pub mod credential;
+mod database;
+pub mod db_commands;
+mod error;
+mod journal;
+pub mod keyslot;
+pub mod metadata;
+pub mod padding;
+pub mod rpc;
+pub mod schema;
+pub mod storage;
+pub mod stream;
+pub mod tokenizer;
+pub mod trust;
+pub mod utils;
+pub use blockencrypt::Kdf;
+pub use credential::Credential;
+pub use database::Database;
+pub use error::MsdbError;
+pub const VERSION_NUMBER: u8 = 4;
@@ -1,20 +1,13 @@
 #![feature(test)]
-use clap::{App, Arg};
-use sodiumoxide::crypto::pwhash;
-use sodiumoxide::crypto::secretbox;
-use std::collections::HashMap;
+use clap::{App, Arg, SubCommand};
+use msdb::tokenizer::Reader;
+use msdb::trust::{RollbackPolicy, TrustFile};
+use msdb::{Credential, Database, Kdf, MsdbError};
 use std::fs::File;
 use std::io::{self, BufRead};
 use std::path;
 use std::path::Path;
 extern crate test;
-mod binary_io;
-mod blockencrypt;
-mod blocks;
-mod db_commands;
-mod metadata;
-mod utils;
-const VERSION_NUMBER: u8 = 4;
 fn main() {
     let matches = App::new("Mobile Secure DataBase (msdb)")
         .version("Version 0.4 (db version code 4)")
@@ -27,14 +20,71 @@ fn main() {
                 .value_name("FILE")
                 .help("Load MSDB script from a file"),
         )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about(
+                    "Serve JSON-RPC 2.0 requests over a Unix domain socket or localhost TCP port",
+                )
+                .arg(
+                    Arg::with_name("socket")
+                        .long("socket")
+                        .value_name("PATH")
+                        .conflicts_with("port")
+                        .required_unless("port")
+                        .help("Listen on a Unix domain socket"),
+                )
+                .arg(
+                    Arg::with_name("port")
+                        .long("port")
+                        .value_name("PORT")
+                        .help("Listen on a localhost TCP port"),
+                )
+                .arg(
+                    Arg::with_name("root")
+                        .long("root")
+                        .value_name("DIR")
+                        .default_value(".")
+                        .help("Directory holding every file clients may open or create"),
+                ),
+        )
         .get_matches();
 
-    if matches.is_present("input") {
+    if let Some(serve_matches) = matches.subcommand_matches("serve") {
+        serve(serve_matches);
+    } else if matches.is_present("input") {
         from_file(matches.value_of("input").unwrap());
     } else {
         main_cli();
     }
 }
+fn serve(matches: &clap::ArgMatches) {
+    let root = matches.value_of("root").unwrap();
+    let result = if let Some(port) = matches.value_of("port") {
+        match port.parse() {
+            Ok(port) => msdb::rpc::serve_tcp(port, root),
+            Err(_) => {
+                println!("Invalid port {}", port);
+                return;
+            }
+        }
+    } else {
+        serve_socket(matches.value_of("socket").unwrap(), root)
+    };
+    if let Err(e) = result {
+        println!("Error: {}", e);
+    }
+}
+#[cfg(unix)]
+fn serve_socket(path: &str, root: &str) -> io::Result<()> {
+    msdb::rpc::serve_unix(path, root)
+}
+#[cfg(not(unix))]
+fn serve_socket(_path: &str, _root: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Unix domain sockets are not supported on this platform",
+    ))
+}
 fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
 where
     P: AsRef<Path>,
@@ -46,100 +96,295 @@ where
 fn from_file(filename: &str) {
     //A simple command parser
     if let Ok(lines) = read_lines(filename) {
-        let mut current_location = db_commands::DatabaseLocation::new();
-        let mut password: (secretbox::Key, bool) = (secretbox::gen_key(), false);
-        let mut main_metadata: metadata::Metadata = metadata::Metadata::create();
-        let mut structure_cache: HashMap<
-            [u8; blocks::CELL_IDENTIFIER_LENGTH as usize],
-            db_commands::Structure,
-        > = HashMap::new();
-        sodiumoxide::init().expect("Unable to initialize SoldiumMoxide");
-        for line in lines {
-            if let Ok(eachline) = line {
-                let mut parsed_commands = eachline.split_whitespace();
-                if let Some(i) = parsed_commands.next() {
-                    match i {
-                        "create" => {
-                            let database_name = parsed_commands.next().unwrap();
-                            let database_password = if let Some(j) = parsed_commands.next() {
-                                j.to_string()
-                            } else {
-                                rpassword::prompt_password_stdout("Password: ")
-                                    .expect("Unable to read password using rpassword")
-                            };
-                            utils::new_database(&database_name, &database_password, VERSION_NUMBER);
-                        }
-                        "decrypt" => {
-                            let database_name = parsed_commands.next().unwrap();
-                            let try_passwd = {
-                                let database_password = if let Some(j) = parsed_commands.next() {
-                                    j.to_string()
-                                } else {
-                                    rpassword::prompt_password_stdout("Password: ")
-                                        .expect("Unable to read password using rpassword")
-                                };
-                                let salt = {
-                                    let salt_directory = format!("{}/salt", database_name);
-                                    let salt_vec = binary_io::read_all(&salt_directory);
-                                    pwhash::Salt::from_slice(&salt_vec[..]).unwrap()
-                                };
-                                blockencrypt::password_deriv(&database_password, salt)
-                            };
-                            main_metadata =
-                                utils::select_database(&database_name, &try_passwd, VERSION_NUMBER);
-                            current_location.select_root(database_name.to_string());
-                            password = (try_passwd, true);
-                        }
-                        "exit" => utils::exit(),
-                        _ => {
-                            if password.1 {
-                                db_commands::run_commands(
-                                    &eachline,
-                                    &mut main_metadata,
-                                    &mut current_location,
-                                    &password.0,
-                                    &mut structure_cache,
-                                );
-                            } else {
-                                panic!("Database unavailable");
-                            }
-                        }
-                    }
+        let mut database: Option<Database> = None;
+        let mut reader = Reader::new();
+        for eachline in lines.map_while(Result::ok) {
+            let result = reader
+                .push_line(&eachline)
+                .and_then(|arguments| match arguments {
+                    Some(arguments) => run_script_line(&arguments, &mut database),
+                    None => Ok(()),
+                });
+            if let Err(e) = result {
+                println!("Error: {}", e);
+            }
+        }
+        if reader.is_pending() {
+            println!("Error: Unterminated heredoc at end of script");
+        }
+    }
+}
+fn run_script_line(arguments: &[String], database: &mut Option<Database>) -> Result<(), MsdbError> {
+    let mut parsed_commands = arguments.iter().map(String::as_str);
+    match parsed_commands.next() {
+        Some("create") => {
+            let database_name = parsed_commands.next().ok_or_else(|| {
+                MsdbError::InvalidArgument(String::from("`create` requires database name"))
+            })?;
+            let mut arguments: Vec<&str> = parsed_commands.collect();
+            let kdf = take_kdf_option(&mut arguments)?;
+            let credential = unlock_credential(&mut arguments.into_iter())?;
+            Database::create_with_credential(database_name, &credential, kdf)?.close();
+            println!("Completed. Have a nice day.");
+            Ok(())
+        }
+        Some("decrypt") => {
+            let database_name = parsed_commands.next().ok_or_else(|| {
+                MsdbError::InvalidArgument(String::from("`decrypt` requires database name"))
+            })?;
+            *database = Some(open_database(database_name, parsed_commands.collect())?);
+            Ok(())
+        }
+        Some("convert") => convert_command(parsed_commands),
+        Some("passwd") => {
+            let db = database
+                .as_mut()
+                .ok_or_else(|| MsdbError::InvalidArgument(String::from("Database unavailable")))?;
+            let credential = match parse_credential(&mut parsed_commands)? {
+                Some(i) => i,
+                None => Credential::password(&prompt_new_password(false)),
+            };
+            db.change_credential(&credential)
+        }
+        Some("kdf") => {
+            let db = database
+                .as_mut()
+                .ok_or_else(|| MsdbError::InvalidArgument(String::from("Database unavailable")))?;
+            let kdf = Kdf::from_profile(parsed_commands.next().ok_or_else(|| {
+                MsdbError::InvalidArgument(String::from("`kdf` requires a profile"))
+            })?)?;
+            db.retune(&unlock_credential(&mut parsed_commands)?, kdf)
+        }
+        Some("keyslot") => {
+            let db = database
+                .as_mut()
+                .ok_or_else(|| MsdbError::InvalidArgument(String::from("Database unavailable")))?;
+            keyslot_command(db, parsed_commands, false)
+        }
+        Some("exit") => msdb::utils::exit(),
+        Some(_) => match database.as_mut() {
+            Some(db) => db.run_arguments(arguments),
+            None => Err(MsdbError::InvalidArgument(String::from(
+                "Database unavailable",
+            ))),
+        },
+        None => Ok(()),
+    }
+}
+/// Credential written after command arguments: `PASSWORD`, `--password-file FILE`,
+/// `--password-fd FD` or `--keyfile FILE`
+fn parse_credential<'a, I: Iterator<Item = &'a str>>(
+    parsed_commands: &mut I,
+) -> Result<Option<Credential>, MsdbError> {
+    let option = parsed_commands.next();
+    let mut source_argument = || {
+        parsed_commands.next().ok_or_else(|| {
+            MsdbError::InvalidArgument(format!("`{}` requires an argument", option.unwrap()))
+        })
+    };
+    match option {
+        None => Ok(None),
+        Some("--password-file") => Credential::from_password_file(source_argument()?).map(Some),
+        Some("--password-fd") => {
+            let fd = source_argument()?
+                .parse()
+                .map_err(|_| MsdbError::InvalidArgument(String::from("Invalid file descriptor")))?;
+            Credential::from_password_fd(fd).map(Some)
+        }
+        Some("--keyfile") => Credential::from_keyfile(source_argument()?).map(Some),
+        Some(password) => Ok(Some(Credential::password(password))),
+    }
+}
+/// Credential for `create` and `decrypt`: from arguments, then `MSDB_PASSWORD`, then prompt
+fn unlock_credential<'a, I: Iterator<Item = &'a str>>(
+    parsed_commands: &mut I,
+) -> Result<Credential, MsdbError> {
+    Ok(match parse_credential(parsed_commands)? {
+        Some(i) => i,
+        None => match Credential::from_env() {
+            Some(i) => i,
+            None => Credential::password(
+                &rpassword::prompt_password_stdout("Password: ")
+                    .expect("Unable to read password using rpassword"),
+            ),
+        },
+    })
+}
+/// `convert SOURCE DESTINATION [CREDENTIAL]`
+fn convert_command<'a, I: Iterator<Item = &'a str>>(
+    mut parsed_commands: I,
+) -> Result<(), MsdbError> {
+    match (parsed_commands.next(), parsed_commands.next()) {
+        (Some(source), Some(destination)) => {
+            let files = msdb::utils::convert_database(
+                source,
+                destination,
+                &unlock_credential(&mut parsed_commands)?,
+            )?;
+            println!("{} files copied.", files);
+            Ok(())
+        }
+        _ => Err(MsdbError::InvalidArgument(String::from(
+            "`convert` requires source and destination",
+        ))),
+    }
+}
+/// `decrypt` with arguments `[--trust FILE [--refuse-rollback]] [CREDENTIAL]`
+fn open_database(database_name: &str, mut arguments: Vec<&str>) -> Result<Database, MsdbError> {
+    let policy = match arguments.iter().position(|i| *i == "--refuse-rollback") {
+        Some(i) => {
+            arguments.remove(i);
+            RollbackPolicy::Refuse
+        }
+        None => RollbackPolicy::Warn,
+    };
+    let trust_file = match arguments.iter().position(|i| *i == "--trust") {
+        Some(i) if i + 1 < arguments.len() => {
+            let trust_file = TrustFile::new(arguments[i + 1], policy);
+            arguments.drain(i..=i + 1);
+            Some(trust_file)
+        }
+        Some(_) => {
+            return Err(MsdbError::InvalidArgument(String::from(
+                "`--trust` requires an argument",
+            )))
+        }
+        None if policy == RollbackPolicy::Refuse => {
+            return Err(MsdbError::InvalidArgument(String::from(
+                "`--refuse-rollback` requires `--trust`",
+            )))
+        }
+        None => None,
+    };
+    let credential = unlock_credential(&mut arguments.into_iter())?;
+    let mut database = Database::open_with_credential(database_name, &credential)?;
+    if database.needs_upgrade() {
+        println!("This database was written by an older version. Please `upgrade` it.");
+    }
+    if let Some(i) = trust_file {
+        database.set_trust_file(i)?;
+    }
+    Ok(database)
+}
+/// Remove `--kdf PROFILE` from arguments, returning the default profile if it is absent
+fn take_kdf_option(arguments: &mut Vec<&str>) -> Result<Kdf, MsdbError> {
+    match arguments.iter().position(|i| *i == "--kdf") {
+        Some(i) if i + 1 < arguments.len() => {
+            let kdf = Kdf::from_profile(arguments[i + 1])?;
+            arguments.drain(i..=i + 1);
+            Ok(kdf)
+        }
+        Some(_) => Err(MsdbError::InvalidArgument(String::from(
+            "`--kdf` requires an argument",
+        ))),
+        None => Ok(Kdf::default()),
+    }
+}
+fn prompt_new_password(confirm: bool) -> String {
+    let password = rpassword::prompt_password_stdout("New password: ")
+        .expect("Unable to read password using rpassword");
+    if confirm {
+        let mut password_comfirm = String::new();
+        while password != password_comfirm {
+            password_comfirm = rpassword::prompt_password_stdout("Confirm new password: ")
+                .expect("Unable to read password confirmation using rpassword");
+        }
+    }
+    password
+}
+/// `keyslot list`, `keyslot add LABEL [--kdf PROFILE] [CREDENTIAL]`, `keyslot label SLOT LABEL` or `keyslot revoke SLOT`
+fn keyslot_command<'a, I: Iterator<Item = &'a str>>(
+    db: &mut Database,
+    mut parsed_commands: I,
+    interactive: bool,
+) -> Result<(), MsdbError> {
+    let usage = || {
+        MsdbError::InvalidArgument(String::from(
+            "Usage: keyslot list | add LABEL | label SLOT LABEL | revoke SLOT",
+        ))
+    };
+    let slot_number = |parsed_commands: &mut I| {
+        parsed_commands
+            .next()
+            .and_then(|i| i.parse::<u32>().ok())
+            .ok_or_else(usage)
+    };
+    match parsed_commands.next() {
+        Some("list") => {
+            for (number, slot) in db.keyslots()? {
+                let kind = match slot.kdf() {
+                    Some(kdf) => format!("password, {}", kdf),
+                    None => String::from("keyfile"),
+                };
+                if number == db.keyslot() {
+                    println!("[{}] {} ({}, current)", number, slot.label(), kind);
+                } else {
+                    println!("[{}] {} ({})", number, slot.label(), kind);
                 }
             }
+            Ok(())
         }
+        Some("add") => {
+            let label = parsed_commands.next().ok_or_else(usage)?;
+            let mut arguments: Vec<&str> = parsed_commands.collect();
+            let kdf = take_kdf_option(&mut arguments)?;
+            let credential = match parse_credential(&mut arguments.into_iter())? {
+                Some(i) => i,
+                None => Credential::password(&prompt_new_password(interactive)),
+            };
+            println!(
+                "Key slot {} added.",
+                db.add_keyslot(label, &credential, kdf)?
+            );
+            Ok(())
+        }
+        Some("label") => {
+            let number = slot_number(&mut parsed_commands)?;
+            let label = parsed_commands.next().ok_or_else(usage)?;
+            db.label_keyslot(number, label)
+        }
+        Some("revoke") => {
+            let number = slot_number(&mut parsed_commands)?;
+            db.revoke_keyslot(number)
+        }
+        _ => Err(usage()),
     }
 }
 fn main_cli() {
-    let mut current_location = db_commands::DatabaseLocation::new();
-    let mut password: (secretbox::Key, bool) = (secretbox::gen_key(), false);
-    let mut main_metadata: metadata::Metadata = metadata::Metadata::create();
-    let mut structure_cache: HashMap<
-        [u8; blocks::CELL_IDENTIFIER_LENGTH as usize],
-        db_commands::Structure,
-    > = HashMap::new();
-    sodiumoxide::init().expect("Unable to initialize SoldiumMoxide");
+    let mut database: Option<Database> = None;
     let mut rl = rustyline::Editor::<()>::new();
+    let mut reader = Reader::new();
     loop {
         let user_input = {
-            let p = format!("{} >", current_location);
+            let p = if reader.is_pending() {
+                //Inside a heredoc
+                String::from("... ")
+            } else {
+                match database.as_ref() {
+                    Some(db) => format!("{} >", db.location()),
+                    None => String::from(" >"),
+                }
+            };
             let readline = rl.readline(&p);
             match readline {
                 Ok(line) => line,
                 Err(rustyline::error::ReadlineError::Interrupted)
-                | Err(rustyline::error::ReadlineError::Eof) => utils::exit(),
+                | Err(rustyline::error::ReadlineError::Eof) => msdb::utils::exit(),
                 _ => String::new(),
             }
         };
-        let user_command_unparsed = user_input.to_ascii_lowercase();
-        let user_command = user_command_unparsed.split_whitespace().next();
-        let mut parsed_command = {
-            let mut result = user_input.split_whitespace();
-            result.next();
-            result
+        let arguments = match reader.push_line(&user_input) {
+            Ok(Some(i)) => i,
+            Ok(None) => continue,
+            Err(e) => {
+                println!("Error: {}", e);
+                continue;
+            }
         };
+        let user_command = arguments.first().map(|i| i.to_ascii_lowercase());
+        let mut parsed_command = arguments.iter().skip(1).map(String::as_str);
         if let Some(command) = user_command {
-            match command {
+            match command.as_str() {
                 "create" => {
                     let directory = if let Some(k) = parsed_command.next() {
                         k.to_string()
@@ -166,22 +411,29 @@ fn main_cli() {
                     } else if path::Path::new(&directory).exists() {
                         println!("Directory {} already exists", directory);
                     } else {
-                        let password = rpassword::prompt_password_stdout("Password: ")
-                            .expect("Unable to read password using rpassword");
-                        {
-                            let mut password_comfirm = String::new();
-                            while password != password_comfirm {
-                                password_comfirm = rpassword::prompt_password_stdout(
-                                    "Confirm password: ",
-                                )
-                                .expect("Unable to read password confirmation using rpassword");
+                        let mut arguments: Vec<&str> = parsed_command.collect();
+                        let credential = take_kdf_option(&mut arguments).and_then(|kdf| {
+                            let credential = match parse_credential(&mut arguments.into_iter())? {
+                                Some(i) => i,
+                                None => Credential::from_env().unwrap_or_else(|| {
+                                    Credential::password(&prompt_new_password(true))
+                                }),
+                            };
+                            Ok((credential, kdf))
+                        });
+                        match credential.and_then(|(credential, kdf)| {
+                            Database::create_with_credential(&directory, &credential, kdf)
+                        }) {
+                            Ok(db) => {
+                                db.close();
+                                println!("Completed. Have a nice day.");
                             }
+                            Err(e) => println!("Error: {}", e),
                         }
-                        utils::new_database(&directory, &password, VERSION_NUMBER);
                     }
                 }
                 "decrypt" => {
-                    if password.1 {
+                    if database.is_some() {
                         println!("The database has already been unlocked.");
                     } else {
                         let try_database = if let Some(i) = parsed_command.next() {
@@ -194,44 +446,69 @@ fn main_cli() {
                             };
                             directory
                         };
-                        let try_passwd = {
-                            let password_raw: String;
-                            password_raw = match parsed_command.next() {
-                                Some(i) => i.to_string(),
-                                None => rpassword::prompt_password_stdout("Password: ").unwrap(),
-                            };
-                            let salt = {
-                                let salt_directory = format!("{}/salt", try_database);
-                                let salt_vec = binary_io::read_all(&salt_directory);
-                                pwhash::Salt::from_slice(&salt_vec[..]).unwrap()
-                            };
-                            blockencrypt::password_deriv(&password_raw, salt)
-                        };
-                        main_metadata =
-                            utils::select_database(&try_database, &try_passwd, VERSION_NUMBER);
-                        current_location.select_root(try_database);
-                        password = (try_passwd, true);
+                        match open_database(&try_database, parsed_command.collect()) {
+                            Ok(db) => database = Some(db),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    }
+                }
+                "passwd" => {
+                    if let Some(db) = database.as_mut() {
+                        let password = prompt_new_password(true);
+                        match db.change_password(&password) {
+                            Ok(()) => println!("Password changed."),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    } else {
+                        println!("You are not logged in.");
+                    }
+                }
+                "kdf" => {
+                    if let Some(db) = database.as_mut() {
+                        let result = parsed_command
+                            .next()
+                            .ok_or_else(|| {
+                                MsdbError::InvalidArgument(String::from("`kdf` requires a profile"))
+                            })
+                            .and_then(Kdf::from_profile)
+                            .and_then(|kdf| {
+                                db.retune(&unlock_credential(&mut parsed_command)?, kdf)
+                            });
+                        match result {
+                            Ok(()) => println!("Key slot {} re-tuned.", db.keyslot()),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    } else {
+                        println!("You are not logged in.");
+                    }
+                }
+                "keyslot" => {
+                    if let Some(db) = database.as_mut() {
+                        if let Err(e) = keyslot_command(db, parsed_command, true) {
+                            println!("Error: {}", e);
+                        }
+                    } else {
+                        println!("You are not logged in.");
+                    }
+                }
+                "convert" => {
+                    if let Err(e) = convert_command(parsed_command) {
+                        println!("Error: {}", e);
                     }
                 }
                 "logout" => {
-                    if password.1 {
-                        main_metadata.clear();
-                        password = (secretbox::gen_key(), false);
-                        current_location.logout();
+                    if let Some(db) = database.take() {
+                        db.close();
                     } else {
                         println!("You are not logged in.");
                     }
                 }
-                "exit" => utils::exit(),
+                "exit" => msdb::utils::exit(),
                 _ => {
-                    if password.1 {
-                        db_commands::run_commands(
-                            &user_input,
-                            &mut main_metadata,
-                            &mut current_location,
-                            &password.0,
-                            &mut structure_cache,
-                        );
+                    if let Some(db) = database.as_mut() {
+                        if let Err(e) = db.run_arguments(&arguments) {
+                            println!("Error: {}", e);
+                        }
                     } else {
                         println!("Unknown command {}", user_input)
                     }
@@ -242,53 +519,58 @@ fn main_cli() {
 }
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use msdb::{db_commands, storage, utils, Credential, Kdf, VERSION_NUMBER};
     use test::Bencher;
     #[bench]
     fn create_database(b: &mut Bencher) {
+        let mut storage = storage::create("createddb").unwrap();
         b.iter(|| {
-            utils::new_database("createddb", "password", VERSION_NUMBER);
+            utils::new_database(
+                storage.as_mut(),
+                &Credential::password("password"),
+                Kdf::default(),
+                VERSION_NUMBER,
+            )
+            .unwrap()
         });
         std::fs::remove_dir_all("createddb").unwrap();
     }
     #[bench]
     fn load_empty_database(b: &mut Bencher) {
-        utils::new_database("emptydb", "password", VERSION_NUMBER);
-        let try_passwd = {
-            let password_raw = "password";
-            let salt = {
-                let salt_vec = binary_io::read_all("emptydb/salt");
-                pwhash::Salt::from_slice(&salt_vec[..]).unwrap()
-            };
-            blockencrypt::password_deriv(&password_raw, salt)
-        };
+        let mut storage = storage::create("emptydb").unwrap();
+        let try_passwd = utils::new_database(
+            storage.as_mut(),
+            &Credential::password("password"),
+            Kdf::default(),
+            VERSION_NUMBER,
+        )
+        .unwrap();
         b.iter(|| {
-            utils::select_database("emptydb", &try_passwd, VERSION_NUMBER);
+            utils::select_database(storage.as_mut(), &try_passwd, VERSION_NUMBER).unwrap();
         });
         std::fs::remove_dir_all("emptydb").unwrap();
     }
     #[bench]
     fn create_empty_struct(b: &mut Bencher) {
-        utils::new_database("structdb", "password", VERSION_NUMBER);
-        let try_passwd = {
-            let password_raw = "password";
-            let salt = {
-                let salt_vec = binary_io::read_all("structdb/salt");
-                pwhash::Salt::from_slice(&salt_vec[..]).unwrap()
-            };
-            blockencrypt::password_deriv(&password_raw, salt)
-        };
-        let mut main_metadata = utils::select_database("structdb", &try_passwd, VERSION_NUMBER);
-        let mut current_location = db_commands::DatabaseLocation::new();
-        current_location.select_root("structdb".to_string());
+        let mut storage = storage::create("structdb").unwrap();
+        let try_passwd = utils::new_database(
+            storage.as_mut(),
+            &Credential::password("password"),
+            Kdf::default(),
+            VERSION_NUMBER,
+        )
+        .unwrap();
+        let mut main_metadata =
+            utils::select_database(storage.as_mut(), &try_passwd, VERSION_NUMBER).unwrap();
         b.iter(|| {
             db_commands::create_structure(
                 "testname",
                 &try_passwd,
+                storage.as_mut(),
                 &mut main_metadata,
-                &mut current_location,
                 None,
             )
+            .unwrap()
         });
         std::fs::remove_dir_all("structdb").unwrap();
     }
@@ -1,4 +1,17 @@
+//! Key-value pairs kept in metadata blocks and key slots
+//!
+//! Metadata written before format version 1 is `key=value;` text, which cannot hold `=`, `;` or
+//! `$`. It is still read. Metadata blocks are written in current format at next `sync`, and key
+//! slots when they change.
+use super::error::MsdbError;
 use std::collections::HashMap;
+use std::convert::TryInto;
+/// First byte of metadata in current format. Legacy metadata is text, which never starts with it.
+const MAGIC: u8 = 0;
+const FORMAT_VERSION: u8 = 1;
+const ATTRIBUTE_TAG: u8 = 1;
+const SUB_DATA_TAG: u8 = 2;
+#[derive(Clone)]
 pub struct Metadata {
     has_modified: bool,
     attribute: HashMap<String, String>,
@@ -18,43 +31,32 @@ impl Metadata {
         self.has_modified = false;
     }
     pub fn into_vec(self) -> Vec<u8> {
-        let mut index: Vec<u8> = Vec::new();
-        for (key, val) in self.attribute {
-            index.append(&mut key.into_bytes());
-            index.append(&mut String::from("=").as_bytes().to_vec());
-            index.append(&mut val.into_bytes());
-            index.append(&mut String::from(";").as_bytes().to_vec());
-        }
-        index.append(&mut String::from("$").as_bytes().to_vec());
-        for (key, val) in self.sub_data {
-            index.append(&mut key.into_bytes());
-            index.append(&mut String::from("=").as_bytes().to_vec());
-            index.append(&mut val.into_bytes());
-            index.append(&mut String::from(";").as_bytes().to_vec());
-        }
-        index
+        self.to_vec()
     }
-    pub fn from_vec(metadata_block: Vec<u8>) -> Self {
+    pub fn from_vec(metadata_block: Vec<u8>) -> Result<Self, MsdbError> {
         let mut result = Self::create();
-        result.import(metadata_block);
-        result
+        result.import(metadata_block)?;
+        Ok(result)
     }
+    /// Encode in current format: `MAGIC`, `FORMAT_VERSION`, then an entry for each attribute and
+    /// sub data, in order. Each entry is `tag`, `key length`, `key`, `value length` and `value`,
+    /// with lengths as u32 (little endian).
     pub fn to_vec(&self) -> Vec<u8> {
-        let mut index: Vec<u8> = Vec::new();
-        for (key, val) in &self.attribute {
-            index.append(&mut key.clone().into_bytes());
-            index.append(&mut String::from("=").as_bytes().to_vec());
-            index.append(&mut val.clone().into_bytes());
-            index.append(&mut String::from(";").as_bytes().to_vec());
-        }
-        index.append(&mut String::from("$").as_bytes().to_vec());
-        for (key, val) in &self.sub_data {
-            index.append(&mut key.clone().into_bytes());
-            index.append(&mut String::from("=").as_bytes().to_vec());
-            index.append(&mut val.clone().into_bytes());
-            index.append(&mut String::from(";").as_bytes().to_vec());
-        }
-        index
+        let mut result = vec![MAGIC, FORMAT_VERSION];
+        let mut write_entries = |tag: u8, entries: &HashMap<String, String>| {
+            let mut entries: Vec<_> = entries.iter().collect();
+            entries.sort();
+            for (key, value) in entries {
+                result.push(tag);
+                for i in [key, value] {
+                    result.extend_from_slice(&(i.len() as u32).to_le_bytes());
+                    result.extend_from_slice(i.as_bytes());
+                }
+            }
+        };
+        write_entries(ATTRIBUTE_TAG, &self.attribute);
+        write_entries(SUB_DATA_TAG, &self.sub_data);
+        result
     }
     pub fn new_attribute(&mut self, lhs: &str, rhs: &str) {
         self.attribute
@@ -64,6 +66,16 @@ impl Metadata {
             self.has_modified = true;
         }
     }
+    /// Create or overwrite an attribute
+    pub fn set_attribute(&mut self, lhs: &str, rhs: &str) {
+        self.attribute.insert(lhs.to_string(), rhs.to_string());
+        self.has_modified = true;
+    }
+    pub fn delete_attribute(&mut self, lhs: &str) {
+        if self.attribute.remove(lhs).is_some() {
+            self.has_modified = true;
+        }
+    }
     pub fn new_sub_data(&mut self, lhs: &str, rhs: &str) {
         self.sub_data
             .entry(lhs.to_string())
@@ -75,38 +87,68 @@ impl Metadata {
     pub fn delete_sub_data(&mut self, lhs: &str) {
         self.sub_data.remove(lhs);
     }
-    pub fn import(&mut self, metadata_block: Vec<u8>) {
-        let metadata_block =
-            String::from_utf8(metadata_block).expect("Unable to convert Metadata Vector to String");
-        println!("{}", metadata_block);
+    /// Read entries from an encoded metadata, in current or legacy format
+    pub fn import(&mut self, metadata_block: Vec<u8>) -> Result<(), MsdbError> {
+        match metadata_block.first() {
+            Some(&MAGIC) => self.import_entries(&metadata_block[1..])?,
+            _ => self.import_legacy(metadata_block)?,
+        }
+        if !self.has_modified {
+            self.has_modified = true;
+        }
+        Ok(())
+    }
+    fn import_entries(&mut self, metadata_block: &[u8]) -> Result<(), MsdbError> {
+        let corrupt = |reason: &str| MsdbError::CorruptBlock(format!("metadata: {}", reason));
+        let (&version, mut rest) = metadata_block
+            .split_first()
+            .ok_or_else(|| corrupt("missing format version"))?;
+        if version != FORMAT_VERSION {
+            return Err(corrupt(&format!("unsupported format version {}", version)));
+        }
+        while let Some((&tag, remaining)) = rest.split_first() {
+            rest = remaining;
+            let key = take_string(&mut rest)?;
+            let value = take_string(&mut rest)?;
+            match tag {
+                ATTRIBUTE_TAG => self.attribute.insert(key, value),
+                SUB_DATA_TAG => self.sub_data.insert(key, value),
+                i => return Err(corrupt(&format!("unknown tag {}", i))),
+            };
+        }
+        Ok(())
+    }
+    /// `key=value;` pairs of attributes, then `$` and pairs of sub data
+    fn import_legacy(&mut self, metadata_block: Vec<u8>) -> Result<(), MsdbError> {
+        let corrupt = |reason: &str| MsdbError::CorruptBlock(format!("metadata: {}", reason));
+        let metadata_block = String::from_utf8(metadata_block)
+            .map_err(|_| corrupt("unable to convert Metadata Vector to String"))?;
         let mut attribute_and_data = metadata_block.split('$');
         let attribute_iter = attribute_and_data
             .next()
-            .expect("Unable to load attribute from import")
+            .ok_or_else(|| corrupt("unable to load attribute from import"))?
             .split(';');
         let data_iter = attribute_and_data
             .next()
-            .expect("Unable to load data from import")
+            .ok_or_else(|| corrupt("unable to load data from import"))?
             .split(';');
         for current_token in attribute_iter {
-            if current_token != "" {
-                let mut token_parse = current_token.split('=');
-                let lhs = token_parse.next().unwrap();
-                let rhs = token_parse.next().unwrap();
+            if !current_token.is_empty() {
+                let (lhs, rhs) = current_token
+                    .split_once('=')
+                    .ok_or_else(|| corrupt("attribute without value"))?;
                 self.attribute.insert(lhs.to_string(), rhs.to_string());
             }
         }
         for current_token in data_iter {
-            if current_token != "" {
-                let mut token_parse = current_token.split('=');
-                let lhs = token_parse.next().unwrap();
-                let rhs = token_parse.next().unwrap();
+            if !current_token.is_empty() {
+                let (lhs, rhs) = current_token
+                    .split_once('=')
+                    .ok_or_else(|| corrupt("data without value"))?;
                 self.sub_data.insert(lhs.to_string(), rhs.to_string());
             }
         }
-        if !self.has_modified {
-            self.has_modified = true;
-        }
+        Ok(())
     }
     pub fn clear(&mut self) {
         self.attribute = HashMap::new();
@@ -123,6 +165,22 @@ impl Metadata {
         }
     }
 }
+/// Length-prefixed string at the start of rest, which is advanced past it
+fn take_string(rest: &mut &[u8]) -> Result<String, MsdbError> {
+    let truncated = || MsdbError::CorruptBlock(String::from("metadata: truncated entry"));
+    if rest.len() < 4 {
+        return Err(truncated());
+    }
+    let (len, remaining) = rest.split_at(4);
+    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    if remaining.len() < len {
+        return Err(truncated());
+    }
+    let (content, remaining) = remaining.split_at(len);
+    *rest = remaining;
+    String::from_utf8(content.to_vec())
+        .map_err(|_| MsdbError::CorruptBlock(String::from("metadata: entry is not valid UTF-8")))
+}
 impl std::fmt::Display for Metadata {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let r1 = "Attribute(s):\n";
@@ -141,3 +199,31 @@ impl std::fmt::Display for Metadata {
         write!(f, "{}{}{}{}", r1, r2, r3, r4)
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::Metadata;
+    #[test]
+    fn round_trip() {
+        let mut metadata = Metadata::create();
+        metadata.new_attribute("note", "a=b;c$d");
+        metadata.new_attribute("", "");
+        metadata.new_sub_data("struct;$=", "0011223344556677");
+        let decoded = Metadata::from_vec(metadata.to_vec()).unwrap();
+        assert_eq!(decoded.attribute(), metadata.attribute());
+        assert_eq!(decoded.sub_data(), metadata.sub_data());
+        let mut truncated = metadata.to_vec();
+        truncated.pop();
+        assert!(Metadata::from_vec(truncated).is_err());
+    }
+    #[test]
+    fn legacy() {
+        let decoded =
+            Metadata::from_vec(b"ver=4;epoch=2;$people=5e823b172250db1e;".to_vec()).unwrap();
+        assert_eq!(decoded.attribute().get("epoch").unwrap(), "2");
+        assert_eq!(
+            decoded.sub_data().get("people").unwrap(),
+            "5e823b172250db1e"
+        );
+        assert!(Metadata::from_vec(b"ver;$".to_vec()).is_err());
+    }
+}
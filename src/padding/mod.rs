@@ -0,0 +1,150 @@
+//! Length-hiding padding of blocks
+//!
+//! Blocks are padded before encryption (after compression), so the size of their ciphertext only
+//! tells which size class their content falls into. Padding is a `0x80` byte followed by zeros
+//! (ISO/IEC 7816-4), so it is stripped without knowing the policy it was added with.
+//!
+//! The policy of a database is kept in main metadata attribute `padding`. It also pads the last
+//! chunk of [streams](crate::stream), whose other chunks all have the same size.
+use super::error::MsdbError;
+use super::metadata::Metadata;
+pub const ATTRIBUTE: &str = "padding";
+const BUCKETS_PREFIX: &str = "buckets:";
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Padding {
+    None,
+    /// Smallest of the sizes which fits, or else a multiple of the largest one. Sizes are sorted.
+    Buckets(Vec<usize>),
+    /// Next power of two, at most doubling the size
+    PowerOfTwo,
+    /// PADMÉ: at most 12% larger, leaking O(log log L) bits of a length L
+    Padme,
+}
+impl Padding {
+    /// `none`, `pow2`, `padme` or `buckets:SIZE,SIZE,...`
+    pub fn from_name(name: &str) -> Result<Self, MsdbError> {
+        let invalid = || {
+            MsdbError::InvalidArgument(format!(
+                "Padding {} not reconized. Available: none, pow2, padme, buckets:SIZE,SIZE,...",
+                name
+            ))
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "pow2" => Ok(Self::PowerOfTwo),
+            "padme" => Ok(Self::Padme),
+            i => {
+                let mut sizes = i
+                    .strip_prefix(BUCKETS_PREFIX)
+                    .ok_or_else(invalid)?
+                    .split(',')
+                    .map(|j| j.parse().ok().filter(|&k| k > 0))
+                    .collect::<Option<Vec<usize>>>()
+                    .ok_or_else(invalid)?;
+                sizes.sort_unstable();
+                sizes.dedup();
+                Ok(Self::Buckets(sizes))
+            }
+        }
+    }
+    pub fn from_metadata(metadata: &Metadata) -> Result<Self, MsdbError> {
+        metadata
+            .attribute()
+            .get(ATTRIBUTE)
+            .map_or(Ok(Self::None), |i| Self::from_name(i))
+    }
+    pub fn set(&self, metadata: &mut Metadata) {
+        if *self == Self::None {
+            metadata.delete_attribute(ATTRIBUTE);
+        } else {
+            metadata.set_attribute(ATTRIBUTE, &self.to_string());
+        }
+    }
+    /// Size a block of `len` bytes (including the `0x80` byte) is padded to
+    pub fn padded_len(&self, len: usize) -> usize {
+        match self {
+            Self::None => len,
+            Self::Buckets(sizes) => {
+                let largest = *sizes.last().unwrap();
+                sizes
+                    .iter()
+                    .copied()
+                    .find(|&i| i >= len)
+                    .unwrap_or_else(|| len.div_ceil(largest) * largest)
+            }
+            Self::PowerOfTwo => len.next_power_of_two(),
+            Self::Padme if len < 2 => len,
+            Self::Padme => {
+                //Keep the top log2(log2(len)) + 1 bits of len, rounding up the others
+                let exponent = usize::BITS - 1 - len.leading_zeros();
+                let size_bits = u32::BITS - exponent.leading_zeros();
+                let mask = (1 << (exponent - size_bits)) - 1;
+                (len + mask) & !mask
+            }
+        }
+    }
+    pub fn pad(&self, data: &[u8]) -> Vec<u8> {
+        let mut result = Vec::with_capacity(self.padded_len(data.len() + 1));
+        result.extend_from_slice(data);
+        result.push(0x80);
+        result.resize(self.padded_len(data.len() + 1), 0);
+        result
+    }
+}
+impl std::fmt::Display for Padding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Buckets(sizes) => write!(
+                f,
+                "{}{}",
+                BUCKETS_PREFIX,
+                sizes
+                    .iter()
+                    .map(usize::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            Self::PowerOfTwo => write!(f, "pow2"),
+            Self::Padme => write!(f, "padme"),
+        }
+    }
+}
+/// Strip padding added by [`Padding::pad`]
+pub fn unpad(mut data: Vec<u8>) -> Result<Vec<u8>, MsdbError> {
+    let len = data
+        .iter()
+        .rposition(|&i| i != 0)
+        .filter(|&i| data[i] == 0x80)
+        .ok_or_else(|| MsdbError::CorruptBlock(String::from("Invalid padding")))?;
+    data.truncate(len);
+    Ok(data)
+}
+#[cfg(test)]
+mod tests {
+    use super::{unpad, Padding};
+    #[test]
+    fn padded_lengths() {
+        let buckets = Padding::from_name("buckets:4096,512").unwrap();
+        assert_eq!(buckets.to_string(), "buckets:512,4096");
+        assert_eq!(buckets.padded_len(1), 512);
+        assert_eq!(buckets.padded_len(513), 4096);
+        assert_eq!(buckets.padded_len(4097), 8192);
+        assert_eq!(Padding::PowerOfTwo.padded_len(1025), 2048);
+        assert_eq!(Padding::Padme.padded_len(9), 10);
+        assert_eq!(Padding::Padme.padded_len(1000), 1024);
+        assert_eq!(Padding::Padme.padded_len(1025), 1088);
+        for len in 1..5000 {
+            let padded = Padding::Padme.padded_len(len);
+            assert!(padded >= len && padded <= len + len / 8);
+        }
+        assert!(Padding::from_name("buckets:0").is_err());
+        assert!(Padding::from_name("buckets:").is_err());
+        for data in [&b""[..], b"data", b"data\x80\x00"] {
+            let padded = Padding::Padme.pad(data);
+            assert_eq!(unpad(padded).unwrap(), data);
+        }
+        assert_eq!(buckets.pad(b"data").len(), 512);
+        assert!(unpad(vec![0; 16]).is_err());
+    }
+}
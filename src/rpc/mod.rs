@@ -0,0 +1,714 @@
+//! JSON-RPC 2.0 server
+//!
+//! Requests are newline-delimited JSON objects (or batches). Every connection owns one session,
+//! which may unlock one database with its own location.
+//!
+//! Clients are not authenticated, so they are only given files inside the served root directory:
+//! databases, keyfiles and trust files are relative paths under it, and blob content is sent
+//! inline.
+use super::blocks::{Cell, LinkTarget, LinkType};
+use super::trust::{RollbackPolicy, TrustFile};
+use super::utils;
+use super::{Credential, Database, Kdf, MsdbError};
+use serde_json::{json, Map, Value};
+use std::convert::TryInto;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener};
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const DATABASE_ERROR: i64 = -32000;
+struct RpcError {
+    code: i64,
+    message: String,
+}
+impl RpcError {
+    fn new(code: i64, message: &str) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+    fn invalid_params(message: &str) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+}
+impl From<MsdbError> for RpcError {
+    fn from(error: MsdbError) -> Self {
+        Self::new(DATABASE_ERROR, &error.to_string())
+    }
+}
+/// A client session, holding at most one unlocked database
+pub struct Session {
+    database: Option<Database>,
+    /// Canonical path of the directory holding every file a client may name
+    root: PathBuf,
+}
+impl Session {
+    /// Create a session confined to directory `root`
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        Ok(Self {
+            database: None,
+            root: root.as_ref().canonicalize()?,
+        })
+    }
+    /// Handle one line of request. Returns `None` if nothing should be replied (notifications).
+    pub fn handle(&mut self, request: &str) -> Option<String> {
+        let response = match serde_json::from_str::<Value>(request) {
+            Err(_) => Some(error_response(
+                Value::Null,
+                RpcError::new(PARSE_ERROR, "Parse error"),
+            )),
+            Ok(Value::Array(batch)) => {
+                if batch.is_empty() {
+                    Some(error_response(
+                        Value::Null,
+                        RpcError::new(INVALID_REQUEST, "Invalid Request"),
+                    ))
+                } else {
+                    let responses: Vec<Value> = batch
+                        .into_iter()
+                        .filter_map(|i| self.handle_value(i))
+                        .collect();
+                    if responses.is_empty() {
+                        None
+                    } else {
+                        Some(Value::Array(responses))
+                    }
+                }
+            }
+            Ok(request) => self.handle_value(request),
+        };
+        response.map(|i| i.to_string())
+    }
+    fn handle_value(&mut self, request: Value) -> Option<Value> {
+        let mut request = match request {
+            Value::Object(i) => i,
+            _ => {
+                return Some(error_response(
+                    Value::Null,
+                    RpcError::new(INVALID_REQUEST, "Invalid Request"),
+                ))
+            }
+        };
+        let id = request.remove("id");
+        let method = match (request.remove("jsonrpc"), request.remove("method")) {
+            (Some(Value::String(version)), Some(Value::String(method))) if version == "2.0" => {
+                method
+            }
+            _ => {
+                return Some(error_response(
+                    id.unwrap_or(Value::Null),
+                    RpcError::new(INVALID_REQUEST, "Invalid Request"),
+                ))
+            }
+        };
+        let params = match request.remove("params") {
+            None => Map::new(),
+            Some(Value::Object(i)) => i,
+            Some(_) => {
+                return id.map(|id| {
+                    error_response(
+                        id,
+                        RpcError::invalid_params("params must be an object of named parameters"),
+                    )
+                })
+            }
+        };
+        let result = self.call(&method, &params);
+        id.map(|id| match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "result": result, "id": id}),
+            Err(e) => error_response(id, e),
+        })
+    }
+    fn call(&mut self, method: &str, params: &Map<String, Value>) -> Result<Value, RpcError> {
+        match method {
+            "create" => {
+                if self.database.is_some() {
+                    return Err(RpcError::new(
+                        DATABASE_ERROR,
+                        "The database has already been unlocked.",
+                    ));
+                }
+                let database = Database::create_with_credential(
+                    &self.path_param(params, "name")?,
+                    &self.credential_param(params)?,
+                    kdf_param(params)?,
+                )?;
+                self.database = Some(database);
+                Ok(Value::Null)
+            }
+            "decrypt" => {
+                if self.database.is_some() {
+                    return Err(RpcError::new(
+                        DATABASE_ERROR,
+                        "The database has already been unlocked.",
+                    ));
+                }
+                let mut database = Database::open_with_credential(
+                    &self.path_param(params, "name")?,
+                    &self.credential_param(params)?,
+                )?;
+                if let Some(i) = self.trust_param(params)? {
+                    database.set_trust_file(i)?;
+                }
+                let needs_upgrade = database.needs_upgrade();
+                self.database = Some(database);
+                Ok(json!({ "needs_upgrade": needs_upgrade }))
+            }
+            "convert" => Ok(json!(utils::convert_database(
+                &self.path_param(params, "source")?,
+                &self.path_param(params, "destination")?,
+                &self.credential_param(params)?,
+            )?)),
+            "logout" => match self.database.take() {
+                Some(db) => {
+                    db.close();
+                    Ok(Value::Null)
+                }
+                None => Err(RpcError::new(DATABASE_ERROR, "You are not logged in.")),
+            },
+            "passwd" => {
+                let credential = self.credential_param(params)?;
+                self.database()?.change_credential(&credential)?;
+                Ok(Value::Null)
+            }
+            "retune" => {
+                let credential = self.credential_param(params)?;
+                self.database()?
+                    .retune(&credential, Kdf::from_profile(param(params, "kdf")?)?)?;
+                Ok(Value::Null)
+            }
+            "keyslot_add" => {
+                let credential = self.credential_param(params)?;
+                Ok(json!(self.database()?.add_keyslot(
+                    param(params, "label")?,
+                    &credential,
+                    kdf_param(params)?
+                )?))
+            }
+            _ => call_database(self.database()?, method, params),
+        }
+    }
+    fn database(&mut self) -> Result<&mut Database, RpcError> {
+        self.database
+            .as_mut()
+            .ok_or_else(|| RpcError::new(DATABASE_ERROR, "Database unavailable"))
+    }
+    /// A path relative to root, refused if it leaves root (through `..` or a symbolic link)
+    fn path_param(&self, params: &Map<String, Value>, name: &str) -> Result<String, RpcError> {
+        let relative = Path::new(param(params, name)?);
+        let outside = || {
+            RpcError::invalid_params(&format!(
+                "`{}` must be a path inside the served directory",
+                name
+            ))
+        };
+        if !relative
+            .components()
+            .all(|i| matches!(i, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(outside());
+        }
+        let path = self.root.join(relative);
+        //Files may not exist yet, so the deepest existing ancestor is checked
+        let mut existing = path.as_path();
+        while !existing.exists() {
+            existing = existing.parent().ok_or_else(outside)?;
+        }
+        match existing.canonicalize() {
+            Ok(i) if i.starts_with(&self.root) => {}
+            _ => return Err(outside()),
+        }
+        path.to_str().map(String::from).ok_or_else(outside)
+    }
+    /// `password`, or `keyfile` (path of a raw 32-byte key inside root)
+    fn credential_param(&self, params: &Map<String, Value>) -> Result<Credential, RpcError> {
+        if params.contains_key("keyfile") {
+            Ok(Credential::from_keyfile(
+                &self.path_param(params, "keyfile")?,
+            )?)
+        } else {
+            Ok(Credential::password(param(params, "password")?))
+        }
+    }
+    /// Optional `trust_file` (path inside root), refused on rollback if `refuse_rollback` is true
+    fn trust_param(&self, params: &Map<String, Value>) -> Result<Option<TrustFile>, RpcError> {
+        if !params.contains_key("trust_file") {
+            return Ok(None);
+        }
+        let policy = match params.get("refuse_rollback") {
+            None | Some(Value::Bool(false)) => RollbackPolicy::Warn,
+            Some(Value::Bool(true)) => RollbackPolicy::Refuse,
+            Some(_) => {
+                return Err(RpcError::invalid_params(
+                    "`refuse_rollback` must be a boolean",
+                ))
+            }
+        };
+        Ok(Some(TrustFile::new(
+            &self.path_param(params, "trust_file")?,
+            policy,
+        )))
+    }
+}
+fn call_database(
+    db: &mut Database,
+    method: &str,
+    params: &Map<String, Value>,
+) -> Result<Value, RpcError> {
+    let in_structure = db.location().current_structure_identifier().is_some();
+    let in_object = db.location().current_object_identifier().is_some();
+    match method {
+        "pwd" => Ok(json!(db.location().to_string())),
+        "keyslot_list" => Ok(Value::Array(
+            db.keyslots()?
+                .iter()
+                .map(|(number, slot)| {
+                    json!({
+                        "slot": number,
+                        "label": slot.label(),
+                        "kdf": slot.kdf().map_or_else(|| String::from("keyfile"), |i| i.to_string()),
+                        "current": *number == db.keyslot(),
+                    })
+                })
+                .collect(),
+        )),
+        "keyslot_label" => {
+            db.label_keyslot(slot_param(params)?, param(params, "label")?)?;
+            Ok(Value::Null)
+        }
+        "keyslot_revoke" => {
+            db.revoke_keyslot(slot_param(params)?)?;
+            Ok(Value::Null)
+        }
+        "create_structure" => {
+            db.create_structure(param(params, "name")?)?;
+            Ok(Value::Null)
+        }
+        "select" => {
+            let name = param(params, "name")?;
+            if in_object {
+                return Err(RpcError::new(
+                    DATABASE_ERROR,
+                    "Cannot select inside a object",
+                ));
+            } else if in_structure {
+                db.select_object(name)?;
+            } else {
+                db.select_structure(name)?;
+            }
+            Ok(Value::Null)
+        }
+        "leave" => {
+            db.leave();
+            Ok(Value::Null)
+        }
+        "new_object" => {
+            db.create_object(param(params, "name")?)?;
+            Ok(Value::Null)
+        }
+        "new_cell" => {
+            let field = param(params, "field")?;
+            let cell_type = param(params, "type")?;
+            if cell_type.eq_ignore_ascii_case("blob") {
+                db.write_blob(field, &mut blob_param(params)?.as_slice())?;
+            } else {
+                db.create_cell(field, cell_type, param(params, "content")?)?;
+            }
+            Ok(Value::Null)
+        }
+        "alter" => {
+            let cell_type = match params.get("type") {
+                None | Some(Value::Null) => None,
+                Some(Value::String(i)) => Some(i.as_str()),
+                Some(_) => return Err(RpcError::invalid_params("`type` must be a string")),
+            };
+            let field = param(params, "field")?;
+            let is_blob = match cell_type {
+                Some(i) => i.eq_ignore_ascii_case("blob"),
+                None => db.cell(field)?.map(Cell::type_name) == Some("blob"),
+            };
+            if is_blob {
+                if db.cell(field)?.is_none() {
+                    return Err(MsdbError::NotFound(format!("Cell {}", field)).into());
+                }
+                db.write_blob(field, &mut blob_param(params)?.as_slice())?;
+            } else {
+                db.alter_cell(field, cell_type, param(params, "content")?)?;
+            }
+            Ok(Value::Null)
+        }
+        "load" => {
+            let field = match params.get("field") {
+                None | Some(Value::Null) => None,
+                Some(Value::String(i)) => Some(i.as_str()),
+                Some(_) => return Err(RpcError::invalid_params("`field` must be a string")),
+            };
+            db.load(field)?;
+            Ok(Value::Null)
+        }
+        "read" => {
+            let field = param(params, "field")?;
+            match db.cell(field)? {
+                Some(Cell::Stream(..) | Cell::Shared(..)) => {
+                    let mut content = Vec::new();
+                    db.read_blob(field, &mut content)?;
+                    Ok(json!({"field": field, "type": "blob", "value": hex::encode(content)}))
+                }
+                Some(cell) => Ok(cell_to_json(field, cell)),
+                None => Err(MsdbError::NotFound(format!("Cell {}", field)).into()),
+            }
+        }
+        "ls" => {
+            if in_object {
+                Ok(Value::Array(
+                    db.cells()?
+                        .into_iter()
+                        .map(|(field, cell)| cell_to_json(field, cell))
+                        .collect(),
+                ))
+            } else if in_structure {
+                Ok(json!(db.objects()?))
+            } else {
+                Ok(json!(db.structures()))
+            }
+        }
+        "del" => {
+            let name = param(params, "name")?;
+            if in_object {
+                db.delete_cell(name)?;
+            } else if in_structure {
+                db.delete_object(name)?;
+            } else {
+                db.delete_structure(name)?;
+            }
+            Ok(Value::Null)
+        }
+        "vacuum" => {
+            let report = db.vacuum()?;
+            Ok(json!({
+                "cells": report.cells_dropped,
+                "fields": report.fields_dropped,
+                "files_before": report.files_before,
+                "bytes_before": report.bytes_before,
+                "files_after": report.files_after,
+                "bytes_after": report.bytes_after,
+            }))
+        }
+        "wipe" => {
+            if !in_structure || in_object {
+                return Err(RpcError::new(
+                    DATABASE_ERROR,
+                    "Objects can only be wiped inside a structure",
+                ));
+            }
+            let (cells, bytes) = db.wipe_object(param(params, "name")?)?;
+            Ok(json!({ "cells": cells, "bytes": bytes }))
+        }
+        "sync" => {
+            db.sync()?;
+            Ok(Value::Null)
+        }
+        "upgrade" => Ok(json!(db.upgrade()?)),
+        "getprop" => Ok(json!(db.properties()?)),
+        "setprop" => {
+            db.set_property(param(params, "name")?, param(params, "value")?)?;
+            Ok(Value::Null)
+        }
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, "Method not found")),
+    }
+}
+fn param<'a>(params: &'a Map<String, Value>, name: &str) -> Result<&'a str, RpcError> {
+    match params.get(name) {
+        Some(Value::String(i)) => Ok(i),
+        Some(_) => Err(RpcError::invalid_params(&format!(
+            "`{}` must be a string",
+            name
+        ))),
+        None => Err(RpcError::invalid_params(&format!("`{}` is required", name))),
+    }
+}
+/// `content` of a blob, hex encoded or base64 if `encoding` is `base64`. Never a path, since the
+/// server would read it from its own file system.
+fn blob_param(params: &Map<String, Value>) -> Result<Vec<u8>, RpcError> {
+    let content = param(params, "content")?;
+    let encoding = match params.get("encoding") {
+        None | Some(Value::Null) => "hex",
+        Some(Value::String(i)) => i.as_str(),
+        Some(_) => return Err(RpcError::invalid_params("`encoding` must be a string")),
+    };
+    match encoding {
+        "hex" => hex::decode(content).ok(),
+        "base64" => utils::base64_decode(content),
+        _ => {
+            return Err(RpcError::invalid_params(
+                "`encoding` must be `hex` or `base64`",
+            ))
+        }
+    }
+    .ok_or_else(|| {
+        RpcError::invalid_params(&format!("Blob `content` must be {} encoded", encoding))
+    })
+}
+/// Optional `kdf` profile, see [`Kdf::from_profile`]
+fn kdf_param(params: &Map<String, Value>) -> Result<Kdf, RpcError> {
+    if params.contains_key("kdf") {
+        Ok(Kdf::from_profile(param(params, "kdf")?)?)
+    } else {
+        Ok(Kdf::default())
+    }
+}
+fn slot_param(params: &Map<String, Value>) -> Result<u32, RpcError> {
+    params
+        .get("slot")
+        .and_then(Value::as_u64)
+        .and_then(|i| i.try_into().ok())
+        .ok_or_else(|| RpcError::invalid_params("`slot` must be a slot number"))
+}
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": {"code": error.code, "message": error.message},
+        "id": id,
+    })
+}
+/// Convert a cell into `{"field", "type", "value"}`. Blobs are hex encoded; streamed and shared
+/// blobs, which are not in memory, have no value.
+fn cell_to_json(field: &str, cell: &Cell) -> Value {
+    let (cell_type, value) = match cell {
+        Cell::Literal(content, _) => ("literal", json!(content)),
+        Cell::Blob(content, _) => ("blob", json!(hex::encode(content))),
+        Cell::Stream(..) | Cell::Shared(..) => ("blob", Value::Null),
+        Cell::Link(link_type, target, _) => {
+            let cell_type = match link_type {
+                LinkType::Forward => "link",
+                LinkType::Reverse => "revlink",
+            };
+            let target = match target {
+                LinkTarget::SameBlock(object) => json!({ "object": hex::encode(object) }),
+                LinkTarget::AnotherField(field, object) => json!({
+                    "field": hex::encode(field),
+                    "object": hex::encode(object),
+                }),
+                LinkTarget::AnotherStruct(structure, field, object) => json!({
+                    "structure": hex::encode(structure),
+                    "field": hex::encode(field),
+                    "object": hex::encode(object),
+                }),
+            };
+            (cell_type, target)
+        }
+        Cell::LiteralIncomplete(content, _) | Cell::BlobIncomplete(content, _) => {
+            ("incomplete", json!(hex::encode(content)))
+        }
+        Cell::Integer(content, _) => ("int", json!(content)),
+        Cell::Float(content, _) => ("float", json!(content)),
+        Cell::Boolean(content, _) => ("bool", json!(content)),
+        Cell::Timestamp(..) | Cell::Uuid(..) => (cell.type_name(), json!(cell.typed_value())),
+    };
+    json!({"field": field, "type": cell_type, "value": value})
+}
+fn handle_connection<S: Read + Write>(stream: S, root: &Path) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut session = Session::new(root)?;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(mut response) = session.handle(&line) {
+            response.push('\n');
+            reader.get_mut().write_all(response.as_bytes())?;
+            reader.get_mut().flush()?;
+        }
+    }
+}
+/// Listen on a localhost TCP port, serving files inside `root`. Each connection is served in its
+/// own thread.
+pub fn serve_tcp(port: u16, root: &str) -> io::Result<()> {
+    let root = Path::new(root).canonicalize()?;
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    println!("Listening on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let root = root.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &root) {
+                println!("Connection error: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+/// Listen on a Unix domain socket, serving files inside `root`. Only the owner may connect to the
+/// socket. Each connection is served in its own thread.
+#[cfg(unix)]
+pub fn serve_unix(path: &str, root: &str) -> io::Result<()> {
+    use std::fs::{self, DirBuilder, Permissions};
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    let root = Path::new(root).canonicalize()?;
+    if Path::new(path).exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", path),
+        ));
+    }
+    //Bound inside a private directory, so nobody can connect before permissions are set
+    let private_directory = format!("{}.tmp", path);
+    DirBuilder::new().mode(0o700).create(&private_directory)?;
+    let private_path = format!("{}/socket", private_directory);
+    let listener = std::os::unix::net::UnixListener::bind(&private_path).and_then(|i| {
+        fs::set_permissions(&private_path, Permissions::from_mode(0o600))?;
+        fs::rename(&private_path, path)?;
+        Ok(i)
+    });
+    let _ = fs::remove_file(&private_path);
+    fs::remove_dir(&private_directory)?;
+    let listener = listener?;
+    println!("Listening on {}", path);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let root = root.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &root) {
+                println!("Connection error: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use super::Session;
+    use serde_json::{json, Value};
+    fn call(session: &mut Session, request: &str) -> Value {
+        serde_json::from_str(&session.handle(request).unwrap()).unwrap()
+    }
+    #[test]
+    fn protocol_errors() {
+        let mut session = Session::new(".").unwrap();
+        assert_eq!(call(&mut session, "{")["error"]["code"], json!(-32700));
+        assert_eq!(call(&mut session, "[]")["error"]["code"], json!(-32600));
+        assert_eq!(
+            call(&mut session, r#"{"jsonrpc":"2.0","method":"ls","id":1}"#)["error"]["code"],
+            json!(-32000)
+        );
+        assert_eq!(
+            call(
+                &mut session,
+                r#"{"jsonrpc":"2.0","method":"create","id":2}"#
+            )["error"]["code"],
+            json!(-32602)
+        );
+        assert!(session
+            .handle(r#"{"jsonrpc":"2.0","method":"logout"}"#)
+            .is_none());
+    }
+    #[test]
+    fn session_roundtrip() {
+        let mut session = Session::new(".").unwrap();
+        let requests = [
+            r#"{"jsonrpc":"2.0","method":"create","params":{"name":"rpcdb","password":"password"},"id":1}"#,
+            r#"{"jsonrpc":"2.0","method":"create_structure","params":{"name":"people"},"id":2}"#,
+            r#"{"jsonrpc":"2.0","method":"select","params":{"name":"people"},"id":3}"#,
+            r#"{"jsonrpc":"2.0","method":"new_object","params":{"name":"alice"},"id":4}"#,
+            r#"{"jsonrpc":"2.0","method":"select","params":{"name":"alice"},"id":5}"#,
+            r#"{"jsonrpc":"2.0","method":"new_cell","params":{"field":"age","type":"literal","content":"20"},"id":6}"#,
+        ];
+        for i in &requests {
+            assert_eq!(call(&mut session, i)["result"], Value::Null, "{}", i);
+        }
+        let result = call(
+            &mut session,
+            r#"{"jsonrpc":"2.0","method":"read","params":{"field":"age"},"id":7}"#,
+        );
+        assert_eq!(
+            result["result"],
+            json!({"field": "age", "type": "literal", "value": "20"})
+        );
+        std::fs::remove_dir_all("rpcdb").unwrap();
+    }
+    #[test]
+    fn blob_content_is_inline() {
+        let mut session = Session::new(".").unwrap();
+        let requests = [
+            r#"{"jsonrpc":"2.0","method":"create","params":{"name":"rpcblobdb","password":"password"},"id":1}"#,
+            r#"{"jsonrpc":"2.0","method":"create_structure","params":{"name":"people"},"id":2}"#,
+            r#"{"jsonrpc":"2.0","method":"select","params":{"name":"people"},"id":3}"#,
+            r#"{"jsonrpc":"2.0","method":"new_object","params":{"name":"alice"},"id":4}"#,
+            r#"{"jsonrpc":"2.0","method":"select","params":{"name":"alice"},"id":5}"#,
+        ];
+        for i in &requests {
+            assert_eq!(call(&mut session, i)["result"], Value::Null, "{}", i);
+        }
+        //A path is never read from the file system of the server
+        for i in ["Cargo.toml", "/etc/passwd"] {
+            let request = json!({"jsonrpc": "2.0", "method": "new_cell", "id": 6,
+                "params": {"field": "key", "type": "blob", "content": i}});
+            let result = call(&mut session, &request.to_string());
+            assert_eq!(result["error"]["code"], json!(-32602), "{}", i);
+        }
+        let requests = [
+            r#"{"jsonrpc":"2.0","method":"new_cell","params":{"field":"key","type":"blob","content":"00ff"},"id":7}"#,
+            r#"{"jsonrpc":"2.0","method":"alter","params":{"field":"key","content":"AQI=","encoding":"base64"},"id":8}"#,
+        ];
+        for i in &requests {
+            assert_eq!(call(&mut session, i)["result"], Value::Null, "{}", i);
+        }
+        assert_eq!(
+            call(
+                &mut session,
+                r#"{"jsonrpc":"2.0","method":"alter","params":{"field":"key","content":"Cargo.toml"},"id":9}"#
+            )["error"]["code"],
+            json!(-32602)
+        );
+        let result = call(
+            &mut session,
+            r#"{"jsonrpc":"2.0","method":"read","params":{"field":"key"},"id":10}"#,
+        );
+        assert_eq!(
+            result["result"],
+            json!({"field": "key", "type": "blob", "value": "0102"})
+        );
+        std::fs::remove_dir_all("rpcblobdb").unwrap();
+    }
+    #[test]
+    fn paths_outside_root() {
+        let mut session = Session::new("src").unwrap();
+        for (method, params) in [
+            (
+                "create",
+                json!({"name": "../rpcoutside", "password": "password"}),
+            ),
+            (
+                "create",
+                json!({"name": "/tmp/rpcoutside", "password": "password"}),
+            ),
+            (
+                "decrypt",
+                json!({"name": "rpc/../../rpcdb", "password": "password"}),
+            ),
+            (
+                "decrypt",
+                json!({"name": "rpcdb", "keyfile": "../Cargo.toml"}),
+            ),
+            (
+                "convert",
+                json!({"source": "rpcdb", "destination": "../rpcoutside", "password": "password"}),
+            ),
+        ] {
+            let request = json!({"jsonrpc": "2.0", "method": method, "params": params, "id": 1});
+            let result = call(&mut session, &request.to_string());
+            assert_eq!(result["error"]["code"], json!(-32602), "{}", request);
+        }
+        assert!(!std::path::Path::new("rpcoutside").exists());
+    }
+}
@@ -0,0 +1,179 @@
+//! Optional schema of a structure
+//!
+//! The schema is kept in structure metadata attributes: `schema.types.<field>` (comma separated,
+//! or `any`), `schema.required.<field>`, `schema.default.<field>` and `schema.strict`. Fields
+//! without `schema.types.<field>` are not declared.
+use super::error::MsdbError;
+use super::metadata::Metadata;
+use std::collections::BTreeMap;
+/// Every schema attribute starts with it
+pub const ATTRIBUTE_PREFIX: &str = "schema.";
+const TYPES_PREFIX: &str = "schema.types.";
+const REQUIRED_PREFIX: &str = "schema.required.";
+const DEFAULT_PREFIX: &str = "schema.default.";
+const STRICT_ATTRIBUTE: &str = "schema.strict";
+const ANY_TYPE: &str = "any";
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FieldSchema {
+    /// Allowed cell types. Empty for any type.
+    pub types: Vec<String>,
+    /// Cell can not be deleted from an object
+    pub required: bool,
+    /// Content of the cell created with each new object, as given to `new`
+    pub default: Option<String>,
+}
+impl FieldSchema {
+    /// Type of default cell: the first allowed type
+    pub fn default_type(&self) -> &str {
+        self.types.first().map_or("literal", String::as_str)
+    }
+}
+impl std::fmt::Display for FieldSchema {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.types.is_empty() {
+            write!(f, "{}", ANY_TYPE)?;
+        } else {
+            write!(f, "{}", self.types.join(", "))?;
+        }
+        match (self.required, &self.default) {
+            (true, Some(i)) => write!(f, " (required, default {})", i),
+            (true, None) => write!(f, " (required)"),
+            (false, Some(i)) => write!(f, " (default {})", i),
+            (false, None) => Ok(()),
+        }
+    }
+}
+#[derive(Debug, Default, PartialEq)]
+pub struct Schema {
+    /// Fields not declared can not be created
+    pub strict: bool,
+    pub fields: BTreeMap<String, FieldSchema>,
+}
+impl Schema {
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let attribute = metadata.attribute();
+        let fields = attribute
+            .iter()
+            .filter_map(|(key, value)| {
+                let field = key.strip_prefix(TYPES_PREFIX)?;
+                let types = if value == ANY_TYPE {
+                    Vec::new()
+                } else {
+                    value.split(',').map(String::from).collect()
+                };
+                let required = attribute.contains_key(&format!("{}{}", REQUIRED_PREFIX, field));
+                let default = attribute
+                    .get(&format!("{}{}", DEFAULT_PREFIX, field))
+                    .cloned();
+                Some((
+                    field.to_string(),
+                    FieldSchema {
+                        types,
+                        required,
+                        default,
+                    },
+                ))
+            })
+            .collect();
+        Self {
+            strict: attribute.contains_key(STRICT_ATTRIBUTE),
+            fields,
+        }
+    }
+    /// Declare a field, replacing its previous declaration
+    pub fn set_field(metadata: &mut Metadata, field: &str, schema: &FieldSchema) {
+        Self::remove_field(metadata, field);
+        let types = if schema.types.is_empty() {
+            String::from(ANY_TYPE)
+        } else {
+            schema.types.join(",")
+        };
+        metadata.set_attribute(&format!("{}{}", TYPES_PREFIX, field), &types);
+        if schema.required {
+            metadata.set_attribute(&format!("{}{}", REQUIRED_PREFIX, field), "true");
+        }
+        if let Some(i) = &schema.default {
+            metadata.set_attribute(&format!("{}{}", DEFAULT_PREFIX, field), i);
+        }
+    }
+    pub fn remove_field(metadata: &mut Metadata, field: &str) {
+        for i in &[TYPES_PREFIX, REQUIRED_PREFIX, DEFAULT_PREFIX] {
+            metadata.delete_attribute(&format!("{}{}", i, field));
+        }
+    }
+    pub fn set_strict(metadata: &mut Metadata, strict: bool) {
+        if strict {
+            metadata.set_attribute(STRICT_ATTRIBUTE, "true");
+        } else {
+            metadata.delete_attribute(STRICT_ATTRIBUTE);
+        }
+    }
+    /// Check a cell of `cell_type` before it is written into field. `field_exists` is whether
+    /// the field has been created already.
+    pub fn check_cell(
+        &self,
+        field: &str,
+        cell_type: &str,
+        field_exists: bool,
+    ) -> Result<(), MsdbError> {
+        match self.fields.get(field) {
+            Some(i) if !i.types.is_empty() && !i.types.iter().any(|j| j == cell_type) => {
+                Err(MsdbError::InvalidArgument(format!(
+                    "Field {} only accepts {} cells, not {}",
+                    field,
+                    i.types.join(", "),
+                    cell_type
+                )))
+            }
+            None if self.strict && !field_exists => Err(MsdbError::InvalidArgument(format!(
+                "Field {} is not declared in schema, and the structure is in strict mode",
+                field
+            ))),
+            _ => Ok(()),
+        }
+    }
+    /// Check removal of a cell in field
+    pub fn check_delete(&self, field: &str) -> Result<(), MsdbError> {
+        match self.fields.get(field) {
+            Some(i) if i.required => Err(MsdbError::InvalidArgument(format!(
+                "Field {} is required by schema",
+                field
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::{FieldSchema, Schema};
+    use crate::metadata::Metadata;
+    #[test]
+    fn metadata_round_trip() {
+        let mut metadata = Metadata::create();
+        let age = FieldSchema {
+            types: vec![String::from("int"), String::from("float")],
+            required: true,
+            default: Some(String::from("0")),
+        };
+        Schema::set_field(&mut metadata, "age", &age);
+        Schema::set_field(&mut metadata, "note.v2", &FieldSchema::default());
+        Schema::set_strict(&mut metadata, true);
+        let schema = Schema::from_metadata(&metadata);
+        assert!(schema.strict);
+        assert_eq!(schema.fields.get("age"), Some(&age));
+        assert_eq!(schema.fields.get("note.v2"), Some(&FieldSchema::default()));
+        assert!(schema.check_cell("age", "float", true).is_ok());
+        assert!(schema.check_cell("age", "literal", true).is_err());
+        assert!(schema.check_cell("typo", "literal", false).is_err());
+        assert!(schema.check_cell("legacy", "literal", true).is_ok());
+        assert!(schema.check_delete("age").is_err());
+        //Replacing a declaration drops what it does not set any more
+        Schema::set_field(&mut metadata, "age", &FieldSchema::default());
+        Schema::remove_field(&mut metadata, "note.v2");
+        Schema::set_strict(&mut metadata, false);
+        let schema = Schema::from_metadata(&metadata);
+        assert_eq!(schema.fields.len(), 1);
+        assert!(schema.check_delete("age").is_ok());
+        assert!(schema.check_cell("typo", "literal", false).is_ok());
+    }
+}
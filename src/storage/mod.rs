@@ -0,0 +1,282 @@
+//! Where the files of a database are kept
+//!
+//! Every backend maps names (paths in the directory layout, like `keyslots/0` or
+//! `<structure>/<field>/0.blk`) to blobs. Changes are staged by `put` and `delete`, and only become
+//! durable at `commit`, which is atomic.
+use super::archive::{self, TarStorage};
+use super::binary_io;
+use super::blockencrypt::DatabaseKey;
+use super::container::{self, Container};
+use super::error::MsdbError;
+use super::journal::{self, Journal};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path;
+pub trait Storage {
+    /// Content of a blob, including staged changes
+    fn get(&mut self, name: &str) -> Result<Vec<u8>, MsdbError>;
+    /// Stage a blob. It will not be durable until `commit`.
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<(), MsdbError>;
+    /// Stage removal of a blob. Removing a missing blob is not an error.
+    fn delete(&mut self, name: &str) -> Result<(), MsdbError>;
+    /// Names starting with `prefix`, in order, including staged changes
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, MsdbError>;
+    /// Make every staged change durable at once
+    fn commit(&mut self) -> Result<(), MsdbError>;
+    /// Whether anything is staged and not committed yet
+    fn has_staged_changes(&self) -> bool;
+    /// Hand over the key of the database once it is known, for backends which encrypt their own
+    /// index. Names needed to find the key (key slots) are available before.
+    fn unlock(&mut self, _key: &DatabaseKey) -> Result<(), MsdbError> {
+        Ok(())
+    }
+    /// Database name, as shown to the user
+    fn name(&self) -> &str;
+    /// Size of a blob in bytes
+    fn size(&mut self, name: &str) -> Result<u64, MsdbError> {
+        self.get(name).map(|i| i.len() as u64)
+    }
+    fn contains(&mut self, name: &str) -> bool {
+        self.list(name)
+            .is_ok_and(|i| i.iter().any(|j| j.as_str() == name))
+    }
+}
+/// Open an existing database: a tar archive, a single-file container or a directory
+pub fn open(name: &str) -> Result<Box<dyn Storage>, MsdbError> {
+    let location = path::Path::new(name);
+    if location.is_file() && name.ends_with(archive::EXTENSION) {
+        Ok(Box::new(TarStorage::open(name)?))
+    } else if location.is_file() {
+        Ok(Box::new(Container::open(name)?))
+    } else {
+        Ok(Box::new(FsStorage::open(name)?))
+    }
+}
+/// Create an empty database, whose backend is chosen by the extension of name
+pub fn create(name: &str) -> Result<Box<dyn Storage>, MsdbError> {
+    if name.ends_with(container::EXTENSION) {
+        Ok(Box::new(Container::create(name)?))
+    } else if name.ends_with(archive::EXTENSION) {
+        Ok(Box::new(TarStorage::create(name)?))
+    } else {
+        fs::create_dir_all(name).map_err(|e| MsdbError::from_io(name, e))?;
+        Ok(Box::new(FsStorage::open(name)?))
+    }
+}
+/// Database directory, with one file per blob. Commits go through the [`Journal`].
+pub struct FsStorage {
+    root: String,
+    journal: Journal,
+}
+impl FsStorage {
+    /// Open a database directory, finishing an interrupted commit first
+    pub fn open(root: &str) -> Result<Self, MsdbError> {
+        if !path::Path::new(root).is_dir() {
+            return Err(MsdbError::MissingFile(root.to_string()));
+        }
+        journal::recover(root)?;
+        Ok(Self {
+            root: root.to_string(),
+            journal: Journal::new(root),
+        })
+    }
+    fn path(&self, name: &str) -> String {
+        format!("{}/{}", self.root, name)
+    }
+    /// Append committed files inside `directory` (relative to root) to result
+    fn walk(&self, directory: &str, result: &mut Vec<String>) -> Result<(), MsdbError> {
+        let full_directory = self.path(directory);
+        let entries = match fs::read_dir(&full_directory) {
+            Ok(i) => i,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(MsdbError::from_io(&full_directory, e)),
+        };
+        for entry in entries {
+            let entry = entry.map_err(|e| MsdbError::from_io(&full_directory, e))?;
+            let name = format!("{}{}", directory, entry.file_name().to_string_lossy());
+            if entry.path().is_dir() {
+                self.walk(&format!("{}/", name), result)?;
+            } else if !name.ends_with(journal::TEMP_SUFFIX) && name != journal::JOURNAL_NAME {
+                result.push(name);
+            }
+        }
+        Ok(())
+    }
+}
+impl Storage for FsStorage {
+    fn get(&mut self, name: &str) -> Result<Vec<u8>, MsdbError> {
+        match self.journal.staged(name) {
+            Some(true) => binary_io::read_all(&self.journal.temp_filename(name)),
+            Some(false) => Err(MsdbError::MissingFile(self.path(name))),
+            None => binary_io::read_all(&self.path(name)),
+        }
+    }
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<(), MsdbError> {
+        if let Some(parent) = path::Path::new(&self.path(name)).parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| MsdbError::from_io(&parent.to_string_lossy(), e))?;
+        }
+        self.journal.write(name, data)
+    }
+    fn delete(&mut self, name: &str) -> Result<(), MsdbError> {
+        self.journal.remove(name);
+        Ok(())
+    }
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, MsdbError> {
+        //Only walk the deepest directory which can contain matches
+        let directory = prefix.rfind('/').map_or("", |i| &prefix[..=i]);
+        let mut result = Vec::new();
+        self.walk(directory, &mut result)?;
+        let mut result: BTreeMap<String, bool> = result
+            .into_iter()
+            .filter(|i| i.starts_with(prefix))
+            .map(|i| (i, true))
+            .collect();
+        for (name, write) in self.journal.changes() {
+            if name.starts_with(prefix) {
+                result.insert(name.clone(), write);
+            }
+        }
+        Ok(result.into_iter().filter(|i| i.1).map(|i| i.0).collect())
+    }
+    fn commit(&mut self) -> Result<(), MsdbError> {
+        self.journal.commit()
+    }
+    fn has_staged_changes(&self) -> bool {
+        self.journal.changes().next().is_some()
+    }
+    fn name(&self) -> &str {
+        &self.root
+    }
+    fn size(&mut self, name: &str) -> Result<u64, MsdbError> {
+        let filename = match self.journal.staged(name) {
+            Some(true) => self.journal.temp_filename(name),
+            Some(false) => return Err(MsdbError::MissingFile(self.path(name))),
+            None => self.path(name),
+        };
+        fs::metadata(&filename)
+            .map(|i| i.len())
+            .map_err(|e| MsdbError::from_io(&filename, e))
+    }
+    fn contains(&mut self, name: &str) -> bool {
+        match self.journal.staged(name) {
+            Some(i) => i,
+            None => path::Path::new(&self.path(name)).is_file(),
+        }
+    }
+}
+/// Database kept in memory only, for tests
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    name: String,
+    committed: BTreeMap<String, Vec<u8>>,
+    /// `None` for removal
+    staged: BTreeMap<String, Option<Vec<u8>>>,
+}
+impl MemoryStorage {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Self::default()
+        }
+    }
+    /// Committed blobs, in order
+    pub fn blobs(&self) -> impl Iterator<Item = (&String, &Vec<u8>)> {
+        self.committed.iter()
+    }
+    /// Replace committed blobs, dropping staged changes
+    pub fn load(&mut self, blobs: BTreeMap<String, Vec<u8>>) {
+        self.committed = blobs;
+        self.staged.clear();
+    }
+}
+impl Storage for MemoryStorage {
+    fn get(&mut self, name: &str) -> Result<Vec<u8>, MsdbError> {
+        match self.staged.get(name) {
+            Some(i) => i.clone(),
+            None => self.committed.get(name).cloned(),
+        }
+        .ok_or_else(|| MsdbError::MissingFile(format!("{}:{}", self.name, name)))
+    }
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<(), MsdbError> {
+        self.staged.insert(name.to_string(), Some(data.to_vec()));
+        Ok(())
+    }
+    fn delete(&mut self, name: &str) -> Result<(), MsdbError> {
+        self.staged.insert(name.to_string(), None);
+        Ok(())
+    }
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, MsdbError> {
+        let mut result: BTreeMap<&String, bool> = self
+            .committed
+            .range(prefix.to_string()..)
+            .take_while(|i| i.0.starts_with(prefix))
+            .map(|i| (i.0, true))
+            .collect();
+        for (name, data) in self.staged.range(prefix.to_string()..) {
+            if !name.starts_with(prefix) {
+                break;
+            }
+            result.insert(name, data.is_some());
+        }
+        Ok(result
+            .into_iter()
+            .filter(|i| i.1)
+            .map(|i| i.0.clone())
+            .collect())
+    }
+    fn commit(&mut self) -> Result<(), MsdbError> {
+        for (name, data) in std::mem::take(&mut self.staged) {
+            match data {
+                Some(data) => self.committed.insert(name, data),
+                None => self.committed.remove(&name),
+            };
+        }
+        Ok(())
+    }
+    fn has_staged_changes(&self) -> bool {
+        !self.staged.is_empty()
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn contains(&mut self, name: &str) -> bool {
+        match self.staged.get(name) {
+            Some(i) => i.is_some(),
+            None => self.committed.contains_key(name),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::{FsStorage, MemoryStorage, Storage};
+    use std::fs;
+    fn staging(storage: &mut dyn Storage) {
+        storage.put("a/1", b"one").unwrap();
+        storage.put("a/2", b"two").unwrap();
+        storage.put("b", b"three").unwrap();
+        storage.commit().unwrap();
+        storage.delete("a/1").unwrap();
+        storage.put("a/3", b"four").unwrap();
+        //Staged changes are visible before commit, and the last change of a name wins
+        storage.delete("b").unwrap();
+        storage.put("b", b"five").unwrap();
+        assert_eq!(storage.list("a/").unwrap(), vec!["a/2", "a/3"]);
+        assert_eq!(storage.get("b").unwrap(), b"five");
+        assert!(!storage.contains("a/1"));
+        storage.commit().unwrap();
+        assert_eq!(storage.list("").unwrap(), vec!["a/2", "a/3", "b"]);
+        assert!(storage.get("a/1").is_err());
+    }
+    #[test]
+    fn memory() {
+        staging(&mut MemoryStorage::new("memory"));
+    }
+    #[test]
+    fn filesystem() {
+        fs::create_dir_all("storagedb").unwrap();
+        staging(&mut FsStorage::open("storagedb").unwrap());
+        assert_eq!(fs::read("storagedb/b").unwrap(), b"five");
+        fs::remove_dir_all("storagedb").unwrap();
+    }
+}
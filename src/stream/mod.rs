@@ -0,0 +1,250 @@
+//! Blobs kept outside of fields
+//!
+//! A streamed blob is encrypted with `secretstream` chunk by chunk while it is read, so it is never
+//! held in memory as a whole. It lives in `<owner>/streams/<id>/`, where owner is the structure of
+//! its cell or the [blob store](crate::blobstore): `header` holds the secretstream header, and `0`,
+//! `1`, ... hold chunks of [`CHUNK_SIZE`] bytes. The last chunk is tagged final, so a truncated
+//! stream fails to read.
+//!
+//! With a [padding](crate::padding) policy, the last chunk is padded, and `header` is followed by a
+//! format byte which is part of the `ad` of every chunk. The number of chunks still tells the
+//! length of the blob to [`CHUNK_SIZE`].
+use super::blockencrypt::DatabaseKey;
+use super::error::MsdbError;
+use super::padding::{self, Padding};
+use super::storage::Storage;
+use sodiumoxide::crypto::secretstream::xchacha20poly1305 as secretstream;
+use sodiumoxide::crypto::secretstream::Tag;
+use std::io::{ErrorKind, Read, Write};
+pub const STREAM_ID_LEN: usize = 16;
+/// Plaintext size of a chunk. Blobs up to this size are kept inside fields.
+pub const CHUNK_SIZE: usize = 65536;
+const STREAM_AD_PREFIX: &[u8] = b"msdb-stream";
+/// Format byte of streams whose last chunk is padded
+const PADDED_FORMAT: u8 = 1;
+/// Directory of a stream inside owner
+pub fn directory(owner: &str, id: &[u8; STREAM_ID_LEN]) -> String {
+    format!("{}/streams/{}", owner, hex::encode(id))
+}
+/// `ad` of a chunk: database ID, chunk index, format byte (if any) and directory of the stream
+fn associated_data(key: &DatabaseKey, directory: &str, index: u64, format: &[u8]) -> Vec<u8> {
+    let mut ad = STREAM_AD_PREFIX.to_vec();
+    ad.extend_from_slice(key.id());
+    ad.extend_from_slice(&index.to_le_bytes());
+    ad.extend_from_slice(format);
+    ad.extend_from_slice(directory.as_bytes());
+    ad
+}
+/// Read until `buffer` is full or `reader` ends, returning the number of bytes read
+fn fill(reader: &mut impl Read, buffer: &mut [u8]) -> Result<usize, MsdbError> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match reader.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(i) => len += i,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(MsdbError::from_io("blob source", e)),
+        }
+    }
+    Ok(len)
+}
+/// Encrypt everything from `reader` into a new stream of owner, returning its identifier and
+/// length. Chunks are staged in storage as they are read, and the last one is padded.
+pub fn write(
+    storage: &mut dyn Storage,
+    key: &DatabaseKey,
+    owner: &str,
+    reader: &mut impl Read,
+    padding: &Padding,
+) -> Result<([u8; STREAM_ID_LEN], u64), MsdbError> {
+    let mut id = [0; STREAM_ID_LEN];
+    sodiumoxide::randombytes::randombytes_into(&mut id);
+    let directory = directory(owner, &id);
+    let (mut stream, header) = secretstream::Stream::init_push(key.stream_key())
+        .expect("Unable to initialize secretstream");
+    let format: &[u8] = if *padding == Padding::None {
+        &[]
+    } else {
+        &[PADDED_FORMAT]
+    };
+    storage.put(
+        &format!("{}/header", directory),
+        &[&header[..], format].concat(),
+    )?;
+    //One chunk is read ahead, so the last one can be tagged final
+    let mut current = vec![0; CHUNK_SIZE];
+    let mut next = vec![0; CHUNK_SIZE];
+    let mut current_len = fill(reader, &mut current)?;
+    let mut index = 0;
+    let mut len = 0;
+    loop {
+        let next_len = if current_len == CHUNK_SIZE {
+            fill(reader, &mut next)?
+        } else {
+            0
+        };
+        let tag = if next_len == 0 {
+            Tag::Final
+        } else {
+            Tag::Message
+        };
+        let plaintext = if tag == Tag::Final && !format.is_empty() {
+            padding.pad(&current[..current_len])
+        } else {
+            current[..current_len].to_vec()
+        };
+        let chunk = stream
+            .push(
+                &plaintext,
+                Some(&associated_data(key, &directory, index, format)),
+                tag,
+            )
+            .expect("Unable to encrypt chunk");
+        storage.put(&format!("{}/{}", directory, index), &chunk)?;
+        len += current_len as u64;
+        if tag == Tag::Final {
+            return Ok((id, len));
+        }
+        std::mem::swap(&mut current, &mut next);
+        current_len = next_len;
+        index += 1;
+    }
+}
+/// Decrypt a stream of owner into `writer` chunk by chunk, returning its length
+pub fn read(
+    storage: &mut dyn Storage,
+    key: &DatabaseKey,
+    owner: &str,
+    id: &[u8; STREAM_ID_LEN],
+    writer: &mut (impl Write + ?Sized),
+) -> Result<u64, MsdbError> {
+    let directory = directory(owner, id);
+    let invalid_header =
+        || MsdbError::CorruptBlock(format!("Invalid secretstream header of {}", directory));
+    let header = storage.get(&format!("{}/header", directory))?;
+    let (header, format) = header.split_at(secretstream::HEADERBYTES.min(header.len()));
+    if !matches!(format, [] | [PADDED_FORMAT]) {
+        return Err(invalid_header());
+    }
+    let header = secretstream::Header::from_slice(header).ok_or_else(invalid_header)?;
+    let mut stream = secretstream::Stream::init_pull(&header, key.stream_key()).map_err(|_| {
+        MsdbError::CorruptBlock(format!("Invalid secretstream header of {}", directory))
+    })?;
+    let mut index = 0;
+    let mut len = 0;
+    loop {
+        let filename = format!("{}/{}", directory, index);
+        let (mut chunk, tag) = stream
+            .pull(
+                &storage.get(&filename)?,
+                Some(&associated_data(key, &directory, index, format)),
+            )
+            .map_err(|_| {
+                MsdbError::CorruptBlock(format!("Authentication failed for {}", filename))
+            })?;
+        if tag == Tag::Final && !format.is_empty() {
+            chunk = padding::unpad(chunk)?;
+        }
+        writer
+            .write_all(&chunk)
+            .map_err(|e| MsdbError::from_io("blob destination", e))?;
+        len += chunk.len() as u64;
+        if tag == Tag::Final {
+            return Ok(len);
+        }
+        index += 1;
+    }
+}
+/// Files of a stream
+pub fn files(
+    storage: &mut dyn Storage,
+    owner: &str,
+    id: &[u8; STREAM_ID_LEN],
+) -> Result<Vec<String>, MsdbError> {
+    storage.list(&format!("{}/", directory(owner, id)))
+}
+/// Stage removal of a stream
+pub fn remove(
+    storage: &mut dyn Storage,
+    owner: &str,
+    id: &[u8; STREAM_ID_LEN],
+) -> Result<(), MsdbError> {
+    for i in files(storage, owner, id)? {
+        storage.delete(&i)?;
+    }
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use super::{files, read, remove, write, CHUNK_SIZE};
+    use crate::blockencrypt::DatabaseKey;
+    use crate::padding::Padding;
+    use crate::storage::{MemoryStorage, Storage};
+    use sodiumoxide::crypto::secretbox;
+    use sodiumoxide::crypto::secretstream::xchacha20poly1305 as secretstream;
+    #[test]
+    fn round_trip() {
+        let key = DatabaseKey::new(secretbox::gen_key());
+        let mut storage = MemoryStorage::new("memory");
+        for len in [0, 10, CHUNK_SIZE, 2 * CHUNK_SIZE + 7] {
+            let content: Vec<u8> = (0..len).map(|i| (i % 253) as u8).collect();
+            let (id, written) = write(
+                &mut storage,
+                &key,
+                "0011",
+                &mut &content[..],
+                &Padding::None,
+            )
+            .unwrap();
+            assert_eq!(written, len as u64);
+            assert_eq!(
+                files(&mut storage, "0011", &id).unwrap().len(),
+                len.max(1).div_ceil(CHUNK_SIZE) + 1
+            );
+            let mut output = Vec::new();
+            assert_eq!(
+                read(&mut storage, &key, "0011", &id, &mut output).unwrap(),
+                len as u64
+            );
+            assert_eq!(output, content);
+            if len > CHUNK_SIZE {
+                //Truncated and reordered streams are refused
+                let last = format!("{}/{}", super::directory("0011", &id), len / CHUNK_SIZE);
+                let first = format!("{}/0", super::directory("0011", &id));
+                let last_chunk = storage.get(&last).unwrap();
+                storage.delete(&last).unwrap();
+                assert!(read(&mut storage, &key, "0011", &id, &mut Vec::new()).is_err());
+                let first_chunk = storage.get(&first).unwrap();
+                storage.put(&last, &first_chunk).unwrap();
+                assert!(read(&mut storage, &key, "0011", &id, &mut Vec::new()).is_err());
+                storage.put(&last, &last_chunk).unwrap();
+            }
+            remove(&mut storage, "0011", &id).unwrap();
+            assert!(files(&mut storage, "0011", &id).unwrap().is_empty());
+        }
+    }
+    #[test]
+    fn padded_last_chunk() {
+        let key = DatabaseKey::new(secretbox::gen_key());
+        let mut storage = MemoryStorage::new("memory");
+        let padding = Padding::from_name("buckets:4096").unwrap();
+        let content = vec![7; CHUNK_SIZE + 10];
+        let (id, written) = write(&mut storage, &key, "0011", &mut &content[..], &padding).unwrap();
+        assert_eq!(written, content.len() as u64);
+        let directory = super::directory("0011", &id);
+        let last = storage.get(&format!("{}/1", directory)).unwrap();
+        assert_eq!(last.len(), 4096 + secretstream::ABYTES);
+        let mut output = Vec::new();
+        read(&mut storage, &key, "0011", &id, &mut output).unwrap();
+        assert_eq!(output, content);
+        //Format byte cannot be stripped
+        let header = storage.get(&format!("{}/header", directory)).unwrap();
+        storage
+            .put(
+                &format!("{}/header", directory),
+                &header[..secretstream::HEADERBYTES],
+            )
+            .unwrap();
+        assert!(read(&mut storage, &key, "0011", &id, &mut Vec::new()).is_err());
+    }
+}
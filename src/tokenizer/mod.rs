@@ -0,0 +1,171 @@
+//! Splitting command lines into arguments
+//!
+//! Arguments are separated by whitespace, like in a shell:
+//! - `"..."` keeps whitespace. Inside, `\"`, `\\`, `\n` and `\t` are escaped.
+//! - `'...'` keeps everything as it is
+//! - `\` outside quotes escapes the next character
+//! - `<<TAG` takes the following lines, up to a line containing only `TAG`, as one argument. It
+//!   needs a [`Reader`], since the value spans several lines.
+use super::error::MsdbError;
+/// Arguments of a single line
+pub fn split(line: &str) -> Result<Vec<String>, MsdbError> {
+    match tokenize(line)? {
+        (arguments, None) => Ok(arguments),
+        (_, Some(_)) => Err(MsdbError::InvalidArgument(String::from(
+            "Heredoc is only available in scripts and command line interface",
+        ))),
+    }
+}
+/// Collects lines until a command is complete
+#[derive(Default)]
+pub struct Reader {
+    arguments: Vec<String>,
+    heredoc: Option<Heredoc>,
+}
+struct Heredoc {
+    tag: String,
+    /// Argument replaced by the content
+    position: usize,
+    lines: Vec<String>,
+}
+impl Reader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Whether more lines are needed to finish current command
+    pub const fn is_pending(&self) -> bool {
+        self.heredoc.is_some()
+    }
+    /// Feed a line, returning the arguments once the command is complete
+    pub fn push_line(&mut self, line: &str) -> Result<Option<Vec<String>>, MsdbError> {
+        if let Some(heredoc) = self.heredoc.as_mut() {
+            if line.trim_end() != heredoc.tag {
+                heredoc.lines.push(line.to_string());
+                return Ok(None);
+            }
+            let heredoc = self.heredoc.take().unwrap();
+            self.arguments[heredoc.position] = heredoc.lines.join("\n");
+            return Ok(Some(std::mem::take(&mut self.arguments)));
+        }
+        let (arguments, heredoc) = tokenize(line)?;
+        if heredoc.is_none() {
+            return Ok(Some(arguments));
+        }
+        self.arguments = arguments;
+        self.heredoc = heredoc;
+        Ok(None)
+    }
+}
+/// Arguments of a line, and the heredoc it starts if there is one
+fn tokenize(line: &str) -> Result<(Vec<String>, Option<Heredoc>), MsdbError> {
+    let mut arguments = Vec::new();
+    let mut heredoc = None;
+    //`None` between arguments
+    let mut current: Option<String> = None;
+    //A quoted / escaped argument is never a heredoc
+    let mut quoted = false;
+    let mut chars = line.chars();
+    let unterminated = |i| MsdbError::InvalidArgument(format!("Unterminated {} in {}", i, line));
+    loop {
+        let c = chars.next();
+        match c {
+            Some('\\') => {
+                quoted = true;
+                let escaped = chars.next().ok_or_else(|| unterminated("escape"))?;
+                current.get_or_insert_with(String::new).push(escaped);
+            }
+            Some('\'') => {
+                quoted = true;
+                let argument = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or_else(|| unterminated("quote"))? {
+                        '\'' => break,
+                        i => argument.push(i),
+                    }
+                }
+            }
+            Some('"') => {
+                quoted = true;
+                let argument = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or_else(|| unterminated("quote"))? {
+                        '"' => break,
+                        '\\' => argument.push(match chars.next() {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some(i) => i,
+                            None => return Err(unterminated("quote")),
+                        }),
+                        i => argument.push(i),
+                    }
+                }
+            }
+            Some(i) if !i.is_whitespace() => current.get_or_insert_with(String::new).push(i),
+            _ => {
+                if let Some(argument) = current.take() {
+                    match argument.strip_prefix("<<") {
+                        Some(tag) if !quoted && !tag.is_empty() => {
+                            if heredoc.is_some() {
+                                return Err(MsdbError::InvalidArgument(String::from(
+                                    "Only one heredoc is allowed in a command",
+                                )));
+                            }
+                            heredoc = Some(Heredoc {
+                                tag: tag.to_string(),
+                                position: arguments.len(),
+                                lines: Vec::new(),
+                            });
+                            arguments.push(String::new());
+                        }
+                        _ => arguments.push(argument),
+                    }
+                    quoted = false;
+                }
+                if c.is_none() {
+                    return Ok((arguments, heredoc));
+                }
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::{split, Reader};
+    #[test]
+    fn quotes_and_escapes() {
+        assert_eq!(
+            split(r#"new note literal "call back tomorrow""#).unwrap(),
+            vec!["new", "note", "literal", "call back tomorrow"]
+        );
+        assert_eq!(
+            split(r#"  a\ b 'c "d"' "e\"\\\n" '' "#).unwrap(),
+            vec!["a b", "c \"d\"", "e\"\\\n", ""]
+        );
+        assert_eq!(split("x'y'z").unwrap(), vec!["xyz"]);
+        assert!(split("\"open").is_err());
+        assert!(split("<<EOF").is_err());
+        assert_eq!(split("'<<EOF'").unwrap(), vec!["<<EOF"]);
+    }
+    #[test]
+    fn heredoc() {
+        let mut reader = Reader::new();
+        assert_eq!(reader.push_line("new note literal <<EOF").unwrap(), None);
+        assert!(reader.is_pending());
+        assert_eq!(reader.push_line("first line").unwrap(), None);
+        assert_eq!(reader.push_line("  \"second\"").unwrap(), None);
+        assert_eq!(
+            reader.push_line("EOF").unwrap(),
+            Some(vec![
+                String::from("new"),
+                String::from("note"),
+                String::from("literal"),
+                String::from("first line\n  \"second\"")
+            ])
+        );
+        assert!(!reader.is_pending());
+        assert_eq!(
+            reader.push_line("ls").unwrap(),
+            Some(vec![String::from("ls")])
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! Local record of the highest epoch seen for each database
+//!
+//! Every `sync` moves a database to a higher epoch. If a database is opened with a lower epoch than
+//! the one recorded here, an older copy of it has been restored (rolled back).
+//!
+//! Each line of the trust file is `<database ID in hex> <epoch>`.
+use super::binary_io;
+use super::error::MsdbError;
+use std::collections::BTreeMap;
+use std::fs;
+/// What to do when a database has been rolled back
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RollbackPolicy {
+    /// Print a warning and open the database anyway
+    Warn,
+    /// Refuse to open the database
+    Refuse,
+}
+pub struct TrustFile {
+    path: String,
+    policy: RollbackPolicy,
+    /// Highest epoch recorded for the database, once checked
+    seen: u64,
+}
+impl TrustFile {
+    pub fn new(path: &str, policy: RollbackPolicy) -> Self {
+        Self {
+            path: path.to_string(),
+            policy,
+            seen: 0,
+        }
+    }
+    /// Compare the epoch of a database with the recorded one, then remember it
+    pub fn check(&mut self, id: &[u8], epoch: u64) -> Result<(), MsdbError> {
+        let seen = self.read()?.get(&hex::encode(id)).copied().unwrap_or(0);
+        if epoch < seen {
+            let error = MsdbError::RolledBack { seen, found: epoch };
+            match self.policy {
+                RollbackPolicy::Refuse => return Err(error),
+                RollbackPolicy::Warn => println!("Warning: {}", error),
+            }
+        }
+        self.seen = seen;
+        self.record(id, epoch)
+    }
+    /// Remember a new epoch of the database. Lower epochs are ignored.
+    pub fn record(&mut self, id: &[u8], epoch: u64) -> Result<(), MsdbError> {
+        if epoch <= self.seen {
+            return Ok(());
+        }
+        let mut entries = self.read()?;
+        let seen = entries.entry(hex::encode(id)).or_insert(0);
+        *seen = epoch.max(*seen);
+        self.seen = *seen;
+        self.write(&entries)
+    }
+    fn read(&self) -> Result<BTreeMap<String, u64>, MsdbError> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(i) => i,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(MsdbError::from_io(&self.path, e)),
+        };
+        let corrupt = || MsdbError::CorruptBlock(format!("Invalid trust file {}", self.path));
+        let mut entries = BTreeMap::new();
+        for line in content.lines().filter(|i| !i.is_empty()) {
+            let mut fields = line.split(' ');
+            let id = fields.next().ok_or_else(corrupt)?;
+            let epoch = fields
+                .next()
+                .and_then(|i| i.parse().ok())
+                .ok_or_else(corrupt)?;
+            entries.insert(id.to_string(), epoch);
+        }
+        Ok(entries)
+    }
+    /// Replace the trust file atomically
+    fn write(&self, entries: &BTreeMap<String, u64>) -> Result<(), MsdbError> {
+        let content: String = entries
+            .iter()
+            .map(|(id, epoch)| format!("{} {}\n", id, epoch))
+            .collect();
+        let temp_path = format!("{}.tmp", self.path);
+        binary_io::write_all_synced(&temp_path, content.as_bytes())?;
+        fs::rename(&temp_path, &self.path).map_err(|e| MsdbError::from_io(&temp_path, e))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::{RollbackPolicy, TrustFile};
+    use std::fs;
+    #[test]
+    fn detect_rollback() {
+        let path = "trustfile_test";
+        let mut trust = TrustFile::new(path, RollbackPolicy::Refuse);
+        trust.check(b"db", 3).unwrap();
+        trust.record(b"db", 5).unwrap();
+        assert!(TrustFile::new(path, RollbackPolicy::Refuse)
+            .check(b"db", 4)
+            .is_err());
+        TrustFile::new(path, RollbackPolicy::Warn)
+            .check(b"db", 4)
+            .unwrap();
+        TrustFile::new(path, RollbackPolicy::Refuse)
+            .check(b"other", 1)
+            .unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "6462 5\n6f74686572 1\n");
+        fs::remove_file(path).unwrap();
+    }
+}
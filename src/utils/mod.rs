@@ -1,67 +1,418 @@
 use super::binary_io;
-use super::blockencrypt;
+use super::blobstore;
+use super::blockencrypt::{self, DatabaseKey, Kdf};
+use super::compression;
+use super::credential::Credential;
+use super::error::MsdbError;
+use super::keyslot;
 use super::metadata;
-use sodiumoxide::crypto::pwhash;
+use super::padding::{self, Padding};
+use super::schema;
+use super::storage::{self, Storage};
 use sodiumoxide::crypto::secretbox;
+use std::convert::TryInto;
+use std::path;
 const DENY_ACCESS_FOR_UNMATCH_VERSION: bool = true;
-/// Create a database
-pub fn new_database(database_name: &str, password: &str, database_version_code: u8) {
-    std::fs::create_dir_all(&database_name).expect("Unable to create database root directory");
-    let salt = pwhash::gen_salt();
-    let salt_directory = format!("{}/salt", database_name);
-    binary_io::write_all(&salt_directory, &salt[..].to_vec());
+/// Main metadata attribute set once every block is authenticated with its location
+pub const AEAD_ATTRIBUTE: &str = "aead";
+pub const AEAD_REQUIRED: &str = "required";
+/// Main metadata attribute counting `sync`s. `epoch.<identifier>` attributes record when a
+/// structure (in main metadata) or a field (in structure metadata) was last written.
+pub const EPOCH_ATTRIBUTE: &str = "epoch";
+/// Create a database inside empty storage, returning its data key
+///
+/// Blocks are encrypted with a random data key, which is wrapped by the credential in key slot 0.
+pub fn new_database(
+    storage: &mut dyn Storage,
+    credential: &Credential,
+    kdf: Kdf,
+    database_version_code: u8,
+) -> Result<DatabaseKey, MsdbError> {
+    let key = DatabaseKey::new(secretbox::gen_key());
+    storage.unlock(&key)?;
+    keyslot::create(storage, credential, kdf, key.data_key())?;
     let mut initial_metadata = metadata::Metadata::create();
     initial_metadata.new_attribute(&String::from("ver"), &format!("{}", database_version_code));
     initial_metadata.new_attribute(&String::from("type"), &String::from("msdb"));
-    let metadata_filename = format!("{}/metadata", database_name);
-    let data = blockencrypt::encrypt_block(
+    initial_metadata.new_attribute(AEAD_ATTRIBUTE, AEAD_REQUIRED);
+    binary_io::write_block(
+        storage,
+        "metadata",
+        0,
+        0,
         &initial_metadata.to_vec(),
-        &blockencrypt::password_deriv(password, salt),
-    );
-    binary_io::write_with_nonce(&metadata_filename, &data.0, data.1);
-    println!("Completed. Have a nice day.");
+        &key,
+        &Padding::None,
+    )?;
+    storage.commit()?;
+    Ok(key)
+}
+/// Copy a database into a new one, converting between storage backends
+///
+/// Blocks are copied as they are. The credential unlocks the file tables of containers. Returns the
+/// number of files copied.
+pub fn convert_database(
+    source: &str,
+    destination: &str,
+    credential: &Credential,
+) -> Result<usize, MsdbError> {
+    let mut source_storage = storage::open(source)?;
+    if path::Path::new(destination).exists() {
+        return Err(MsdbError::AlreadyExists(destination.to_string()));
+    }
+    let (_, key) = derive_database_key(source_storage.as_mut(), credential)?;
+    source_storage.unlock(&key)?;
+    if !source_storage.contains("metadata") {
+        return Err(MsdbError::NotFound(format!("Database {}", source)));
+    }
+    let mut destination_storage = storage::create(destination)?;
+    destination_storage.unlock(&key)?;
+    let names = source_storage.list("")?;
+    for i in &names {
+        destination_storage.put(i, &source_storage.get(i)?)?;
+    }
+    destination_storage.commit()?;
+    Ok(names.len())
 }
 pub fn select_database(
-    database_name: &str,
-    password: &secretbox::Key,
+    storage: &mut dyn Storage,
+    password: &DatabaseKey,
     database_version_code: u8,
-) -> metadata::Metadata {
+) -> Result<metadata::Metadata, MsdbError> {
     let mut result_metadata = metadata::Metadata::create();
-    let block_decrypted = {
-        let block_directory = format!("{}/metadata", database_name);
-        let block = binary_io::read_with_nonce(&block_directory);
-        blockencrypt::decrypt_block(&block.0, password, block.1)
-    };
-    result_metadata.import(block_decrypted);
-    if result_metadata
-        .attribute()
-        .get(&"type".to_string())
-        .expect("Unable to identify metadata type")
-        .as_str()
-        != "msdb"
-    {
-        panic!("Unexpected metadata type");
+    let (block, header) = binary_io::read_with_nonce(storage, "metadata")?;
+    let epoch = blockencrypt::header_epoch(&header);
+    let block_decrypted =
+        blockencrypt::decrypt_block(&block, password, "metadata", 0, epoch, &header)?;
+    result_metadata.import(block_decrypted)?;
+    if read_epoch(&result_metadata, EPOCH_ATTRIBUTE)? != epoch {
+        return Err(MsdbError::CorruptBlock(String::from(
+            "Epoch of main metadata does not match its header",
+        )));
+    }
+    if is_upgraded(&result_metadata) {
+        password.require_aead();
+    }
+    //Refuse unknown policies now rather than at next `sync`
+    Padding::from_metadata(&result_metadata)?;
+    if result_metadata.attribute().get("type").map(String::as_str) != Some("msdb") {
+        return Err(MsdbError::CorruptBlock(String::from(
+            "Unexpected metadata type",
+        )));
     }
     {
         let database_version: u8 = result_metadata
             .attribute()
-            .get(&"ver".to_string())
-            .expect("Unable to fetch database version from metadata")
-            .parse()
-            .expect("Unable to translate database version from metadata to integer");
-        if database_version != database_version_code {
-            if DENY_ACCESS_FOR_UNMATCH_VERSION {
-                panic!("Database version({}) is not equal than database manager version({}). Denying access.", database_version, database_version_code);
+            .get("ver")
+            .and_then(|i| i.parse().ok())
+            .ok_or_else(|| {
+                MsdbError::CorruptBlock(String::from(
+                    "Unable to fetch database version from metadata",
+                ))
+            })?;
+        if database_version != database_version_code && DENY_ACCESS_FOR_UNMATCH_VERSION {
+            return Err(MsdbError::VersionMismatch {
+                database: database_version,
+                manager: database_version_code,
+            });
+        }
+    }
+    Ok(result_metadata)
+}
+/// Whether every block of the database is authenticated with its location, so legacy blocks are
+/// refused
+pub fn is_upgraded(main_metadata: &metadata::Metadata) -> bool {
+    main_metadata
+        .attribute()
+        .get(AEAD_ATTRIBUTE)
+        .map(String::as_str)
+        == Some(AEAD_REQUIRED)
+}
+/// Epoch stored in attribute `key`. Missing attributes are epoch 0.
+pub fn read_epoch(metadata: &metadata::Metadata, key: &str) -> Result<u64, MsdbError> {
+    metadata.attribute().get(key).map_or(Ok(0), |i| {
+        i.parse()
+            .map_err(|_| MsdbError::CorruptBlock(format!("Invalid epoch attribute {}", key)))
+    })
+}
+/// Refuse properties kept by the database itself (version, epochs, block format, blob store) or
+/// set by commands of their own (`compression`, `schema`), so `setprop` cannot forge them
+pub fn check_property_name(name: &str) -> Result<(), MsdbError> {
+    let reserved = [
+        "ver",
+        "type",
+        EPOCH_ATTRIBUTE,
+        AEAD_ATTRIBUTE,
+        padding::ATTRIBUTE,
+        blobstore::ENABLED_ATTRIBUTE,
+        compression::STRUCTURE_ATTRIBUTE,
+    ];
+    let reserved_prefixes = [
+        &epoch_key("")[..],
+        blobstore::ENTRY_PREFIX,
+        compression::FIELD_PREFIX,
+        schema::ATTRIBUTE_PREFIX,
+    ];
+    if reserved.contains(&name) || reserved_prefixes.iter().any(|i| name.starts_with(i)) {
+        return Err(MsdbError::InvalidArgument(format!(
+            "Property {} is reserved",
+            name
+        )));
+    }
+    Ok(())
+}
+/// Attribute recording the epoch of a structure or field
+pub fn epoch_key(identifier: &str) -> String {
+    format!("{}.{}", EPOCH_ATTRIBUTE, identifier)
+}
+/// Find the key slot unlocked by credential, returning slot number and data key
+pub fn derive_database_key(
+    storage: &mut dyn Storage,
+    credential: &Credential,
+) -> Result<(u32, DatabaseKey), MsdbError> {
+    let (keyslot, key) = keyslot::unlock(storage, credential)?;
+    Ok((keyslot, DatabaseKey::new(key)))
+}
+/// Standard base64 with padding
+pub fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut result = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(ALPHABET[(group >> (18 - 6 * i) & 63) as usize] as char);
             } else {
-                println!(
-                    "Warning: Database version({}) is not equal than database manager version({})",
-                    database_version, database_version_code
-                );
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+/// Decode standard base64 with padding, `None` if it is malformed
+pub fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+    let groups = encoded.len() / 4;
+    let mut result = Vec::with_capacity(groups * 3);
+    for (index, chunk) in encoded.chunks(4).enumerate() {
+        let padding = chunk.iter().rev().take_while(|i| **i == b'=').count();
+        //Padding only ends the last group
+        if padding > 2 || (padding > 0 && index + 1 != groups) {
+            return None;
+        }
+        let mut group = 0u32;
+        for i in &chunk[..4 - padding] {
+            let value = match i {
+                b'A'..=b'Z' => i - b'A',
+                b'a'..=b'z' => i - b'a' + 26,
+                b'0'..=b'9' => i - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                _ => return None,
+            };
+            group = group << 6 | u32::from(value);
+        }
+        group <<= 6 * padding;
+        result.extend_from_slice(&group.to_be_bytes()[1..4 - padding]);
+    }
+    Some(result)
+}
+/// `YYYY-MM-DDTHH:MM:SSZ` of seconds since Unix epoch
+pub fn format_timestamp(timestamp: i64) -> String {
+    let (year, month, day) = civil_from_days(timestamp.div_euclid(86400));
+    let seconds = timestamp.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+/// Seconds since Unix epoch of `now`, a date (`YYYY-MM-DD`, at midnight UTC) or an RFC 3339 time
+/// (`YYYY-MM-DDTHH:MM:SS`, optionally with fractional seconds, which are dropped, then `Z` or
+/// `+HH:MM` / `-HH:MM`)
+pub fn parse_timestamp(text: &str) -> Option<i64> {
+    if text == "now" {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()?;
+        return now.as_secs().try_into().ok();
+    }
+    let number = |start: usize, len: usize| {
+        let digits = text.get(start..start + len)?;
+        if digits.bytes().all(|i| i.is_ascii_digit()) {
+            digits.parse::<i64>().ok()
+        } else {
+            None
+        }
+    };
+    let separator = |position: usize, expected: &[u8]| {
+        text.as_bytes()
+            .get(position)
+            .filter(|i| expected.contains(i))
+            .is_some()
+    };
+    if !separator(4, b"-") || !separator(7, b"-") {
+        return None;
+    }
+    let (year, month, day) = (number(0, 4)?, number(5, 2)?, number(8, 2)?);
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    if text.len() == 10 {
+        return Some(days * 86400);
+    }
+    if !separator(10, b"Tt ") || !separator(13, b":") || !separator(16, b":") {
+        return None;
+    }
+    let (hour, minute, second) = (number(11, 2)?, number(14, 2)?, number(17, 2)?);
+    if hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    let mut rest = &text[19..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return None;
+        }
+        rest = &fraction[digits..];
+    }
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+            let hours = rest.get(1..3)?.parse::<i64>().ok()?;
+            let minutes = rest.get(4..6)?.parse::<i64>().ok()?;
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            match rest.as_bytes()[0] {
+                b'+' => hours * 3600 + minutes * 60,
+                b'-' => -(hours * 3600 + minutes * 60),
+                _ => return None,
             }
         }
+        _ => return None,
+    };
+    Some(days * 86400 + hour * 3600 + minute * 60 + second - offset)
+}
+/// Days since Unix epoch of a date in proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+const fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
     }
-    result_metadata
+}
+/// `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let uuid = hex::encode(uuid);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &uuid[..8],
+        &uuid[8..12],
+        &uuid[12..16],
+        &uuid[16..20],
+        &uuid[20..]
+    )
+}
+/// UUID in hyphenated or plain hex form, or a new random (version 4) one for `random`
+pub fn parse_uuid(text: &str) -> Option<[u8; 16]> {
+    if text == "random" {
+        let mut uuid = [0; 16];
+        sodiumoxide::randombytes::randombytes_into(&mut uuid);
+        uuid[6] = (uuid[6] & 0x0f) | 0x40;
+        uuid[8] = (uuid[8] & 0x3f) | 0x80;
+        return Some(uuid);
+    }
+    let plain = match text.len() {
+        32 => text.to_string(),
+        36 if [8, 13, 18, 23].iter().all(|i| text.as_bytes()[*i] == b'-') => text.replace('-', ""),
+        _ => return None,
+    };
+    hex::decode(plain).ok()?.try_into().ok()
 }
 pub fn exit() -> ! {
     std::process::exit(0);
 }
+#[cfg(test)]
+mod tests {
+    use super::{
+        base64_decode, base64_encode, format_timestamp, format_uuid, parse_timestamp, parse_uuid,
+    };
+    #[test]
+    fn base64() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        for i in [&b""[..], b"f", b"fo", b"foo", b"foob", b"\xff\x00\xfe"] {
+            assert_eq!(base64_decode(&base64_encode(i)).unwrap(), i);
+        }
+        for i in ["Zg=", "Zg=a", "Z===", "Zg==Zg==", "Zm9v YmFy"] {
+            assert_eq!(base64_decode(i), None);
+        }
+    }
+    #[test]
+    fn timestamp() {
+        assert_eq!(parse_timestamp("1970-01-01"), Some(0));
+        assert_eq!(
+            parse_timestamp("2024-02-29T12:34:56.789+02:00"),
+            Some(1_709_202_896)
+        );
+        assert_eq!(format_timestamp(1_709_202_896), "2024-02-29T10:34:56Z");
+        assert_eq!(format_timestamp(-1), "1969-12-31T23:59:59Z");
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59Z"), Some(-1));
+        assert_eq!(parse_timestamp("2023-02-29"), None);
+        assert_eq!(parse_timestamp("2024-01-01T24:00:00Z"), None);
+        assert_eq!(parse_timestamp("2024-01-01T00:00:00"), None);
+    }
+    #[test]
+    fn uuid() {
+        let uuid = parse_uuid("123E4567-e89b-12d3-a456-426614174000").unwrap();
+        assert_eq!(format_uuid(&uuid), "123e4567-e89b-12d3-a456-426614174000");
+        assert_eq!(parse_uuid("123e4567e89b12d3a456426614174000"), Some(uuid));
+        assert_eq!(parse_uuid("123e4567-e89b-12d3-a456-42661417400"), None);
+        assert_eq!(
+            format_uuid(&parse_uuid("random").unwrap()).as_bytes()[14],
+            b'4'
+        );
+    }
+}